@@ -1,20 +1,127 @@
 //#############################################################################################
 //#                                 IMPORTANT INFORMATION                                     #
 //#############################################################################################
-//#   The codebase is at the moment synchronus. This should be amended when we have a working #
-//#   prototype. at the moment, if i am not being too doom and gloom,                         #
-//#   somewhere around 70%+ of the time used by this approach would likely                    #
-//#   be on just waiting for things.                                                          #
+//#   On Linux the gateway runs on a non-blocking, epoll based event loop (the_event_loop.rs) #
+//#   so a few threads can keep thousands of devices going. The rest of the codebase is still #
+//#   synchronus: the DeviceListener gives every device a thread, and the Connection waits    #
+//#   and sleeps between retries when calling out. Don't use those for large fleets.          #
 //#############################################################################################
 
 mod the_gate;
 
-// use the_gate::binary_parser::binary_parser;
-fn main() {
-    let mut buf = String::new();
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use the_gate::{
+    DeviceProfile, GateGuard, IoDictionary, ProcessingPipeline, SessionConfig, ValidationPolicy,
+};
 
-    // binary_parser("/home/charon/Projects/deviaq_gateway/e".to_string()).expect("thoeu");
+// The address the devices are told to call, unless we are given another one
+const DEFAULT_LISTEN_ADDR: &str = "0.0.0.0:5027";
+// How many seconds a device may stay quiet before we hang up, unless we are told otherwise.
+// Open link devices ping us to stay on the line, so this should be longer than their ping interval.
+const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 30;
 
-    let mut a = std::io::Stdin::read_line(&std::io::stdin(), &mut buf);
-    println!("{:?}", a);
+// The device family (FM6XXX, FMB, FMC or any) and the validation policy (strict, lenient or drop)
+// are given after the timeout. We would rather not start than hold the devices to the wrong rules.
+fn session_config(idle_timeout: Duration) -> io::Result<SessionConfig> {
+    let mut config = SessionConfig::new(idle_timeout);
+    if let Some(name) = std::env::args().nth(3) {
+        config.profile = DeviceProfile::named(&name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown device family {}", name),
+            )
+        })?;
+    }
+    if let Some(name) = std::env::args().nth(4) {
+        config.policy = ValidationPolicy::named(&name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown validation policy {}", name),
+            )
+        })?;
+    }
+    Ok(config)
+}
+
+// The IO names the gateway knows out of the box, with whatever the file given after the
+// policy adds for newer firmware. A file that doesn't read keeps us from starting, too.
+fn io_dictionary() -> io::Result<IoDictionary> {
+    match std::env::args().nth(5) {
+        Some(path) => IoDictionary::load(path),
+        None => Ok(IoDictionary::built_in()),
+    }
+}
+
+fn main() -> std::io::Result<()> {
+    let addr = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_LISTEN_ADDR.to_string());
+    let idle_timeout = std::env::args()
+        .nth(2)
+        .and_then(|secs| secs.parse().ok())
+        .map_or(
+            Duration::from_secs(DEFAULT_IDLE_TIMEOUT_SECS),
+            Duration::from_secs,
+        );
+    let config = session_config(idle_timeout)?;
+    let dictionary = io_dictionary()?;
+
+    let pipeline = Arc::new(Mutex::new(ProcessingPipeline::new(100)));
+    let workers = thread::available_parallelism().map_or(1, |n| n.get());
+
+    #[cfg(target_os = "linux")]
+    let gateway = the_gate::EventLoop::bind(
+        &addr,
+        GateGuard::open(),
+        Arc::clone(&pipeline),
+        config,
+        workers,
+    )?;
+    #[cfg(not(target_os = "linux"))]
+    let gateway =
+        the_gate::DeviceListener::bind(&addr, GateGuard::open(), Arc::clone(&pipeline), config)?;
+    println!("Gateway listening on {} ({} workers)", gateway.local_addr()?, workers);
+
+    // The battery powered trackers save their breath and send datagrams to the same port
+    let udp = the_gate::UdpListener::bind(&addr, GateGuard::open(), Arc::clone(&pipeline), config)?;
+    println!("Gateway listening for datagrams on {}", udp.local_addr()?);
+    thread::spawn(move || udp.run());
+
+    // Until the packets have somewhere better to go, we empty the post office once a second
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(1));
+        if let Ok(delivered) = pipeline.lock().map(|mut pipeline| pipeline.flush()) {
+            let delivered = delivered.unwrap_or_default();
+            if !delivered.is_empty() {
+                println!("Delivered {} packets", delivered.len());
+            }
+
+            // Only the IO elements we know by name, the rest is just numbers to whoever reads this
+            for record in delivered.iter().flat_map(|packet| &packet.avl_data) {
+                let named: Vec<String> = record
+                    .named_ios(&dictionary, &config.profile)
+                    .filter_map(|io| {
+                        let value = format!("{}: {} {}", io.name()?, io.scaled()?, io.unit());
+                        Some(value.trim_end().to_string())
+                    })
+                    .collect();
+                if !named.is_empty() {
+                    println!("  {}", named.join(", "));
+                }
+            }
+        }
+
+        // The same goes for the answers to our commands, and anything else the devices tell us
+        let messages = pipeline.lock().map(|mut pipeline| pipeline.take_messages());
+        if let Ok(messages) = messages {
+            for message in messages {
+                println!("{} sent us {}", message.imei, message.frame.describe());
+            }
+        }
+    });
+
+    gateway.run()
 }
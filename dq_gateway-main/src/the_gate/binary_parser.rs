@@ -1,15 +1,15 @@
 //#############################################################################################
 //#                                 IMPORTANT INFORMATION                                     #
 //#############################################################################################
-//#   The codebase is at the moment synchronus. This should be amended when we have a working #
-//#   prototype. at the moment, if i am not being too doom and gloom,                         #
-//#   somewhere around 70%+ of the time used by this approach would likely                    #
-//#   be on just waiting for things.                                                          #
+//#   The Parser never waits on anything. It is fed bytes and hands out frames, so the        #
+//#   same Parser reads from a blocking socket, behind the epoll event loop or from a file.   #
+//#   parse_stream, parse_frame and read_from are the only ones reading from a source,        #
+//#   and they wait for as long as the source makes them.                                     #
 //#############################################################################################
 
 use super::*;
 // The size of a packet can't be less than 45 bytes, and not more than 1280 bytes
-// This is the size limitations. We should make sure this information should be used.
+// This is the size limitations. The tighter ones some devices keep to live in DeviceProfile.
 
 // Look at the mod file for constansts, custom macros and crates.
 
@@ -18,619 +18,698 @@ use super::*;
 // into meaningful information about where vehicles are and what they're doing.
 
 // The Parser struct is like a librarian who knows how to read these special books
-// buffer: Where we store the bytes we've received but haven't processed yet
+// buffer: Where we store the bytes we've received but haven't processed yet, it never grows
 // position: Which byte we're currently looking at (like keeping a finger on the line we're reading)
 
+// end: Where the section we are reading ends, we never read past it
+// codec: The codec the section we are reading is written in, once we know it
+// finished: Whether the device has put down the pen, meaning no more pages will come
+// skipped: How many pages we have had to tear out to find our place again
+// profile: What kind of device we are reading for, and so how big its sections can get
+// policy: What we do with records that can't be true, see ValidationPolicy
+// greeted: Whether the device has introduced itself, after that nothing is a handshake anymore
+// lent: How many pages at the front someone is still reading through a packet view,
+//       we only throw them away once they come back for the next one
 pub struct Parser {
-    buffer: Vec<u8>,
+    buffer: ReceiveBuffer,
     position: usize,
+    end: usize,
+    codec: Option<u8>,
+    finished: bool,
+    skipped: u64,
+    profile: DeviceProfile,
+    policy: ValidationPolicy,
+    greeted: bool,
+    lent: usize,
 }
 
 impl Parser {
     // We start with a fresh parser, the opening of a new book if you will.
     pub fn new() -> Self {
+        Self::with_profile(DeviceProfile::ANY)
+    }
+
+    // A fresh parser for a particular kind of device, that won't wait on anything
+    // bigger than that kind of device could send
+    pub fn with_profile(profile: DeviceProfile) -> Self {
         Self {
-            buffer: Vec::with_capacity(LARGEST_AVL_SIZE),
+            buffer: ReceiveBuffer::new(RECEIVE_BUFFER_SIZE),
             position: 0,
+            end: 0,
+            codec: None,
+            finished: false,
+            skipped: 0,
+            profile,
+            policy: ValidationPolicy::Strict,
+            greeted: false,
+            lent: 0,
         }
     }
 
-    // We then starat reading the pages from the book as they come in.
-    // stream: The source of our data (like someone handing us pages)
-    // Returns: A complete packet if we have enough data, or None if we need more
-    pub fn parse_stream(&mut self, stream: &mut TcpStream) -> io::Result<Option<AVLPacket>> {
-        let mut temp_buffer = [0u8; 4096];
-        let bytes_read = stream.read(&mut temp_buffer)?;
+    pub fn profile(&self) -> &DeviceProfile {
+        &self.profile
+    }
 
-        // If we got no new data, the book is finished
-        if bytes_read == 0 {
-            return Ok(None);
-        }
+    // Once we know better who we are talking to, we can hold them to their own limits
+    pub fn set_profile(&mut self, profile: DeviceProfile) {
+        self.profile = profile;
+    }
 
-        // Add the new pages to our book
-        self.buffer.extend_from_slice(&temp_buffer[..bytes_read]);
+    pub fn validation_policy(&self) -> ValidationPolicy {
+        self.policy
+    }
 
-        // We then try to make sense of what we have read so far
-        match self.try_parse_packet() {
-            Ok(Some(packet)) => {
-                // We have successfully read a complete section of the book, remove those pages
-                self.buffer.drain(..self.position);
-                self.position = 0;
-                Ok(Some(packet))
-            }
-            // We need more pages to complete the section, I mean, it doesn't even end on a cliffhanger
-            Ok(None) => Ok(None),
-            Err(e) => {
-                // Something went wrong, the writer is clearly drunk, start fresh.
-                self.buffer.clear();
-                self.position = 0;
-                Err(e)
-            }
-        }
+    pub fn set_validation_policy(&mut self, policy: ValidationPolicy) {
+        self.policy = policy;
     }
 
-    // This would be like reading the extended footnotes in a fancy academic book
-    // The extendet protocol contains extra details that normal one does't have
-    fn parse_codec8_extended_io(&mut self) -> io::Result<IOElement> {
-        // Checks if we have enough bytes for the header
-        if self.buffer.len() < self.position + 4 {
-            return Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                "The parsing buffer is too short for extended IO header",
-            ));
-        }
-        // Read the event ID (like a chapter number in our book) and total IO count (words)
-        let event_io_id =
-            u16::from_be_bytes([self.buffer[self.position], self.buffer[self.position + 1]]);
-        self.position += 2;
+    // Once the device has been let in, a page that doesn't start with a zeroed preamble
+    // is a torn one, and we look for the next frame instead of a second introduction
+    pub fn set_greeted(&mut self, greeted: bool) {
+        self.greeted = greeted;
+    }
 
-        let n_total_io =
-            u16::from_be_bytes([self.buffer[self.position], self.buffer[self.position + 1]]);
-        self.position += 2;
+    // Has the other side closed the book? Once it has, reading more won't give us anything.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
 
-        if self.buffer.len() < self.position + 2 {
-            return Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                "Buffer too short for one byte elements count",
-            ));
-        }
+    // Hand the librarian some more pages, no matter where they came from.
+    // A socket, a UDP datagram, a file or a test, the librarian doesn't care.
+    // Returns: How many of the bytes we took. The desk is only so big, so when we are fed
+    // more than fits, the rest has to wait until some frames have been taken out.
+    pub fn feed(&mut self, bytes: &[u8]) -> usize {
+        self.release();
+        self.buffer.extend(bytes)
+    }
 
-        let n1_of_one_byte =
-            u16::from_be_bytes([self.buffer[self.position], self.buffer[self.position + 1]]);
-        self.position += 2;
+    // Let the librarian know no more pages are coming
+    pub fn finish(&mut self) {
+        self.finished = true;
+    }
 
-        if self.buffer.len() < self.position + (n1_of_one_byte as usize * 3) {
-            return Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                "Buffer too short for one-byte IO elements",
-            ));
-        }
+    // How many bytes we have thrown away so far, looking for the start of a frame
+    pub fn skipped_bytes(&self) -> u64 {
+        self.skipped
+    }
 
-        let mut one_byte_ios = Vec::with_capacity(n1_of_one_byte as usize);
-        for _ in 0..n1_of_one_byte {
-            let id =
-                u16::from_be_bytes([self.buffer[self.position], self.buffer[self.position + 1]]);
-            self.position += 2;
-            let value = self.buffer[self.position];
-            self.position += 1;
-            one_byte_ios.push((id, value));
-        }
+    // How many pages we are holding on to that haven't made a complete section yet
+    pub fn buffered_len(&self) -> usize {
+        self.buffer.len() - self.lent
+    }
 
-        if self.buffer.len() < self.position + 2 {
-            return Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                "Buffer too short for two byte elements count",
-            ));
-        }
+    // Whoever we lent the last packet to is done with it, now that they are back for more
+    fn release(&mut self) {
+        self.buffer.consume(self.lent);
+        self.lent = 0;
+    }
 
-        let n2_of_two_bytes =
-            u16::from_be_bytes([self.buffer[self.position], self.buffer[self.position + 1]]);
-        self.position += 2;
+    // Take the next complete AVL packet out of what we have been fed so far.
+    // Returns: A complete packet, or None if we need to be fed more
+    // Keepalive pings are passed over, anything else that isn't AVL data,
+    // like an answer to a command, is reported as an error.
+    pub fn next_packet(&mut self) -> Result<Option<AVLPacket>, ParseError> {
+        loop {
+            return match self.next_frame()? {
+                Some(TeltonikaFrame::Avl(packet)) => Ok(Some(packet)),
+                Some(TeltonikaFrame::Ping) => continue,
+                Some(frame) => Err(ParseError::UnexpectedFrame {
+                    found: frame.describe(),
+                    offset: 0,
+                    codec: self.codec,
+                }),
+                None => Ok(None),
+            };
+        }
+    }
 
-        if self.buffer.len() < self.position + (n2_of_two_bytes as usize * 4) {
-            return Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                "Buffer too short for two-byte IO elements",
-            ));
+    // Like next_packet, but the packet stays where it is in our buffer, and nothing is
+    // copied. The fields are only read when asked for, see AVLPacketRef.
+    // The pages are ours again the next time we are fed or asked for a frame,
+    // so anything that has to live longer than that is copied out with to_packet().
+    // Returns: A view of a complete packet, or None if we need to be fed more
+    pub fn next_packet_ref(&mut self) -> Result<Option<AVLPacketRef<'_>>, ParseError> {
+        self.release();
+        loop {
+            match self.frame_ahead() {
+                Ok(Some(FrameKind::Avl)) => break,
+                // The device is only letting us know it is still there, nothing to hand out
+                Ok(Some(FrameKind::Ping)) => self.buffer.consume(self.end),
+                // Not AVL data, which next_packet turns into the right error for us
+                Ok(Some(_)) => return self.next_packet().map(|_| None),
+                Ok(None) => return Ok(None),
+                Err(e) => {
+                    self.resynchronise();
+                    return Err(e);
+                }
+            }
         }
 
-        let mut two_byte_ios = Vec::with_capacity(n2_of_two_bytes as usize);
-        for _ in 0..n2_of_two_bytes {
-            let id =
-                u16::from_be_bytes([self.buffer[self.position], self.buffer[self.position + 1]]);
-            self.position += 2;
-            let value =
-                u16::from_be_bytes([self.buffer[self.position], self.buffer[self.position + 1]]);
-            self.position += 2;
-            two_byte_ios.push((id, value));
+        let end = self.end;
+        if let Err(e) =
+            AVLPacketRef::validate(&self.buffer.data()[..end], &self.profile, self.policy)
+        {
+            self.resynchronise();
+            return Err(e);
         }
+        self.lent = end;
+        Ok(Some(AVLPacketRef::from_checked_frame(
+            &self.buffer.data()[..end],
+            self.policy,
+        )))
+    }
 
-        if self.buffer.len() < self.position + 2 {
-            return Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                "Buffer too short for four byte elements count",
-            ));
+    // Take the next complete frame out of what we have been fed so far,
+    // be it AVL data, a handshake or the answer to a command we sent earlier.
+    // Returns: A complete frame, or None if we need to be fed more
+    pub fn next_frame(&mut self) -> Result<Option<TeltonikaFrame>, ParseError> {
+        self.release();
+        match self.try_parse_packet() {
+            Ok(Some(frame)) => {
+                // We have successfully read a complete section of the book, remove those pages
+                self.buffer.consume(self.end);
+                self.position = 0;
+                Ok(Some(frame))
+            }
+            // We need more pages to complete the section, I mean, it doesn't even end on a cliffhanger
+            Ok(None) => Ok(None),
+            Err(e) => {
+                // Something went wrong, the writer is clearly drunk. We don't throw the whole
+                // book away though, the sections after the bad one may be perfectly fine.
+                self.resynchronise();
+                Err(e)
+            }
         }
+    }
 
-        let n4_of_four_bytes =
-            u16::from_be_bytes([self.buffer[self.position], self.buffer[self.position + 1]]);
-        self.position += 2;
+    // Skip ahead to the next place that looks like the start of a frame,
+    // always skipping at least the byte that got us into trouble.
+    fn resynchronise(&mut self) {
+        let data = self.buffer.data();
+        let skip = (1..=data.len())
+            .find(|&start| Self::could_start_frame(&data[start..]))
+            .unwrap_or(data.len());
+
+        self.buffer.consume(skip);
+        self.position = 0;
+        self.skipped += skip as u64;
+    }
 
-        if self.buffer.len() < self.position + (n4_of_four_bytes as usize * 6) {
-            return Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                "Buffer too short for four-byte IO elements",
-            ));
-        }
+    // Could a frame start at the beginning of these bytes? A zeroed preamble, followed by
+    // a length a frame could actually have, followed by a codec we know how to read.
+    // When we haven't got that far yet, we give the bytes the benefit of the doubt.
+    fn could_start_frame(bytes: &[u8]) -> bool {
+        if bytes.iter().take(4).any(|&byte| byte != 0x00) {
+            return false;
+        }
+        if bytes.len() < 9 {
+            return true;
+        }
+
+        let data_length = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as usize;
+        let smallest = match bytes[8] {
+            0x0C => SMALLEST_CODEC12_DATA_LENGTH,
+            // The same, with a timestamp or an IMEI in front of the text
+            0x0D => SMALLEST_CODEC12_DATA_LENGTH + 4,
+            0x0E => SMALLEST_CODEC12_DATA_LENGTH + 8,
+            // Codec 15 has both, the timestamp and the IMEI, in front of the serial data
+            0x0F => SMALLEST_CODEC12_DATA_LENGTH + 12,
+            0x08 | 0x8E | 0x10 => SMALLEST_AVL_SIZE - 12,
+            _ => return false,
+        };
+        // The whole frame, with its preamble, length and crc, has to fit the largest one there is
+        (smallest..=LARGEST_AVL_SIZE - 12).contains(&data_length)
+    }
 
-        let mut four_byte_ios = Vec::with_capacity(n4_of_four_bytes as usize);
-        for _ in 0..n4_of_four_bytes {
-            let id =
-                u16::from_be_bytes([self.buffer[self.position], self.buffer[self.position + 1]]);
-            self.position += 2;
-            let value = u32::from_be_bytes([
-                self.buffer[self.position],
-                self.buffer[self.position + 1],
-                self.buffer[self.position + 2],
-                self.buffer[self.position + 3],
-            ]);
-            self.position += 4;
-            four_byte_ios.push((id, value));
-        }
+    // Every complete frame we are holding on to right now, one after the other.
+    // Devices happily send several packets in one go, and none of them should
+    // have to wait for the next read to be noticed.
+    pub fn frames(&mut self) -> Frames<'_> {
+        Frames { parser: self }
+    }
 
-        if self.buffer.len() < self.position + 2 {
-            return Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                "Buffer too short for eight byte elements count",
-            ));
+    // We then starat reading the pages from the book as they come in.
+    // stream: The source of our data (like someone handing us pages)
+    // Returns: A complete packet if we have enough data, or None if we need more
+    // This is for those of us who only care about the AVL data. If the device
+    // hands us anything else, like an answer to a command, we let the caller know.
+    pub fn parse_stream<R: Read>(&mut self, stream: &mut R) -> io::Result<Option<AVLPacket>> {
+        // Whatever is already on the table goes first, the device may be waiting on our answer
+        if let Some(packet) = self.next_packet()? {
+            return Ok(Some(packet));
+        }
+        if self.read_from(stream)? == 0 {
+            return Ok(None);
         }
+        Ok(self.next_packet()?)
+    }
 
-        let n8_of_eight_bytes =
-            u16::from_be_bytes([self.buffer[self.position], self.buffer[self.position + 1]]);
-        self.position += 2;
-
-        if self.buffer.len() < self.position + (n8_of_eight_bytes as usize * 10) {
-            return Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                "Buffer too short for eight-byte IO elements",
-            ));
+    // Reads whatever the device is sending us, be it AVL data or the answer to
+    // a command we sent it earlier.
+    // Returns: A complete frame if we have enough data, or None if we need more
+    pub fn parse_frame<R: Read>(&mut self, stream: &mut R) -> io::Result<Option<TeltonikaFrame>> {
+        if let Some(frame) = self.next_frame()? {
+            return Ok(Some(frame));
+        }
+        if self.read_from(stream)? == 0 {
+            return Ok(None);
         }
+        Ok(self.next_frame()?)
+    }
 
-        let mut eight_byte_ios = Vec::with_capacity(n8_of_eight_bytes as usize);
-        for _ in 0..n8_of_eight_bytes {
-            let id =
-                u16::from_be_bytes([self.buffer[self.position], self.buffer[self.position + 1]]);
-            self.position += 2;
-            let value = u64::from_be_bytes([
-                self.buffer[self.position],
-                self.buffer[self.position + 1],
-                self.buffer[self.position + 2],
-                self.buffer[self.position + 3],
-                self.buffer[self.position + 4],
-                self.buffer[self.position + 5],
-                self.buffer[self.position + 6],
-                self.buffer[self.position + 7],
-            ]);
-            self.position += 8;
-            eight_byte_ios.push((id, value));
-        }
-
-        if self.buffer.len() < self.position + 2 {
+    // Read once from the source and feed whatever we got to ourselves.
+    // Handy together with next_packet_ref, when nothing should be copied at all.
+    // Returns: How many bytes we read, 0 meaning the book is finished
+    pub fn read_from<R: Read>(&mut self, stream: &mut R) -> io::Result<usize> {
+        self.release();
+
+        // We should only ever get here holding less than a whole frame, so there ought to be
+        // room for the rest of it. The device writes straight onto our desk, no copies in between.
+        // If the desk is full anyway, we say so, reading into nothing would look like the end.
+        let spare = self.buffer.spare(LARGEST_AVL_SIZE);
+        if spare.is_empty() {
             return Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                "Buffer too short for variable length elements count",
+                io::ErrorKind::InvalidData,
+                "Receive buffer is full without a whole frame in it",
             ));
         }
+        let bytes_read = stream.read(spare)?;
 
-        let nx_of_var_bytes =
-            u16::from_be_bytes([self.buffer[self.position], self.buffer[self.position + 1]]);
-        self.position += 2;
+        // If we got no new data, the book is finished
+        if bytes_read == 0 {
+            self.finish();
+        } else {
+            self.buffer.commit(bytes_read);
+        }
+        Ok(bytes_read)
+    }
 
-        let mut var_byte_ios = Vec::with_capacity(nx_of_var_bytes as usize);
-        for _ in 0..nx_of_var_bytes {
-            if self.buffer.len() < self.position + 4 {
-                return Err(io::Error::new(
-                    io::ErrorKind::UnexpectedEof,
-                    "Buffer too short for variable length IO element header",
-                ));
+    // The librarian never reads past the last page of the section in front of them.
+    // Every read below goes through these, so a section that lies about its own size
+    // gets us an error instead of a finger pointing at a page that isn't there.
+    // what: What we were hoping to find, so the error can tell us where it went wrong
+    fn take(&mut self, count: usize, what: &'static str) -> Result<&[u8], ParseError> {
+        let start = self.position;
+        let end = match start.checked_add(count) {
+            Some(end) if end <= self.end => end,
+            _ => {
+                return Err(ParseError::Incomplete {
+                    needed: what,
+                    offset: start,
+                    codec: self.codec,
+                })
             }
+        };
+        self.position = end;
+        Ok(&self.buffer.data()[start..end])
+    }
 
-            let id =
-                u16::from_be_bytes([self.buffer[self.position], self.buffer[self.position + 1]]);
-            self.position += 2;
-
-            let length =
-                u16::from_be_bytes([self.buffer[self.position], self.buffer[self.position + 1]]);
-            self.position += 2;
+    fn read_array<const N: usize>(&mut self, what: &'static str) -> Result<[u8; N], ParseError> {
+        let mut bytes = [0u8; N];
+        bytes.copy_from_slice(self.take(N, what)?);
+        Ok(bytes)
+    }
 
-            // Validate we have enough bytes for the variable length value
-            if self.buffer.len() < self.position + length as usize {
-                return Err(io::Error::new(
-                    io::ErrorKind::UnexpectedEof,
-                    "Buffer too short for variable length IO element value",
-                ));
-            }
+    fn read_u8(&mut self, what: &'static str) -> Result<u8, ParseError> {
+        Ok(self.read_array::<1>(what)?[0])
+    }
 
-            let mut value = Vec::with_capacity(length as usize);
-            for _ in 0..length {
-                value.push(self.buffer[self.position]);
-                self.position += 1;
-            }
+    fn read_u16(&mut self, what: &'static str) -> Result<u16, ParseError> {
+        Ok(u16::from_be_bytes(self.read_array(what)?))
+    }
 
-            var_byte_ios.push((id, length, value));
-        }
+    fn read_u32(&mut self, what: &'static str) -> Result<u32, ParseError> {
+        Ok(u32::from_be_bytes(self.read_array(what)?))
+    }
 
-        Ok(IOElement::Codec8Extended(IOElement8Extended {
-            event_io_id,
-            n_total_io,
-            n1_of_one_byte,
-            one_byte_ios,
-            n2_of_two_bytes,
-            two_byte_ios,
-            n4_of_four_bytes,
-            four_byte_ios,
-            n8_of_eight_bytes,
-            eight_byte_ios,
-            nx_of_var_bytes,
-            var_byte_ios,
-        }))
+    // How many bytes are left of the section we are reading
+    fn remaining(&self) -> usize {
+        self.end.saturating_sub(self.position)
     }
 
     // This is where we try to read one complete section of our book
-    // Returns: A complete packet if we have enough data, or None if we need more
-    fn try_parse_packet(&mut self) -> io::Result<Option<AVLPacket>> {
-        // First, make sure the section we are reading have at least the minimum amount of pages
-        if self.buffer.len() < SMALLEST_AVL_SIZE {
-            return Ok(None);
+    // Returns: A complete frame if we have enough data, or None if we need more
+    fn try_parse_packet(&mut self) -> Result<Option<TeltonikaFrame>, ParseError> {
+        match self.frame_ahead()? {
+            Some(FrameKind::Ping) => Ok(Some(TeltonikaFrame::Ping)),
+            Some(FrameKind::Handshake) => self.parse_imei_handshake(),
+            Some(FrameKind::CommandResponse) => {
+                let response = self.parse_codec12_response()?;
+                Ok(Some(TeltonikaFrame::CommandResponse(response)))
+            }
+            Some(FrameKind::TimestampedResponse) => {
+                let response = self.parse_codec13_response()?;
+                Ok(Some(TeltonikaFrame::TimestampedResponse(response)))
+            }
+            Some(FrameKind::ImeiCommandResponse) => {
+                let response = self.parse_codec14_response()?;
+                Ok(Some(TeltonikaFrame::ImeiCommandResponse(response)))
+            }
+            Some(FrameKind::ThirdPartyData) => {
+                let packet = self.parse_codec15_packet()?;
+                Ok(Some(TeltonikaFrame::ThirdPartyData(packet)))
+            }
+            // AVL data is read through a view, and copied out of the buffer in one go
+            Some(FrameKind::Avl) => {
+                let frame = &self.buffer.data()[..self.end];
+                let packet = AVLPacketRef::parse_with_policy(frame, &self.profile, self.policy)?;
+                Ok(Some(TeltonikaFrame::Avl(packet.to_packet())))
+            }
+            None => Ok(None),
         }
-        let codec_id = self.buffer[self.position];
-        self.position += 1;
+    }
 
-        let number_of_data1 = self.buffer[self.position];
-        self.position += 1;
+    // Find out what kind of section is waiting for us, and where it ends.
+    // For everything but the handshake, we only say so once the whole section is here.
+    // Returns: The kind of section, or None if we need more pages to tell
+    fn frame_ahead(&mut self) -> Result<Option<FrameKind>, ParseError> {
+        // Every section starts from the first page we haven't handed out yet,
+        // and until we know how long it is, we can read as far as we have pages
+        self.position = 0;
+        self.end = self.buffer.len();
+        self.codec = None;
 
-        let mut avl_data = Vec::with_capacity(number_of_data1 as usize);
-        for _ in 0..number_of_data1 {
-            let data = self.parse_avl_data(codec_id)?; // Pass the codec_id here
-            avl_data.push(data);
+        // With open link on, a device with nothing to say sends a single 0xFF now and then,
+        // just to keep the line from going quiet. It is the whole frame, nothing follows it.
+        if self.buffer.data().first() == Some(&KEEPALIVE_PING) {
+            self.end = 1;
+            return Ok(Some(FrameKind::Ping));
         }
 
-        // We then check that the book starts with the right sequence (like "Chapter 1")
-        let preamble = u32::from_be_bytes([
-            self.buffer[0],
-            self.buffer[1],
-            self.buffer[2],
-            self.buffer[3],
-        ]);
-        if preamble != 0x00000000 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!(
-                    "Invalid preamble: expected 0x00000000, got {:#010x}",
-                    preamble
-                ),
-            ));
+        if self.buffer.len() < 2 {
+            return Ok(None);
         }
 
-        self.position = 4;
+        // Before anything else, a device introduces itself with a 2 byte length and its IMEI.
+        // A real packet always starts with a zeroed preamble, so a non-zero length
+        // tells us we are being greeted rather than handed data. It only does so once though,
+        // after that a non-zero start is a bad preamble, and we find our place again.
+        if !self.greeted && self.buffer.data()[..2] != [0x00, 0x00] {
+            return Ok(Some(FrameKind::Handshake));
+        }
 
-        if self.buffer.len() < self.position + 4 {
+        // We need at least the preamble and the data length before we know anything
+        if self.buffer.len() < 8 {
             return Ok(None);
         }
 
-        let data_length = u32::from_be_bytes([
-            self.buffer[self.position],
-            self.buffer[self.position + 1],
-            self.buffer[self.position + 2],
-            self.buffer[self.position + 3],
-        ]);
-
-        self.position += 4;
-
-        let total_length = 8 + data_length as usize + 4; // preamble + length + data + crc
-        if self.buffer.len() < total_length {
-            return Ok(None);
+        // We then check that the book starts with the right sequence (like "Chapter 1")
+        let preamble = self.read_u32("preamble")?;
+        if preamble != 0x00000000 {
+            return Err(ParseError::BadPreamble {
+                found: preamble,
+                offset: 0,
+                codec: None,
+            });
         }
 
-        let codec_id = self.buffer[self.position];
-        self.position += 1;
+        let data_length = self.read_u32("data length")?;
 
-        let number_of_data1 = self.buffer[self.position];
-        self.position += 1;
+        // preamble + length + data + crc
+        let total_length = (data_length as usize).saturating_add(12);
 
-        let mut avl_data = Vec::with_capacity(number_of_data1 as usize);
-        for _ in 0..number_of_data1 {
-            let data = self.parse_avl_data(codec_id)?;
-            avl_data.push(data);
+        // The device we are reading for never sends a bigger frame, and nothing bigger than
+        // LARGEST_AVL_SIZE would fit on our desk anyway. Better to say so now,
+        // than to wait for four gigabytes that are never coming.
+        if total_length > self.profile.frame_limit() {
+            return Err(ParseError::FrameTooLarge {
+                length: total_length,
+                maximum: self.profile.frame_limit(),
+                offset: 4,
+                codec: None,
+            });
         }
-
-        let number_of_data2 = self.buffer[self.position];
-        self.position += 1;
-
-        if number_of_data1 != number_of_data2 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Data record count mismatch",
-            ));
+        if self.buffer.len() < total_length {
+            return Ok(None);
         }
-
-        let crc = u32::from_be_bytes([
-            self.buffer[self.position],
-            self.buffer[self.position + 1],
-            self.buffer[self.position + 2],
-            self.buffer[self.position + 3],
-        ]);
-        self.position += 4;
-
-        let calculated_crc = self.calculate_crc(&self.buffer[4..self.position - 4]);
-        if crc != calculated_crc {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "CRC validation failed",
-            ));
+        // From here on, the section ends where it told us it would
+        self.end = total_length;
+
+        let codec_offset = self.position;
+        let codec_id = self.read_u8("codec id")?;
+        self.codec = Some(codec_id);
+
+        // Commands and their answers are written in a completely different way,
+        // so they get their own reading glasses.
+        match codec_id {
+            0x0C => Ok(Some(FrameKind::CommandResponse)),
+            0x0D => Ok(Some(FrameKind::TimestampedResponse)),
+            0x0E => Ok(Some(FrameKind::ImeiCommandResponse)),
+            0x0F => Ok(Some(FrameKind::ThirdPartyData)),
+            0x08 | 0x8E | 0x10 => Ok(Some(FrameKind::Avl)),
+            _ => Err(ParseError::UnsupportedCodec {
+                offset: codec_offset,
+                codec: self.codec,
+            }),
         }
-
-        Ok(Some(AVLPacket {
-            preamble,
-            data_length,
-            codec_id,
-            number_of_data1,
-            avl_data,
-            number_of_data2,
-            crc16: crc,
-        }))
     }
 
-    // Each book contains the the story of our vehicle(The AVL-data), about the advetures it has been on.
-    // This means that each entry contains information about where a vehicle was and what it was doing.
-    // This is what we are reading hear.
-    fn parse_avl_data(&mut self, codec_id: u8) -> io::Result<AVLData> {
-        // First 8 bytes tell us when this happened
-        if self.buffer.len() < self.position + 8 {
-            return Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                "Buffer too short for timestamp",
-            ));
-        }
-        let timestamp = u64::from_be_bytes([
-            self.buffer[self.position],
-            self.buffer[self.position + 1],
-            self.buffer[self.position + 2],
-            self.buffer[self.position + 3],
-            self.buffer[self.position + 4],
-            self.buffer[self.position + 5],
-            self.buffer[self.position + 6],
-            self.buffer[self.position + 7],
-        ]);
-        self.position += 8;
-
-        if timestamp == 0 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Invalid timestamp",
-            ));
-        }
+    // The handshake is the device saying hello: a 2 byte length followed by its IMEI in ASCII.
+    // It has to answer with a 0x01 or 0x00 before the device will send us any AVL data.
+    fn parse_imei_handshake(&mut self) -> Result<Option<TeltonikaFrame>, ParseError> {
+        let imei_length = self.read_u16("IMEI length")? as usize;
 
-        if self.buffer.len() < self.position + 1 {
-            return Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                "Buffer too short for priority",
-            ));
+        // An IMEI is always 15 digits, anything else is a device we can't tell apart from noise
+        if imei_length != MAX_IMEI_LENGTH {
+            return Err(ParseError::InvalidImei {
+                reason: if imei_length > MAX_IMEI_LENGTH {
+                    "longer than 15 digits"
+                } else {
+                    "shorter than 15 digits"
+                },
+                offset: 0,
+                codec: None,
+            });
         }
-        let priority = self.buffer[self.position];
-        self.position += 1;
 
-        if self.buffer.len() < self.position + 15 {
-            return Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                "Buffer too short for GPS data",
-            ));
+        if self.remaining() < imei_length {
+            return Ok(None);
         }
+        self.end = self.position + imei_length;
 
-        let longitude = i32::from_be_bytes([
-            self.buffer[self.position],
-            self.buffer[self.position + 1],
-            self.buffer[self.position + 2],
-            self.buffer[self.position + 3],
-        ]);
-        self.position += 4;
-
-        let latitude = i32::from_be_bytes([
-            self.buffer[self.position],
-            self.buffer[self.position + 1],
-            self.buffer[self.position + 2],
-            self.buffer[self.position + 3],
-        ]);
-        self.position += 4;
-
-        if longitude < -180_00000 || longitude > 180_00000 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Longitude out of range",
-            ));
-        }
-        if latitude < -90_00000 || latitude > 90_00000 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Latitude out of range",
-            ));
+        let imei = self.take(imei_length, "IMEI")?;
+        if !imei.iter().all(u8::is_ascii_digit) {
+            return Err(ParseError::InvalidImei {
+                reason: "contains non-digit characters",
+                offset: 2,
+                codec: None,
+            });
         }
+        let imei = String::from_utf8_lossy(imei).into_owned();
 
-        let altitude =
-            i16::from_be_bytes([self.buffer[self.position], self.buffer[self.position + 1]]);
-        self.position += 2;
-
-        let angle =
-            i16::from_be_bytes([self.buffer[self.position], self.buffer[self.position + 1]]);
-        self.position += 2;
-
-        let satellites = self.buffer[self.position];
-        self.position += 1;
-
-        let speed =
-            i16::from_be_bytes([self.buffer[self.position], self.buffer[self.position + 1]]);
-        self.position += 2;
-        let io = match codec_id {
-            0x08 => self.parse_codec8_io()?,
-            0x8E => self.parse_codec8_extended_io()?,
-            unknown_codec => {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("Unsupported codec: {:#04x}", unknown_codec),
-                ))
-            }
-        };
+        Ok(Some(TeltonikaFrame::Imei(imei)))
+    }
 
-        Ok(AVLData {
-            timestamp,
-            priority,
-            gps: GPSElement {
-                longitude,
-                latitude,
-                altitude,
-                angle,
-                satellites,
-                speed,
-            },
-            io,
+    // Codec 12 is how we and the device pass notes to each other outside of the AVL data.
+    // We send it a command, and it answers with a response in plain text.
+    fn parse_codec12_response(&mut self) -> Result<Codec12ResponsePacket, ParseError> {
+        let envelope = self.parse_command_envelope(&[CODEC12_RESPONSE_TYPE])?;
+
+        Ok(Codec12ResponsePacket {
+            preamble: 0x00000000,
+            data_length: (self.end - 12) as u32,
+            codec_id: 0x0C,
+            response_qty1: envelope.quantity,
+            response_type: envelope.message_type,
+            response_size: envelope.message.len() as u32,
+            response: envelope.message,
+            response_qty2: envelope.quantity,
+            crc16: envelope.crc16,
         })
     }
 
-    fn parse_codec8_io(&mut self) -> io::Result<IOElement> {
-        if self.buffer.len() < self.position + 3 {
-            return Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                "Buffer too short for IO header",
-            ));
-        }
-
-        let event_io_id = self.buffer[self.position];
-        self.position += 1;
-
-        let n_total_io = self.buffer[self.position];
-        self.position += 1;
-
-        let n1_of_one_byte = self.buffer[self.position];
-        self.position += 1;
-
-        if self.buffer.len() < self.position + (n1_of_one_byte as usize * 2) {
-            return Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                "Buffer too short for one-byte IO elements",
-            ));
-        }
-
-        let mut one_byte_ios = Vec::with_capacity(n1_of_one_byte as usize);
-        for _ in 0..n1_of_one_byte {
-            let id = self.buffer[self.position];
-            self.position += 1;
-            let value = self.buffer[self.position];
-            self.position += 1;
-            one_byte_ios.push((id, value));
-        }
-
-        let n2_of_two_bytes = self.buffer[self.position];
-        self.position += 1;
+    // Codec 13 is a Codec 12 response with the time the device wrote it in front.
+    // It is sent as a command (0x05), as the device isn't answering anything we asked.
+    fn parse_codec13_response(&mut self) -> Result<Codec13ResponsePacket, ParseError> {
+        let envelope = self.parse_command_envelope(&[CODEC12_COMMAND_TYPE])?;
+        if envelope.message.len() < 4 {
+            return Err(ParseError::FrameTooSmall {
+                length: self.end,
+                minimum: SMALLEST_CODEC12_DATA_LENGTH + 4 + 12,
+                offset: 0,
+                codec: self.codec,
+            });
+        }
+        let (timestamp, response) = envelope.message.split_at(4);
+
+        Ok(Codec13ResponsePacket {
+            preamble: 0x00000000,
+            data_length: (self.end - 12) as u32,
+            codec_id: 0x0D,
+            response_qty1: envelope.quantity,
+            response_type: envelope.message_type,
+            response_size: envelope.message.len() as u32,
+            timestamp: u32::from_be_bytes(timestamp.try_into().unwrap()),
+            response: response.to_vec(),
+            response_qty2: envelope.quantity,
+            crc16: envelope.crc16,
+        })
+    }
 
-        if self.buffer.len() < self.position + (n2_of_two_bytes as usize * 3) {
-            // id(1) + value(2)
-            return Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                "Buffer too short for two-byte IO elements",
-            ));
-        }
+    // Codec 14 is the answer to a command we addressed to an IMEI. The device says whose
+    // IMEI it has, and either answers (0x06), or tells us it isn't the one (0x11).
+    fn parse_codec14_response(&mut self) -> Result<Codec14ResponsePacket, ParseError> {
+        let envelope = self.parse_command_envelope(&[CODEC14_ACK_TYPE, CODEC14_NACK_TYPE])?;
+        if envelope.message.len() < 8 {
+            return Err(ParseError::FrameTooSmall {
+                length: self.end,
+                minimum: SMALLEST_CODEC12_DATA_LENGTH + 8 + 12,
+                offset: 0,
+                codec: self.codec,
+            });
+        }
+        let (imei, response) = envelope.message.split_at(8);
+        let imei = unpack_imei(imei.try_into().unwrap()).ok_or(ParseError::InvalidImei {
+            reason: "contains non-digit characters",
+            offset: 15,
+            codec: self.codec,
+        })?;
+
+        Ok(Codec14ResponsePacket {
+            preamble: 0x00000000,
+            data_length: (self.end - 12) as u32,
+            codec_id: 0x0E,
+            response_qty1: envelope.quantity,
+            response_type: envelope.message_type,
+            response_size: envelope.message.len() as u32,
+            imei,
+            response: response.to_vec(),
+            response_qty2: envelope.quantity,
+            crc16: envelope.crc16,
+        })
+    }
 
-        let mut two_byte_ios = Vec::with_capacity(n2_of_two_bytes as usize);
-        for _ in 0..n2_of_two_bytes {
-            let id = self.buffer[self.position];
-            self.position += 1;
-            let value =
-                u16::from_be_bytes([self.buffer[self.position], self.buffer[self.position + 1]]);
-            self.position += 2;
-            two_byte_ios.push((id, value));
-        }
+    // Codec 15 is how an FMX6 passes on what a serial device attached to it said.
+    // The timestamp and the IMEI come first, the rest is the serial data, untouched.
+    // Nothing is expected back, the device doesn't wait for an answer.
+    fn parse_codec15_packet(&mut self) -> Result<Codec15Packet, ParseError> {
+        let envelope = self.parse_command_envelope(&[CODEC15_MESSAGE_TYPE])?;
+        if envelope.message.len() < 12 {
+            return Err(ParseError::FrameTooSmall {
+                length: self.end,
+                minimum: SMALLEST_CODEC12_DATA_LENGTH + 12 + 12,
+                offset: 0,
+                codec: self.codec,
+            });
+        }
+        let (timestamp, rest) = envelope.message.split_at(4);
+        let (imei, payload) = rest.split_at(8);
+        let imei = unpack_imei(imei.try_into().unwrap()).ok_or(ParseError::InvalidImei {
+            reason: "contains non-digit characters",
+            offset: 19,
+            codec: self.codec,
+        })?;
+
+        Ok(Codec15Packet {
+            preamble: 0x00000000,
+            data_length: (self.end - 12) as u32,
+            codec_id: 0x0F,
+            message_qty1: envelope.quantity,
+            message_type: envelope.message_type,
+            message_size: envelope.message.len() as u32,
+            timestamp: u32::from_be_bytes(timestamp.try_into().unwrap()),
+            imei,
+            payload: payload.to_vec(),
+            message_qty2: envelope.quantity,
+            crc16: envelope.crc16,
+        })
+    }
 
-        let n4_of_four_bytes = self.buffer[self.position];
-        self.position += 1;
+    // Codec 12, 13, 14 and 15 are all written the same way after the codec id:
+    // quantity 1, type, size, the message, quantity 2 and the CRC.
+    // types: The types the frame may have, the first is the one we expected
+    fn parse_command_envelope(&mut self, types: &[u8]) -> Result<CommandEnvelope, ParseError> {
+        let quantity1 = self.read_u8("command header")?;
+        let type_offset = self.position;
+        let message_type = self.read_u8("command header")?;
+
+        if !types.contains(&message_type) {
+            return Err(ParseError::UnexpectedType {
+                expected: types[0],
+                found: message_type,
+                offset: type_offset,
+                codec: self.codec,
+            });
+        }
+
+        let size = self.read_u32("command header")?;
+
+        // The message, the quantity and the CRC all have to fit inside what we were promised
+        let message = self.take(size as usize, "command message")?.to_vec();
+
+        let count_offset = self.position;
+        let quantity2 = self.read_u8("command quantity")?;
+
+        if quantity1 != quantity2 {
+            return Err(ParseError::RecordCountMismatch {
+                first: quantity1,
+                second: quantity2,
+                offset: count_offset,
+                codec: self.codec,
+            });
+        }
+
+        // The message has to fill the frame exactly, just like the records of an AVL packet
+        let crc_start = self.position;
+        if crc_start + 4 != self.end {
+            return Err(ParseError::LengthMismatch {
+                declared: self.end - 12,
+                consumed: crc_start - 8,
+                offset: crc_start,
+                codec: self.codec,
+            });
+        }
+        let crc16 = self.read_u32("command CRC")?;
+
+        let calculated_crc = calculate_crc16(&self.buffer.data()[8..crc_start]);
+        if crc16 != calculated_crc {
+            return Err(ParseError::CrcMismatch {
+                expected: calculated_crc,
+                actual: crc16,
+                offset: crc_start,
+                codec: self.codec,
+            });
+        }
+
+        Ok(CommandEnvelope {
+            quantity: quantity1,
+            message_type,
+            message,
+            crc16,
+        })
+    }
+}
 
-        if self.buffer.len() < self.position + (n4_of_four_bytes as usize * 5) {
-            return Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                "Buffer too short for four-byte IO elements",
-            ));
-        }
+// What Codec 12, 13, 14 and 15 frames have in common, once checked
+struct CommandEnvelope {
+    quantity: u8,
+    message_type: u8,
+    message: Vec<u8>,
+    crc16: u32,
+}
 
-        let mut four_byte_ios = Vec::with_capacity(n4_of_four_bytes as usize);
-        for _ in 0..n4_of_four_bytes {
-            let id = self.buffer[self.position];
-            self.position += 1;
-            let value = u32::from_be_bytes([
-                self.buffer[self.position],
-                self.buffer[self.position + 1],
-                self.buffer[self.position + 2],
-                self.buffer[self.position + 3],
-            ]);
-            self.position += 4;
-            four_byte_ios.push((id, value));
-        }
+// The kinds of section a device can hand us, told apart before we read them
+enum FrameKind {
+    Ping,
+    Handshake,
+    CommandResponse,
+    TimestampedResponse,
+    ImeiCommandResponse,
+    ThirdPartyData,
+    Avl,
+}
 
-        let n8_of_eight_bytes = self.buffer[self.position];
-        self.position += 1;
+//   Walks through every complete frame the Parser is holding, like flipping
+//   through the finished sections of the book. It stops when we need more pages.
+//   A bad section shows up as an error, and the walk carries on after it.
+pub struct Frames<'a> {
+    parser: &'a mut Parser,
+}
 
-        if self.buffer.len() < self.position + (n8_of_eight_bytes as usize * 9) {
-            return Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
-                "Buffer too short for eight-byte IO elements",
-            ));
-        }
+impl Iterator for Frames<'_> {
+    type Item = Result<TeltonikaFrame, ParseError>;
 
-        let mut eight_byte_ios = Vec::with_capacity(n8_of_eight_bytes as usize);
-        for _ in 0..n8_of_eight_bytes {
-            let id = self.buffer[self.position];
-            self.position += 1;
-            let value = u64::from_be_bytes([
-                self.buffer[self.position],
-                self.buffer[self.position + 1],
-                self.buffer[self.position + 2],
-                self.buffer[self.position + 3],
-                self.buffer[self.position + 4],
-                self.buffer[self.position + 5],
-                self.buffer[self.position + 6],
-                self.buffer[self.position + 7],
-            ]);
-            self.position += 8;
-            eight_byte_ios.push((id, value));
-        }
-
-        Ok(IOElement::Codec8(IOElement8 {
-            event_io_id,
-            n_total_io,
-            n1_of_one_byte,
-            one_byte_ios,
-            n2_of_two_bytes,
-            two_byte_ios,
-            n4_of_four_bytes,
-            four_byte_ios,
-            n8_of_eight_bytes,
-            eight_byte_ios,
-        }))
-    }
-
-    // This calculates a special number that helps us verify nothing got corrupted
-    // Like checking if any pages have grammatical mistakes, spelling errors
-    // or got coffee stains on them during delivery. Maybe it's dog ate his homework?
-    fn calculate_crc(&self, data: &[u8]) -> u32 {
-        let mut crc: u16 = 0xFFFF;
-        for &byte in data {
-            crc ^= (byte as u16) & 0xFF;
-            for _ in 0..8 {
-                if (crc & 0x0001) != 0 {
-                    crc = (crc >> 1) ^ 0xA001;
-                } else {
-                    crc >>= 1;
-                }
-            }
-        }
-        crc as u32
+    fn next(&mut self) -> Option<Self::Item> {
+        self.parser.next_frame().transpose()
     }
 }
@@ -0,0 +1,77 @@
+use super::*;
+
+//   Not every device writes letters of the same length. The older FM6XXX devices
+//   never send more than 512 bytes in one go, the FMB and FMC families go up to 1280.
+//   A profile tells the Parser how much a device of a given family could possibly send,
+//   so anything bigger is turned away straight away, instead of waited on.
+// - name: What we call the family, for the logs
+// - max_frame_size: The largest whole frame, from the preamble to the CRC
+// - max_record_size: The largest single AVL record inside a frame
+//
+//   The fields are public, so a family we haven't written down yet is only a
+//   struct literal away. Nothing gets bigger than LARGEST_AVL_SIZE though,
+//   however generous the profile is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceProfile {
+    pub name: &'static str,
+    pub max_frame_size: usize,
+    pub max_record_size: usize,
+}
+
+// Everything around the records: preamble(4), data length(4), codec id(1),
+// the two record counts(1 + 1) and the CRC(4)
+const FRAME_OVERHEAD: usize = 15;
+
+impl DeviceProfile {
+    // The FM6XXX family, with its own limits written down by Teltonika
+    pub const FM6XXX: DeviceProfile = DeviceProfile {
+        name: "FM6XXX",
+        max_frame_size: MAX_AVL_PACKET_SIZE_FM6XXX,
+        max_record_size: MAX_AVL_RECORD_SIZE_FM6XXX,
+    };
+
+    // The FMB family, a single record can take up the whole frame
+    pub const FMB: DeviceProfile = DeviceProfile {
+        name: "FMB",
+        max_frame_size: LARGEST_AVL_SIZE,
+        max_record_size: LARGEST_AVL_SIZE - FRAME_OVERHEAD,
+    };
+
+    // The FMC family, the same limits as the FMB family
+    pub const FMC: DeviceProfile = DeviceProfile {
+        name: "FMC",
+        max_frame_size: LARGEST_AVL_SIZE,
+        max_record_size: LARGEST_AVL_SIZE - FRAME_OVERHEAD,
+    };
+
+    // For when we don't know who we are talking to, the most any device could send
+    pub const ANY: DeviceProfile = DeviceProfile {
+        name: "any",
+        max_frame_size: LARGEST_AVL_SIZE,
+        max_record_size: LARGEST_AVL_SIZE - FRAME_OVERHEAD,
+    };
+
+    // The family that goes by the given name, however it is capitalised
+    pub fn named(name: &str) -> Option<DeviceProfile> {
+        [Self::FM6XXX, Self::FMB, Self::FMC, Self::ANY]
+            .into_iter()
+            .find(|profile| profile.name.eq_ignore_ascii_case(name))
+    }
+
+    // The largest frame we will wait for, never more than any device could send
+    pub fn frame_limit(&self) -> usize {
+        self.max_frame_size.min(LARGEST_AVL_SIZE)
+    }
+
+    // The largest record we will accept, never more than fits in the largest frame
+    pub fn record_limit(&self) -> usize {
+        self.max_record_size
+            .min(self.frame_limit().saturating_sub(FRAME_OVERHEAD))
+    }
+}
+
+impl Default for DeviceProfile {
+    fn default() -> Self {
+        DeviceProfile::ANY
+    }
+}
@@ -0,0 +1,257 @@
+use super::*;
+
+//   The PacketEncoder is the Parser in reverse. Where the librarian reads a book,
+//   the encoder writes one, in the exact same language the devices speak.
+//   We need it when we pass packets on upstream, when we pretend to be a fleet of
+//   devices, and when we write a packet back out after filtering it.
+// - buffer: The page we are writing on, kept around so we don't need a new one every time
+// - normalise_totals: Whether to put the IO totals right, even where nobody changed the groups
+//
+//   The record counts, the group counts, the data length and the CRC are worked out from
+//   what is actually in the packet, not taken from the packet's own fields. A packet that
+//   had records dropped or filtered out still comes out as a frame the Parser can read back.
+//   The IO total is the exception, see io_total. Some firmware writes it wrong, and the Parser
+//   lets that through with a warning, so we write it the way the device did.
+//   Anything the Parser has read in full comes out byte for byte the same when written again.
+pub struct PacketEncoder {
+    buffer: Vec<u8>,
+    normalise_totals: bool,
+}
+
+impl PacketEncoder {
+    pub fn new() -> Self {
+        Self {
+            buffer: Vec::with_capacity(LARGEST_AVL_SIZE),
+            normalise_totals: false,
+        }
+    }
+
+    // Write every IO total as what the groups add up to, instead of what the device wrote
+    pub fn set_normalise_totals(&mut self, normalise: bool) {
+        self.normalise_totals = normalise;
+    }
+
+    // Write a whole AVL packet, from the preamble to the CRC.
+    // Panics: When a count doesn't fit in its field, see count
+    pub fn encode(&mut self, packet: &AVLPacket) -> Vec<u8> {
+        self.buffer.clear();
+
+        self.write_u32(packet.preamble);
+
+        // We don't know the data length until we have written the data, so we leave room for it
+        self.write_u32(0);
+
+        // The device counts the records it sends, we count the ones we write
+        let records = count(packet.avl_data.len(), "records");
+        self.write_u8(packet.codec_id);
+        self.write_u8(records);
+        for data in &packet.avl_data {
+            self.encode_avl_data(data);
+        }
+        self.write_u8(records);
+
+        // The data length covers everything from the codec id to the second record count
+        let data_length = (self.buffer.len() - 8) as u32;
+        self.buffer[4..8].copy_from_slice(&data_length.to_be_bytes());
+
+        let crc = calculate_crc16(&self.buffer[8..]);
+        self.write_u32(crc);
+
+        self.buffer.clone()
+    }
+
+    fn encode_avl_data(&mut self, data: &AVLData) {
+        self.write_u64(data.timestamp);
+        self.write_u8(data.priority);
+
+        self.write_i32(data.gps.longitude);
+        self.write_i32(data.gps.latitude);
+        self.write_i16(data.gps.altitude);
+        self.write_i16(data.gps.angle);
+        self.write_u8(data.gps.satellites);
+        self.write_i16(data.gps.speed);
+
+        match &data.io {
+            IOElement::Codec8(io) => self.encode_codec8_io(io),
+            IOElement::Codec8Extended(io) => self.encode_codec8_extended_io(io),
+            IOElement::Codec16(io) => self.encode_codec16_io(io),
+        }
+    }
+
+    fn encode_codec8_io(&mut self, io: &IOElement8) {
+        self.write_u8(io.event_io_id);
+        let total = self.io_total(
+            io.n_total_io as usize,
+            &[
+                (io.n1_of_one_byte as usize, io.one_byte_ios.len()),
+                (io.n2_of_two_bytes as usize, io.two_byte_ios.len()),
+                (io.n4_of_four_bytes as usize, io.four_byte_ios.len()),
+                (io.n8_of_eight_bytes as usize, io.eight_byte_ios.len()),
+            ],
+        );
+        self.write_u8(count(total, "IO elements"));
+
+        self.write_u8(count(io.one_byte_ios.len(), "one byte IO elements"));
+        for (id, value) in &io.one_byte_ios {
+            self.write_u8(*id);
+            self.write_u8(*value);
+        }
+
+        self.write_u8(count(io.two_byte_ios.len(), "two byte IO elements"));
+        for (id, value) in &io.two_byte_ios {
+            self.write_u8(*id);
+            self.write_u16(*value);
+        }
+
+        self.write_u8(count(io.four_byte_ios.len(), "four byte IO elements"));
+        for (id, value) in &io.four_byte_ios {
+            self.write_u8(*id);
+            self.write_u32(*value);
+        }
+
+        self.write_u8(count(io.eight_byte_ios.len(), "eight byte IO elements"));
+        for (id, value) in &io.eight_byte_ios {
+            self.write_u8(*id);
+            self.write_u64(*value);
+        }
+    }
+
+    fn encode_codec8_extended_io(&mut self, io: &IOElement8Extended) {
+        self.write_u16(io.event_io_id);
+        let total = self.io_total(
+            io.n_total_io as usize,
+            &[
+                (io.n1_of_one_byte as usize, io.one_byte_ios.len()),
+                (io.n2_of_two_bytes as usize, io.two_byte_ios.len()),
+                (io.n4_of_four_bytes as usize, io.four_byte_ios.len()),
+                (io.n8_of_eight_bytes as usize, io.eight_byte_ios.len()),
+                (io.nx_of_var_bytes as usize, io.var_byte_ios.len()),
+            ],
+        );
+        self.write_u16(count(total, "IO elements"));
+
+        self.write_u16(count(io.one_byte_ios.len(), "one byte IO elements"));
+        for (id, value) in &io.one_byte_ios {
+            self.write_u16(*id);
+            self.write_u8(*value);
+        }
+
+        self.write_u16(count(io.two_byte_ios.len(), "two byte IO elements"));
+        for (id, value) in &io.two_byte_ios {
+            self.write_u16(*id);
+            self.write_u16(*value);
+        }
+
+        self.write_u16(count(io.four_byte_ios.len(), "four byte IO elements"));
+        for (id, value) in &io.four_byte_ios {
+            self.write_u16(*id);
+            self.write_u32(*value);
+        }
+
+        self.write_u16(count(io.eight_byte_ios.len(), "eight byte IO elements"));
+        for (id, value) in &io.eight_byte_ios {
+            self.write_u16(*id);
+            self.write_u64(*value);
+        }
+
+        self.write_u16(count(io.var_byte_ios.len(), "variable length IO elements"));
+        for (id, _, value) in &io.var_byte_ios {
+            self.write_u16(*id);
+            self.write_u16(count(value.len(), "bytes in a variable length IO value"));
+            self.buffer.extend_from_slice(value);
+        }
+    }
+
+    fn encode_codec16_io(&mut self, io: &IOElement16) {
+        self.write_u16(io.event_io_id);
+        self.write_u8(io.generation_type);
+        let total = self.io_total(
+            io.n_total_io as usize,
+            &[
+                (io.n1_of_one_byte as usize, io.one_byte_ios.len()),
+                (io.n2_of_two_bytes as usize, io.two_byte_ios.len()),
+                (io.n4_of_four_bytes as usize, io.four_byte_ios.len()),
+                (io.n8_of_eight_bytes as usize, io.eight_byte_ios.len()),
+            ],
+        );
+        self.write_u8(count(total, "IO elements"));
+
+        self.write_u8(count(io.one_byte_ios.len(), "one byte IO elements"));
+        for (id, value) in &io.one_byte_ios {
+            self.write_u16(*id);
+            self.write_u8(*value);
+        }
+
+        self.write_u8(count(io.two_byte_ios.len(), "two byte IO elements"));
+        for (id, value) in &io.two_byte_ios {
+            self.write_u16(*id);
+            self.write_u16(*value);
+        }
+
+        self.write_u8(count(io.four_byte_ios.len(), "four byte IO elements"));
+        for (id, value) in &io.four_byte_ios {
+            self.write_u16(*id);
+            self.write_u32(*value);
+        }
+
+        self.write_u8(count(io.eight_byte_ios.len(), "eight byte IO elements"));
+        for (id, value) in &io.eight_byte_ios {
+            self.write_u16(*id);
+            self.write_u64(*value);
+        }
+    }
+
+    //   The IO total as the device wrote it, so a frame from firmware that leaves a group
+    //   out of it comes out the same as it came in. Once the groups no longer hold what their
+    //   counts say, someone has changed them, and the old total means nothing anymore.
+    //   Then, or when we were asked to, the total is what the groups add up to.
+    // written: The total the packet holds
+    // groups: The count the packet holds for each group, and how many elements it really has
+    fn io_total(&self, written: usize, groups: &[(usize, usize)]) -> usize {
+        let edited = groups.iter().any(|(count, len)| count != len);
+        if self.normalise_totals || edited {
+            groups.iter().map(|(_, len)| len).sum()
+        } else {
+            written
+        }
+    }
+
+    // Everything on the wire is big-endian
+    fn write_u8(&mut self, value: u8) {
+        self.buffer.push(value);
+    }
+
+    fn write_u16(&mut self, value: u16) {
+        self.buffer.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn write_u32(&mut self, value: u32) {
+        self.buffer.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.buffer.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn write_i16(&mut self, value: i16) {
+        self.buffer.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn write_i32(&mut self, value: i32) {
+        self.buffer.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+// A count as wide as its field on the wire. One that doesn't fit would wrap around and still
+// get a good CRC, handing the device's readers a frame that lies, so we don't write it at all.
+// Panics: When there are more than the field can count
+fn count<T: TryFrom<usize>>(count: usize, what: &str) -> T {
+    T::try_from(count).unwrap_or_else(|_| panic!("{} {} don't fit in the frame", count, what))
+}
+
+impl AVLPacket {
+    // The packet as the device would have sent it, for when we only have the one to write
+    pub fn to_bytes(&self) -> Vec<u8> {
+        PacketEncoder::new().encode(self)
+    }
+}
@@ -1 +1,44 @@
 use super::*;
+use std::collections::HashSet;
+
+// The GateGuard is the bouncer standing at the door of our gateway.
+// Every device has to show its IMEI before it is let in, and the guard decides
+// if it is on the list, or at least looks like a proper IMEI.
+// - allowed: The guest list. If there is no list, anyone with a valid IMEI gets in.
+pub struct GateGuard {
+    allowed: Option<HashSet<String>>,
+}
+
+impl GateGuard {
+    // A guard without a guest list, letting in every device with a well formed IMEI
+    pub fn open() -> Self {
+        Self { allowed: None }
+    }
+
+    // A guard that only lets in the devices we have been told about
+    pub fn with_allowed<I, S>(imeis: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            allowed: Some(imeis.into_iter().map(Into::into).collect()),
+        }
+    }
+
+    // Looks the device in the eye and decides if it gets in.
+    // Returns the event the StateMachine should hear, AuthSuccess or AuthFailure.
+    pub fn check(&self, imei: &str) -> ProtocolEvent {
+        let well_formed = imei.len() == MAX_IMEI_LENGTH && imei.bytes().all(|b| b.is_ascii_digit());
+        let on_the_list = self
+            .allowed
+            .as_ref()
+            .is_none_or(|allowed| allowed.contains(imei));
+
+        if well_formed && on_the_list {
+            ProtocolEvent::AuthSuccess
+        } else {
+            ProtocolEvent::AuthFailure
+        }
+    }
+}
@@ -18,11 +18,16 @@ pub enum ProtocolEvent {
     Connect,
     Disconnect,
     ConnectionLost,
+    KeepAlive, // Device has nothing to say, but wants us to know it is still there
 
     // When we're talking to eachother and exchanging actual information
-    PacketReceived(AVLPacket),    // Device sent us a message
-    PacketSent(u32),              // We sent a message (with an ID)
-    AcknowledgementReceived(u32), // Device confirmed they got our message
+    PacketReceived(AVLPacket),                  // Device sent us a message
+    PacketSent(u32),                            // We sent a message (with an ID)
+    AcknowledgementReceived(u32),               // Device confirmed they got our message
+    CommandResponse(Codec12ResponsePacket),     // Device answered a command we sent
+    TimestampedResponse(Codec13ResponsePacket), // Device told us something, and when
+    ImeiCommandResponse(Codec14ResponsePacket), // Device answered a command addressed to an IMEI
+    ThirdPartyData(Codec15Packet),              // Device passed on what its serial device said
 
     // When devices need to prove who they are, it tells us its name
     Authenticate(String, String), // Device says "Hey, I'm device Fjordor"
@@ -35,6 +40,25 @@ pub enum ProtocolEvent {
     ProtocolError(String), // Something else went wrong, this thing is speaking in tongues.
 }
 
+// Whatever the Parser reads off the wire is something that happened in our conversation
+impl From<TeltonikaFrame> for ProtocolEvent {
+    fn from(frame: TeltonikaFrame) -> Self {
+        match frame {
+            TeltonikaFrame::Ping => ProtocolEvent::KeepAlive,
+            TeltonikaFrame::Imei(imei) => ProtocolEvent::Authenticate(imei, String::new()),
+            TeltonikaFrame::Avl(packet) => ProtocolEvent::PacketReceived(packet),
+            TeltonikaFrame::CommandResponse(response) => ProtocolEvent::CommandResponse(response),
+            TeltonikaFrame::TimestampedResponse(response) => {
+                ProtocolEvent::TimestampedResponse(response)
+            }
+            TeltonikaFrame::ImeiCommandResponse(response) => {
+                ProtocolEvent::ImeiCommandResponse(response)
+            }
+            TeltonikaFrame::ThirdPartyData(packet) => ProtocolEvent::ThirdPartyData(packet),
+        }
+    }
+}
+
 // ProtocolState is the different stages of our conversation
 // You can think of it as the relationship status between our system and the device
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -59,25 +83,29 @@ pub struct ProtocolResult {
 // Like our responses in the conversation
 #[derive(Debug)]
 pub enum ProtocolAction {
-    SendAcknowledgement(u32),   // "Yay! Got your message!"
-    RequestRetransmission(u32), // "Could you say that again?"
-    DisconnectClient,           // "Yeah, We need to end this conversation"
-    SendAuthResponse(bool),     // "Yes, I know you" or "No, who are you?"
-    ResetConnection,            // "Let's start over"
+    SendAcknowledgement(u32), // "Yay! Got your message!", with how many records we accepted
+    DisconnectClient,         // "Yeah, We need to end this conversation"
+    SendAuthResponse(bool),   // "Yes, I know you" or "No, who are you?"
+    ResetConnection,          // "Let's start over"
 }
 
 // The StateMachine is like a evesdropping receptionist who keeps track of:
 // - What stage each conversation is in
-// - What messages we're waiting for
-// - How long we've been waiting
+// - How many records we have accepted so far
+// - How long it has been since we last heard anything
 // - Who we're talking to
+//
+// Teltonika devices don't number their packets. We answer each packet with the
+// number of records we accepted, and if that answer never arrives, or the count is
+// wrong, the device sends the same data again on its own. So there is nothing
+// for us to chase, we just need to be honest about what we took in.
 pub struct StateMachine {
     state: ProtocolState,
-    sequence: u32,                        // Helps us keep messages in order
-    last_ack: u32,                        // Last message we confirmed
-    pending_packets: Vec<(u32, Instant)>, // Messages we're waiting for
-    timeout_duration: Duration,           // How long we're prepared to wait
-    imei: Option<String>,                 // The device's ID
+    records_acknowledged: u64, // Every record we have told the device we accepted
+    packets_acknowledged: u64, // Every packet we have answered
+    last_activity: Instant,    // When we last heard anything from the device
+    timeout_duration: Duration, // How long we're prepared to wait
+    imei: Option<String>,      // The device's ID
 }
 
 impl StateMachine {
@@ -86,18 +114,55 @@ impl StateMachine {
     pub fn new(timeout_duration: Duration) -> Self {
         Self {
             state: ProtocolState::Disconnected,
-            sequence: 0,
-            last_ack: 0,
-            pending_packets: Vec::new(),
+            records_acknowledged: 0,
+            packets_acknowledged: 0,
+            last_activity: Instant::now(),
             timeout_duration,
             imei: None,
         }
     }
 
+    // Where are we in the conversation right now?
+    pub fn state(&self) -> ProtocolState {
+        self.state
+    }
+
+    // Who are we talking to? Only known once the device has introduced itself
+    pub fn imei(&self) -> Option<&str> {
+        self.imei.as_deref()
+    }
+
+    // How many records and packets we have acknowledged during this conversation
+    // Returns: (records, packets)
+    pub fn ack_stats(&self) -> (u64, u64) {
+        (self.records_acknowledged, self.packets_acknowledged)
+    }
+
+    // How long it has been since we last heard anything from the device, pings included
+    pub fn idle_time(&self) -> Duration {
+        self.last_activity.elapsed()
+    }
+
+    // How long the device is allowed to stay quiet before we give up on it
+    pub fn timeout_duration(&self) -> Duration {
+        self.timeout_duration
+    }
+
+    pub fn set_timeout_duration(&mut self, timeout_duration: Duration) {
+        self.timeout_duration = timeout_duration;
+    }
+
+    // Has the device been quiet for longer than we are prepared to wait?
+    // If so, it is time to hand the StateMachine a Timeout.
+    pub fn is_timed_out(&self) -> bool {
+        self.idle_time() > self.timeout_duration
+    }
+
     // This is the main brain of our system, it decides what to say when things happen
     // Like a controlling partner that tells us how to responses to different situations.
     pub fn handle_event(&mut self, event: ProtocolEvent) -> ProtocolResult {
         let mut actions = Vec::new();
+        self.last_activity = Instant::now();
 
         let new_state = match (self.state, event) {
             // Handling new connections, telling us to pick up the phone.
@@ -108,39 +173,58 @@ impl StateMachine {
                 ProtocolState::Authenticating
             }
             // Device passed the identity check. Even though we don't want to, we know Bertil
-            (ProtocolState::Authenticating, ProtocolEvent::AuthSuccess) => ProtocolState::Ready,
+            (ProtocolState::Authenticating, ProtocolEvent::AuthSuccess) => {
+                actions.push(ProtocolAction::SendAuthResponse(true));
+                ProtocolState::Ready
+            }
+            // We don't know Bertil, tell him so and hang up before he starts talking
+            (ProtocolState::Authenticating, ProtocolEvent::AuthFailure) => {
+                actions.push(ProtocolAction::SendAuthResponse(false));
+                actions.push(ProtocolAction::DisconnectClient);
+                self.imei = None;
+                ProtocolState::Disconnected
+            }
 
             // When we're ready and receiving data, when to litsen to Bertils endless moaning.
             (ProtocolState::Ready, ProtocolEvent::PacketReceived(packet)) => {
-                actions.push(ProtocolAction::SendAcknowledgement(packet.crc16));
-                self.handle_packet(packet, &mut actions);
+                self.handle_packet(&packet, &mut actions);
                 ProtocolState::Ready
             }
 
+            // Bertil did what we asked of him and tells us how it went
+            (ProtocolState::Ready, ProtocolEvent::CommandResponse(_)) => ProtocolState::Ready,
+            (ProtocolState::Ready, ProtocolEvent::TimestampedResponse(_)) => ProtocolState::Ready,
+
+            // Even when it turns out the command wasn't for him, Bertil is still Bertil
+            (ProtocolState::Ready, ProtocolEvent::ImeiCommandResponse(_)) => ProtocolState::Ready,
+
+            // Bertil passes on what his friend on the serial port said, no reply needed
+            (ProtocolState::Ready, ProtocolEvent::ThirdPartyData(_)) => ProtocolState::Ready,
+
             // Device confirmed they got our message, Bertil starts yapping.
-            (ProtocolState::Ready, ProtocolEvent::AcknowledgementReceived(packet_id)) => {
-                self.handle_acknowledgement(packet_id);
+            (ProtocolState::Ready, ProtocolEvent::AcknowledgementReceived(_)) => {
                 ProtocolState::Ready
             }
 
+            // Bertil is still on the line, he just has nothing to say. Nothing changes,
+            // other than us knowing he hasn't wandered off.
+            (state, ProtocolEvent::KeepAlive) => state,
+
             // Oh no, we lost connection. Totally by accident, such an unfortunate turn of events...
-            (current_state, ProtocolEvent::ConnectionLost) => {
+            (_, ProtocolEvent::ConnectionLost) => {
                 actions.push(ProtocolAction::ResetConnection);
                 ProtocolState::Disconnected
             }
 
-            // We waited too long for a response, is he dead?
-            (current_state, ProtocolEvent::Timeout) => {
-                self.handle_timeout(&mut actions);
-                if current_state != ProtocolState::Error {
-                    ProtocolState::Error
-                } else {
-                    current_state
-                }
+            // We waited too long for a response, is he dead? Either way, we hang up.
+            // The device will call back and resend whatever we never acknowledged.
+            (_, ProtocolEvent::Timeout) => {
+                actions.push(ProtocolAction::DisconnectClient);
+                ProtocolState::Error
             }
 
             // Something unexpected happened
-            (current_state, _) => {
+            (_, _) => {
                 actions.push(ProtocolAction::DisconnectClient);
                 ProtocolState::Error
             }
@@ -153,52 +237,13 @@ impl StateMachine {
         }
     }
 
-    // When we receive a packet, we need to:
-    // 1. Keep track of it
-    // 2. Make sure we're not missing any earlier packets
-    // 3. Let the device know we got it
-    fn handle_packet(&mut self, packet: AVLPacket, actions: &mut Vec<ProtocolAction>) {
-        // Remember this packet if we haven't seen it before
-        if !self
-            .pending_packets
-            .iter()
-            .any(|(id, _)| *id == packet.crc16)
-        {
-            self.pending_packets.push((packet.crc16, Instant::now()));
-        }
-
-        // Check if we missed any packets
-        if packet.crc16 != self.last_ack + 1 {
-            actions.push(ProtocolAction::RequestRetransmission(self.last_ack + 1));
-        } else {
-            self.last_ack = packet.crc16;
-        }
-    }
-
-    // When device confirms they got our message, we can stop waiting for it
-    fn handle_acknowledgement(&mut self, packet_id: u32) {
-        self.pending_packets.retain(|(id, _)| *id != packet_id);
-    }
-
-    // Check if we've been waiting too long for any messages
-    // going over all the unanswered texts
-    fn handle_timeout(&mut self, actions: &mut Vec<ProtocolAction>) {
-        // Check for timed out packets
-        let now = Instant::now();
-        let timed_out: Vec<_> = self
-            .pending_packets
-            .iter()
-            .filter(|(_, timestamp)| now.duration_since(*timestamp) > self.timeout_duration)
-            .map(|(id, _)| *id)
-            .collect();
-
-        // We promised ourselves we wouldn't, but we text back for any messages we've waited too long for
-        for packet_id in timed_out {
-            actions.push(ProtocolAction::RequestRetransmission(packet_id));
-        }
-
-        // We clean up our waiting list and move on
-        self.pending_packets
-            .retain(|(_, timestamp)| now.duration_since(*timestamp) <= self.timeout_duration);
+    // When we receive a packet, we tell the device how many records we accepted.
+    // The Parser only hands us packets where both record counts agree, so that is
+    // the number the device expects to hear back, as a 4 byte big-endian count.
+    fn handle_packet(&mut self, packet: &AVLPacket, actions: &mut Vec<ProtocolAction>) {
+        let accepted = packet.number_of_data1 as u32;
+        self.records_acknowledged += accepted as u64;
+        self.packets_acknowledged += 1;
+        actions.push(ProtocolAction::SendAcknowledgement(accepted));
     }
 }
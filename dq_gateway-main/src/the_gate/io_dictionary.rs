@@ -0,0 +1,258 @@
+use super::*;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// The IO elements we know out of the box, see the file for its shape
+const BUILT_IN: &str = include_str!("io_dictionary.csv");
+
+// The family that stands for every family in the dictionary
+const EVERY_FAMILY: &str = "*";
+
+// How a device writes the value of an IO element
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoType {
+    U8,
+    U16,
+    U32,
+    U64,
+    Bytes,
+}
+
+//   What an IO id means, one line of the dictionary
+// - id: The AVL id, as the device sends it
+// - name: What the analysts call it, "External Voltage"
+// - value_type: How the device writes the value
+// - unit: What the scaled value is measured in, empty if it is just a number
+// - multiplier: What the raw value is multiplied with to get to the unit
+// - signed: If the raw value is two's complement
+#[derive(Debug, Clone, PartialEq)]
+pub struct IoDefinition {
+    pub id: u16,
+    pub name: String,
+    pub value_type: IoType,
+    pub unit: String,
+    pub multiplier: f64,
+    pub signed: bool,
+}
+
+impl IoDefinition {
+    // Whether the value is as wide as the dictionary says it should be. When it isn't,
+    // the page was written for other firmware, and scaling the value by it would only mislead.
+    pub fn fits(&self, raw: &IoValue) -> bool {
+        matches!(
+            (self.value_type, raw),
+            (IoType::U8, IoValue::U8(_))
+                | (IoType::U16, IoValue::U16(_))
+                | (IoType::U32, IoValue::U32(_))
+                | (IoType::U64, IoValue::U64(_))
+                | (IoType::Bytes, IoValue::Bytes(_))
+        )
+    }
+}
+
+//   The librarian's dictionary, so "66: 12840" can be read as "External Voltage: 12.84 V".
+//   The same id can mean something (slightly) different to different device families,
+//   so every family gets its own pages, on top of the ones that hold for all of them.
+//
+//   The built in pages come from io_dictionary.csv. Ids that new firmware brings along
+//   can be added from a file in the same shape, without building the gateway again.
+// families: The definitions by DeviceProfile name, then by id. "*" holds for every family.
+#[derive(Debug, Clone, Default)]
+pub struct IoDictionary {
+    families: HashMap<String, HashMap<u16, IoDefinition>>,
+}
+
+impl IoDictionary {
+    // A dictionary without a single page in it
+    pub fn empty() -> Self {
+        Self::default()
+    }
+
+    // The IO elements we know out of the box
+    pub fn built_in() -> Self {
+        let mut dictionary = Self::empty();
+        dictionary
+            .extend_from_str(BUILT_IN)
+            .expect("the built in IO dictionary is well formed");
+        dictionary
+    }
+
+    // The built in dictionary, with whatever the file adds or changes on top
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut dictionary = Self::built_in();
+        dictionary.extend_from_file(path)?;
+        Ok(dictionary)
+    }
+
+    // Add the definitions in the file, replacing the ones we had for the same family and id
+    pub fn extend_from_file(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.extend_from_str(&fs::read_to_string(path)?)
+    }
+
+    //   Add the definitions in text shaped like io_dictionary.csv, one per line:
+    //   family, id, name, type, unit, multiplier, signed
+    //   Empty lines and lines starting with # are skipped.
+    //   Nothing is added if a single line is wrong, the error says which one.
+    pub fn extend_from_str(&mut self, text: &str) -> io::Result<()> {
+        let mut definitions = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let definition = parse_line(line).map_err(|reason| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("IO dictionary line {}: {}", index + 1, reason),
+                )
+            })?;
+            definitions.push(definition);
+        }
+
+        for (family, definition) in definitions {
+            self.insert(&family, definition);
+        }
+        Ok(())
+    }
+
+    // Add a single definition for a family, "*" for every family
+    pub fn insert(&mut self, family: &str, definition: IoDefinition) {
+        self.families
+            .entry(family.to_string())
+            .or_default()
+            .insert(definition.id, definition);
+    }
+
+    // What the id means to a device of the given family, if we know
+    pub fn lookup(&self, profile: &DeviceProfile, id: u16) -> Option<&IoDefinition> {
+        [profile.name, EVERY_FAMILY]
+            .iter()
+            .find_map(|family| self.families.get(*family)?.get(&id))
+    }
+
+    // Put a name and a unit to a value straight out of a record.
+    // A value that doesn't fit the page we have for its id is handed out as if we didn't know it.
+    pub fn describe<'v>(
+        &self,
+        profile: &DeviceProfile,
+        id: u16,
+        raw: IoValue<'v>,
+    ) -> NamedIo<'_, 'v> {
+        NamedIo {
+            id,
+            definition: self
+                .lookup(profile, id)
+                .filter(|definition| definition.fits(&raw)),
+            raw,
+        }
+    }
+}
+
+// One line of the dictionary, along with the family it is for
+fn parse_line(line: &str) -> Result<(String, IoDefinition), String> {
+    let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+    let [family, id, name, value_type, unit, multiplier, signed] = fields[..] else {
+        return Err(format!("expected 7 fields, found {}", fields.len()));
+    };
+
+    if family.is_empty() {
+        return Err("the family is empty, use * for every family".to_string());
+    }
+    if name.is_empty() {
+        return Err("the name is empty".to_string());
+    }
+    let id = id
+        .parse()
+        .map_err(|_| format!("{:?} is not an IO id", id))?;
+    let value_type = match value_type {
+        "u8" => IoType::U8,
+        "u16" => IoType::U16,
+        "u32" => IoType::U32,
+        "u64" => IoType::U64,
+        "bytes" => IoType::Bytes,
+        other => {
+            return Err(format!(
+                "{:?} is not a type, use u8, u16, u32, u64 or bytes",
+                other
+            ))
+        }
+    };
+    let multiplier: f64 = multiplier
+        .parse()
+        .ok()
+        .filter(|multiplier: &f64| multiplier.is_finite())
+        .ok_or_else(|| format!("{:?} is not a multiplier", multiplier))?;
+    let signed = match signed {
+        "signed" => true,
+        "unsigned" => false,
+        other => return Err(format!("{:?} is not signed or unsigned", other)),
+    };
+
+    Ok((
+        family.to_string(),
+        IoDefinition {
+            id,
+            name: name.to_string(),
+            value_type,
+            unit: unit.to_string(),
+            multiplier,
+            signed,
+        },
+    ))
+}
+
+//   An IO value with its dictionary page next to it, if there is one.
+//   The raw value is kept as the device sent it, the scaled value is worked out when asked.
+// - id: The AVL id
+// - definition: What the dictionary says about the id, None if it doesn't know it
+// - raw: The value as it came off the wire
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NamedIo<'d, 'v> {
+    pub id: u16,
+    pub definition: Option<&'d IoDefinition>,
+    pub raw: IoValue<'v>,
+}
+
+impl<'d> NamedIo<'d, '_> {
+    pub fn name(&self) -> Option<&'d str> {
+        self.definition.map(|definition| definition.name.as_str())
+    }
+
+    // The unit of scaled(), empty for plain numbers and ids we don't know
+    pub fn unit(&self) -> &'d str {
+        self.definition
+            .map_or("", |definition| definition.unit.as_str())
+    }
+
+    //   The value in its unit, read as signed if the dictionary says so.
+    //   Ids we don't know are handed out as they are, variable length values aren't numbers.
+    pub fn scaled(&self) -> Option<f64> {
+        let Some(definition) = self.definition else {
+            return self.raw.as_u64().map(|value| value as f64);
+        };
+
+        let value = match (self.raw, definition.signed) {
+            (IoValue::U8(value), true) => value as i8 as f64,
+            (IoValue::U16(value), true) => value as i16 as f64,
+            (IoValue::U32(value), true) => value as i32 as f64,
+            (IoValue::U64(value), true) => value as i64 as f64,
+            (raw, _) => raw.as_u64()? as f64,
+        };
+        Some(value * definition.multiplier)
+    }
+}
+
+impl AVLData {
+    // Every IO element of the record, with its name and unit for a device of the given family
+    pub fn named_ios<'a, 'd: 'a>(
+        &'a self,
+        dictionary: &'d IoDictionary,
+        profile: &DeviceProfile,
+    ) -> impl Iterator<Item = NamedIo<'d, 'a>> + 'a {
+        let profile = *profile;
+        self.io
+            .iter()
+            .map(move |(id, raw)| dictionary.describe(&profile, id, raw))
+    }
+}
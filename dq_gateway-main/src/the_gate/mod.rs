@@ -1,10 +1,10 @@
 //#############################################################################################
 //#                                 IMPORTANT INFORMATION                                     #
 //#############################################################################################
-//#   The codebase is at the moment synchronus. This should be amended when we have a working #
-//#   prototype. at the moment, if i am not being too doom and gloom,                         #
-//#   somewhere around 70%+ of the time used by this approach would likely                    #
-//#   be on just waiting for things.                                                          #
+//#   the_gate holds everything between a device and the post office. A Session talks to      #
+//#   a device the same way on a blocking line (the DeviceListener, one thread per device)    #
+//#   and on a non-blocking one (the EventLoop, epoll on Linux). Only the Connection still    #
+//#   waits and sleeps between retries when it is the one calling out.                        #
 //#############################################################################################
 
 #![cfg_attr(rustfmt, rustfmt_skip)]
@@ -18,7 +18,7 @@
 /////////////////////////////////\\\\\\\\\\\\\\\\\\\\\\\\\\\\\\\\\
 
 
-use std::io::Read;
+use std::io::{Read, Write};
 use std::time::{Duration, Instant};
 use std::io::{self};
 use std::net::{SocketAddr, TcpStream};
@@ -28,7 +28,18 @@ use std::collections::VecDeque;
 //-------------IMPORTS---------------\\
 pub mod the_teltonica_protocol;
 pub mod binary_parser;
+pub mod device_profile;
+pub mod io_dictionary;
+pub mod receive_buffer;
+pub mod encoder;
+pub mod packet_view;
+pub mod parse_error;
 pub mod the_connector;
+pub mod the_listener;
+pub mod the_udp_listener;
+pub mod the_session;
+#[cfg(target_os = "linux")]
+pub mod the_event_loop;
 pub mod gate_guard;
 pub mod gate_state;
 pub mod pipeline;
@@ -41,7 +52,20 @@ pub mod testing;
 //-------------EXPORTS---------------\\
 pub use the_teltonica_protocol::*;
 pub use the_connector::*;
+// On Linux main answers the phones with the EventLoop instead
+#[cfg(any(test, not(target_os = "linux")))]
+pub use the_listener::*;
+pub use the_udp_listener::*;
+pub use the_session::*;
+#[cfg(target_os = "linux")]
+pub use the_event_loop::*;
 pub use binary_parser::*;
+pub use device_profile::*;
+pub use io_dictionary::*;
+pub use receive_buffer::*;
+pub use encoder::*;
+pub use packet_view::*;
+pub use parse_error::*;
 pub use gate_state::*;
 pub use gate_guard::*;
 pub use pipeline::*;
@@ -60,5 +84,25 @@ pub use testing::*;
       pub const MAX_AVL_PACKET_SIZE_FM6XXX: usize = 512;          //|\
 //    The maximum possible size  packet of any devices            //|\
       pub const LARGEST_AVL_SIZE: usize = 1280;                   //|\
+//    How many digits an IMEI has, no more and no less            //|\
+      pub const MAX_IMEI_LENGTH: usize = 15;                      //|\
+//    The Codec 12 type byte of a command sent to a device        //|\
+      pub const CODEC12_COMMAND_TYPE: u8 = 0x05;                  //|\
+//    The Codec 12 type byte of a response sent by a device       //|\
+      pub const CODEC12_RESPONSE_TYPE: u8 = 0x06;                 //|\
+//    The smallest Codec 12 data length, an empty command/answer  //|\
+      pub const SMALLEST_CODEC12_DATA_LENGTH: usize = 8;          //|\
+//    How much a Parser holds on to, twice the largest frame, so  //|\
+//    we don't have to tidy up the buffer after every frame       //|\
+      pub const RECEIVE_BUFFER_SIZE: usize =                      //|\
+          2 * LARGEST_AVL_SIZE;                                   //|\
+//    The Codec 14 type byte of a device that ran our command     //|\
+      pub const CODEC14_ACK_TYPE: u8 = 0x06;                      //|\
+//    The Codec 14 type byte of a device the command wasn't for   //|\
+      pub const CODEC14_NACK_TYPE: u8 = 0x11;                     //|\
+//    The Codec 15 type byte of serial data passed on by a device //|\
+      pub const CODEC15_MESSAGE_TYPE: u8 = 0x01;                  //|\
+//    The single byte an open link device pings us with           //|\
+      pub const KEEPALIVE_PING: u8 = 0xFF;                        //|\
 //------------------------------------------------------------------|\
 //-------------------------------------------------------------------\
@@ -0,0 +1,801 @@
+use super::*;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+//   Copying every letter into a fresh notebook before reading it is a lot of work when
+//   most of the time we only want to glance at a couple of lines. The views below read the
+//   letter right where it lies, in the Parser's buffer, and only decode a field when
+//   someone asks for it. When a record has to outlive the buffer, we copy it out then.
+//
+//   The frame is checked once, up front, the same way the Parser checks an owned packet:
+//   preamble, length, codec, that every record fits, the record counts and the CRC.
+//   After that, reading a field can't go wrong, so none of the accessors return errors.
+
+//   What to do with a record that is well formed, but says something that can't be true,
+//   like a timestamp of 0 or a position off the map. Some older units send zeroed
+//   records before their first fix, and turning down the whole packet over those only
+//   gets us the same packet again, and again.
+// - Strict: The whole packet is turned down, the way it has always been
+// - Lenient: The record is kept, with what is wrong with it written down in its warnings
+// - DropBadRecords: The record is left out, the rest of the packet is kept
+//   Whatever is kept or left out, the device is acknowledged for every record it sent.
+//   An IO total that doesn't add up is only ever written down, whatever the policy.
+//   The groups themselves are read by their own counts, so nothing in the record is wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ValidationPolicy {
+    #[default]
+    Strict,
+    Lenient,
+    DropBadRecords,
+}
+
+impl ValidationPolicy {
+    // The policy that goes by the given name: strict, lenient or drop
+    pub fn named(name: &str) -> Option<ValidationPolicy> {
+        match name.to_ascii_lowercase().as_str() {
+            "strict" => Some(ValidationPolicy::Strict),
+            "lenient" => Some(ValidationPolicy::Lenient),
+            "drop" => Some(ValidationPolicy::DropBadRecords),
+            _ => None,
+        }
+    }
+}
+
+// The parts of the IO layout that differ between the codecs
+// codec_id: The codec the record is written in
+// id_size: How many bytes an IO id takes
+// count_size: How many bytes the count in front of each group takes
+// header_size: The event id, the total count and, for Codec 16, the generation type
+// var_group: Whether the variable length group follows the eight byte one (Codec 8E only)
+#[derive(Debug, Clone, Copy)]
+struct IoLayout {
+    codec_id: u8,
+    id_size: usize,
+    count_size: usize,
+    header_size: usize,
+    var_group: bool,
+}
+
+impl IoLayout {
+    fn of(codec_id: u8) -> Self {
+        match codec_id {
+            0x8E => IoLayout {
+                codec_id,
+                id_size: 2,
+                count_size: 2,
+                header_size: 4,
+                var_group: true,
+            },
+            0x10 => IoLayout {
+                codec_id,
+                id_size: 2,
+                count_size: 1,
+                header_size: 4,
+                var_group: false,
+            },
+            _ => IoLayout {
+                codec_id,
+                id_size: 1,
+                count_size: 1,
+                header_size: 2,
+                var_group: false,
+            },
+        }
+    }
+}
+
+// The fixed size groups, in the order they come in, with what we call them in errors
+const IO_GROUPS: [(usize, &str); 4] = [
+    (1, "one-byte IO elements"),
+    (2, "two-byte IO elements"),
+    (4, "four-byte IO elements"),
+    (8, "eight-byte IO elements"),
+];
+
+// Timestamp(8), priority(1) and the GPS element(15) come before the IO
+const IO_START: usize = 24;
+
+// A big-endian number of up to 8 bytes, the bytes are always there once the frame is checked
+fn be(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(0, |value, &byte| (value << 8) | byte as u64)
+}
+
+//   A finger on the page, that never moves past the end of the frame.
+//   It reads the same way the Parser does, and fails the same way.
+struct Reader<'a> {
+    frame: &'a [u8],
+    position: usize,
+    end: usize,
+    codec: Option<u8>,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize, what: &'static str) -> Result<&'a [u8], ParseError> {
+        let start = self.position;
+        let end = match start.checked_add(count) {
+            Some(end) if end <= self.end => end,
+            _ => {
+                return Err(ParseError::Incomplete {
+                    needed: what,
+                    offset: start,
+                    codec: self.codec,
+                })
+            }
+        };
+        self.position = end;
+        Ok(&self.frame[start..end])
+    }
+
+    fn read(&mut self, size: usize, what: &'static str) -> Result<u64, ParseError> {
+        Ok(be(self.take(size, what)?))
+    }
+
+    // The codec id, as long as it is one we know how to read
+    fn read_codec(&mut self) -> Result<u8, ParseError> {
+        let codec_offset = self.position;
+        let codec_id = self.read(1, "codec id")? as u8;
+        self.codec = Some(codec_id);
+        if !matches!(codec_id, 0x08 | 0x8E | 0x10) {
+            return Err(ParseError::UnsupportedCodec {
+                offset: codec_offset,
+                codec: self.codec,
+            });
+        }
+        Ok(codec_id)
+    }
+
+    //   Step over the records and the two counts around them, the part of an AVL packet
+    //   that is the same over TCP and UDP. Every record has to fit, within the limits of the
+    //   profile, and Strict turns down the first record that can't be true.
+    //   A mismatched IO total doesn't count as one, see AVLDataRef::io_warnings.
+    fn skip_records(
+        &mut self,
+        codec_id: u8,
+        profile: &DeviceProfile,
+        policy: ValidationPolicy,
+    ) -> Result<(), ParseError> {
+        let layout = IoLayout::of(codec_id);
+        let number_of_data1 = self.read(1, "record count")? as u8;
+        for _ in 0..number_of_data1 {
+            let record_offset = self.position;
+            self.skip_record_header()?;
+
+            // A record that can't be true is turned down before we look any further,
+            // the bytes after it are likely no better
+            let strict = policy == ValidationPolicy::Strict;
+            let frame = self.frame;
+            let record = |end| AVLDataRef {
+                record: &frame[record_offset..end],
+                offset: record_offset,
+                layout,
+            };
+            if strict {
+                if let Some(warning) = record(self.position).header_warnings().into_iter().next() {
+                    return Err(warning);
+                }
+            }
+            self.skip_io(layout)?;
+
+            let length = self.position - record_offset;
+            if length > profile.record_limit() {
+                return Err(ParseError::RecordTooLarge {
+                    length,
+                    maximum: profile.record_limit(),
+                    offset: record_offset,
+                    codec: self.codec,
+                });
+            }
+        }
+
+        let count_offset = self.position;
+        let number_of_data2 = self.read(1, "second record count")? as u8;
+        if number_of_data1 != number_of_data2 {
+            return Err(ParseError::RecordCountMismatch {
+                first: number_of_data1,
+                second: number_of_data2,
+                offset: count_offset,
+                codec: self.codec,
+            });
+        }
+        Ok(())
+    }
+
+    // Step over one AVL record, making sure all of it is inside the frame
+    fn skip_avl_data(&mut self, layout: IoLayout) -> Result<(), ParseError> {
+        self.skip_record_header()?;
+        self.skip_io(layout)
+    }
+
+    // The timestamp, priority and GPS element, everything in front of the IO
+    fn skip_record_header(&mut self) -> Result<(), ParseError> {
+        self.take(8, "timestamp")?;
+        self.take(1, "priority")?;
+        self.take(15, "GPS data")?;
+        Ok(())
+    }
+
+    fn skip_io(&mut self, layout: IoLayout) -> Result<(), ParseError> {
+        self.take(layout.header_size, "IO header")?;
+
+        for (size, what) in IO_GROUPS {
+            let count = self.read(layout.count_size, "IO element count")?;
+            for _ in 0..count {
+                self.take(layout.id_size + size, what)?;
+            }
+        }
+
+        if layout.var_group {
+            let count = self.read(layout.count_size, "variable length elements count")?;
+            for _ in 0..count {
+                self.take(layout.id_size, "variable length IO element header")?;
+                let length = self.read(2, "variable length IO element header")?;
+                self.take(length as usize, "variable length IO element value")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+//   An AVL packet (Codec 8, 8E or 16) that is still sitting in the buffer it arrived in.
+//   It is cheap to make and cheap to copy, it is only a reference to the bytes.
+// policy: What to do with the records that say something that can't be true
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AVLPacketRef<'a> {
+    frame: &'a [u8],
+    policy: ValidationPolicy,
+}
+
+impl<'a> AVLPacketRef<'a> {
+    // Check the frame at the start of the bytes, and look at it where it lies.
+    // Anything after the frame is left alone.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, ParseError> {
+        Self::parse_with_profile(bytes, &DeviceProfile::ANY)
+    }
+
+    // The same, held to the limits of a particular kind of device
+    pub fn parse_with_profile(
+        bytes: &'a [u8],
+        profile: &DeviceProfile,
+    ) -> Result<Self, ParseError> {
+        Self::parse_with_policy(bytes, profile, ValidationPolicy::Strict)
+    }
+
+    // The same, with our own say in what happens to records that can't be true
+    pub fn parse_with_policy(
+        bytes: &'a [u8],
+        profile: &DeviceProfile,
+        policy: ValidationPolicy,
+    ) -> Result<Self, ParseError> {
+        let length = Self::validate(bytes, profile, policy)?;
+        Ok(Self::from_checked_frame(&bytes[..length], policy))
+    }
+
+    // Everything the Parser checks before it hands out an AVL packet, without keeping any of it.
+    // Returns: How long the frame is
+    pub(crate) fn validate(
+        bytes: &[u8],
+        profile: &DeviceProfile,
+        policy: ValidationPolicy,
+    ) -> Result<usize, ParseError> {
+        let mut reader = Reader {
+            frame: bytes,
+            position: 0,
+            end: bytes.len(),
+            codec: None,
+        };
+
+        let preamble = reader.read(4, "preamble")? as u32;
+        if preamble != 0x00000000 {
+            return Err(ParseError::BadPreamble {
+                found: preamble,
+                offset: 0,
+                codec: None,
+            });
+        }
+
+        let data_length = reader.read(4, "data length")? as usize;
+        let total_length = data_length.saturating_add(12);
+        if total_length > profile.frame_limit() {
+            return Err(ParseError::FrameTooLarge {
+                length: total_length,
+                maximum: profile.frame_limit(),
+                offset: 4,
+                codec: None,
+            });
+        }
+        if bytes.len() < total_length {
+            return Err(ParseError::Incomplete {
+                needed: "the whole frame",
+                offset: bytes.len(),
+                codec: None,
+            });
+        }
+        reader.end = total_length;
+
+        let codec_id = reader.read_codec()?;
+
+        if total_length < SMALLEST_AVL_SIZE {
+            return Err(ParseError::FrameTooSmall {
+                length: total_length,
+                minimum: SMALLEST_AVL_SIZE,
+                offset: 0,
+                codec: reader.codec,
+            });
+        }
+
+        reader.skip_records(codec_id, profile, policy)?;
+
+        // The records have to fill the frame exactly, anything left over is something
+        // the device said was there, that we haven't found a place for
+        let crc_start = reader.position;
+        if crc_start + 4 != total_length {
+            return Err(ParseError::LengthMismatch {
+                declared: data_length,
+                consumed: crc_start - 8,
+                offset: crc_start,
+                codec: reader.codec,
+            });
+        }
+        let crc = reader.read(4, "record count and CRC")? as u32;
+        let calculated_crc = calculate_crc16(&bytes[8..crc_start]);
+        if crc != calculated_crc {
+            return Err(ParseError::CrcMismatch {
+                expected: calculated_crc,
+                actual: crc,
+                offset: crc_start,
+                codec: reader.codec,
+            });
+        }
+
+        Ok(total_length)
+    }
+
+    // For a frame that has already been through validate(), and is exactly that long
+    pub(crate) fn from_checked_frame(frame: &'a [u8], policy: ValidationPolicy) -> Self {
+        Self { frame, policy }
+    }
+
+    // The frame exactly as it came in, from the preamble to the CRC
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.frame
+    }
+
+    pub fn data_length(&self) -> u32 {
+        be(&self.frame[4..8]) as u32
+    }
+
+    pub fn codec_id(&self) -> u8 {
+        self.frame[8]
+    }
+
+    // The number of records the device sent, the two counts are known to match.
+    // This is what the device is acknowledged for, even when records() leaves some out.
+    pub fn number_of_data(&self) -> u8 {
+        self.frame[9]
+    }
+
+    pub fn crc16(&self) -> u32 {
+        be(&self.frame[self.frame.len() - 4..]) as u32
+    }
+
+    // The records one after the other, each found when we get to it.
+    // With ValidationPolicy::DropBadRecords, the ones that can't be true are skipped.
+    pub fn records(&self) -> Records<'a> {
+        Records::new(self.frame, 8, self.frame.len() - 4, self.policy)
+    }
+
+    // Copy the whole packet out of the buffer, for when it has to live on without it
+    pub fn to_packet(self) -> AVLPacket {
+        let number_of_data = self.number_of_data();
+        AVLPacket {
+            preamble: 0x00000000,
+            data_length: self.data_length(),
+            codec_id: self.codec_id(),
+            number_of_data1: number_of_data,
+            avl_data: self.records().map(|record| record.to_avl_data()).collect(),
+            number_of_data2: number_of_data,
+            crc16: self.crc16(),
+        }
+    }
+}
+
+//   The AVL part of a UDP datagram, from the codec id at start to the end of the datagram.
+//   UDP looks after the bytes itself, so there is no preamble, data length or CRC around it,
+//   the records are checked the same way as over TCP.
+//   Returns: The packet copied out, with the length of the AVL part as its data length and no CRC
+pub(crate) fn parse_udp_avl(
+    datagram: &[u8],
+    start: usize,
+    profile: &DeviceProfile,
+    policy: ValidationPolicy,
+) -> Result<AVLPacket, ParseError> {
+    let mut reader = Reader {
+        frame: datagram,
+        position: start,
+        end: datagram.len(),
+        codec: None,
+    };
+    let codec_id = reader.read_codec()?;
+    reader.skip_records(codec_id, profile, policy)?;
+
+    if reader.position != datagram.len() {
+        return Err(ParseError::LengthMismatch {
+            declared: datagram.len() - start,
+            consumed: reader.position - start,
+            offset: reader.position,
+            codec: reader.codec,
+        });
+    }
+
+    let number_of_data = datagram[start + 1];
+    Ok(AVLPacket {
+        preamble: 0x00000000,
+        data_length: (datagram.len() - start) as u32,
+        codec_id,
+        number_of_data1: number_of_data,
+        avl_data: Records::new(datagram, start, datagram.len(), policy)
+            .map(|record| record.to_avl_data())
+            .collect(),
+        number_of_data2: number_of_data,
+        crc16: 0,
+    })
+}
+
+//   Walks through the records of an AVLPacketRef, one at a time
+pub struct Records<'a> {
+    reader: Reader<'a>,
+    layout: IoLayout,
+    remaining: u8,
+    policy: ValidationPolicy,
+}
+
+impl<'a> Records<'a> {
+    // The records of a checked packet that runs from the codec id at start
+    // to the second record count, just before end
+    fn new(frame: &'a [u8], start: usize, end: usize, policy: ValidationPolicy) -> Self {
+        let codec_id = frame[start];
+        Records {
+            reader: Reader {
+                frame,
+                position: start + 2,
+                end: end - 1,
+                codec: Some(codec_id),
+            },
+            layout: IoLayout::of(codec_id),
+            remaining: frame[start + 1],
+            policy,
+        }
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = AVLDataRef<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.remaining > 0 {
+            self.remaining -= 1;
+
+            // The frame has been checked, so this only stops early if someone lied to us about that
+            let start = self.reader.position;
+            self.reader.skip_avl_data(self.layout).ok()?;
+            let record = AVLDataRef {
+                record: &self.reader.frame[start..self.reader.position],
+                offset: start,
+                layout: self.layout,
+            };
+
+            let dropped = self.policy == ValidationPolicy::DropBadRecords
+                && !record.header_warnings().is_empty();
+            if !dropped {
+                return Some(record);
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.remaining as usize))
+    }
+}
+
+//   A single AVL record, still in the buffer
+// offset: Where in the frame the record starts
+#[derive(Debug, Clone, Copy)]
+pub struct AVLDataRef<'a> {
+    record: &'a [u8],
+    offset: usize,
+    layout: IoLayout,
+}
+
+impl<'a> AVLDataRef<'a> {
+    pub fn timestamp(&self) -> u64 {
+        be(&self.record[0..8])
+    }
+
+    // When the record was written, the same as AVLData::time()
+    pub fn time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.timestamp())
+    }
+
+    pub fn priority(&self) -> u8 {
+        self.record[8]
+    }
+
+    // The GPS element is small and fixed in size, so we hand out a copy
+    pub fn gps(&self) -> GPSElement {
+        GPSElement {
+            longitude: be(&self.record[9..13]) as u32 as i32,
+            latitude: be(&self.record[13..17]) as u32 as i32,
+            altitude: be(&self.record[17..19]) as u16 as i16,
+            angle: be(&self.record[19..21]) as u16 as i16,
+            satellites: self.record[21],
+            speed: be(&self.record[22..24]) as u16 as i16,
+        }
+    }
+
+    pub fn io(&self) -> IOElementRef<'a> {
+        IOElementRef {
+            io: &self.record[IO_START..],
+            layout: self.layout,
+        }
+    }
+
+    // Everything the record says that can't be true: a timestamp at the very start of time,
+    // a position that isn't on this planet, or an IO total that doesn't add up.
+    // Empty for a record that is fine.
+    pub fn warnings(&self) -> Vec<ParseError> {
+        let mut warnings = self.header_warnings();
+        warnings.extend(self.io_warnings());
+        warnings
+    }
+
+    // What is wrong with the timestamp and the GPS element, the part in front of the IO
+    fn header_warnings(&self) -> Vec<ParseError> {
+        let codec = Some(self.layout.codec_id);
+        let gps = self.gps();
+        let mut warnings = Vec::new();
+
+        if self.timestamp() == 0 {
+            warnings.push(ParseError::InvalidTimestamp {
+                offset: self.offset,
+                codec,
+            });
+        }
+        if !(-GPSElement::MAX_LONGITUDE..=GPSElement::MAX_LONGITUDE).contains(&gps.longitude) {
+            warnings.push(ParseError::CoordinateOutOfRange {
+                coordinate: Coordinate::Longitude,
+                value: gps.longitude,
+                offset: self.offset + 9,
+                codec,
+            });
+        }
+        if !(-GPSElement::MAX_LATITUDE..=GPSElement::MAX_LATITUDE).contains(&gps.latitude) {
+            warnings.push(ParseError::CoordinateOutOfRange {
+                coordinate: Coordinate::Latitude,
+                value: gps.latitude,
+                offset: self.offset + 13,
+                codec,
+            });
+        }
+        warnings
+    }
+
+    // The IO total is written down next to the counts of every group, and the two
+    // should agree. Some firmware leaves the variable length group out of the total, but
+    // we read the groups by their own counts, so the record is still whole. We only write
+    // it down, so someone can have a word with the firmware, and never turn the record down.
+    fn io_warnings(&self) -> Option<ParseError> {
+        let io = self.io();
+        let counted = io.counted_io();
+        if io.n_total_io() as usize == counted {
+            return None;
+        }
+        Some(ParseError::IoCountMismatch {
+            declared: io.n_total_io(),
+            counted,
+            offset: self.offset + IO_START + self.layout.header_size - self.layout.count_size,
+            codec: Some(self.layout.codec_id),
+        })
+    }
+
+    // Copy the record out of the buffer
+    pub fn to_avl_data(self) -> AVLData {
+        AVLData {
+            timestamp: self.timestamp(),
+            priority: self.priority(),
+            gps: self.gps(),
+            io: self.io().to_io_element(),
+            warnings: self.warnings(),
+        }
+    }
+}
+
+//   The IO part of a record, still in the buffer. The ids are handed out as u16
+//   whatever the codec, since a Codec 8 id fits in one just as well.
+#[derive(Debug, Clone, Copy)]
+pub struct IOElementRef<'a> {
+    io: &'a [u8],
+    layout: IoLayout,
+}
+
+impl<'a> IOElementRef<'a> {
+    pub fn event_io_id(&self) -> u16 {
+        be(&self.io[..self.layout.id_size]) as u16
+    }
+
+    // Only Codec 16 tells us why the record was written
+    pub fn generation_type(&self) -> Option<u8> {
+        match self.layout.codec_id {
+            0x10 => Some(self.io[2]),
+            _ => None,
+        }
+    }
+
+    pub fn n_total_io(&self) -> u16 {
+        let size = self.layout.count_size;
+        let header = self.layout.header_size;
+        be(&self.io[header - size..header]) as u16
+    }
+
+    // What the counts of the groups add up to, what n_total_io should have said
+    pub fn counted_io(&self) -> usize {
+        let groups = IO_GROUPS.len() + self.layout.var_group as usize;
+        (0..groups).map(|index| self.group(index).0).sum()
+    }
+
+    // Finds one of the groups, by skipping over the ones in front of it.
+    // Returns: How many elements the group has, and the bytes of those elements
+    fn group(&self, index: usize) -> (usize, &'a [u8]) {
+        let count_size = self.layout.count_size;
+        let mut position = self.layout.header_size;
+        for (size, _) in IO_GROUPS.iter().take(index) {
+            let count = be(&self.io[position..position + count_size]) as usize;
+            position += count_size + count * (self.layout.id_size + size);
+        }
+
+        let count = be(&self.io[position..position + count_size]) as usize;
+        let start = position + count_size;
+        let length = match IO_GROUPS.get(index) {
+            Some((size, _)) => count * (self.layout.id_size + size),
+            // The variable length group runs to the end of the record
+            None => self.io.len() - start,
+        };
+        (count, &self.io[start..start + length])
+    }
+
+    // The (id, value) pairs of one of the fixed size groups
+    fn fixed(&self, index: usize) -> impl Iterator<Item = (u16, u64)> + 'a {
+        let id_size = self.layout.id_size;
+        let (_, elements) = self.group(index);
+        elements
+            .chunks_exact(id_size + IO_GROUPS[index].0)
+            .map(move |element| (be(&element[..id_size]) as u16, be(&element[id_size..])))
+    }
+
+    pub fn one_byte_ios(&self) -> impl Iterator<Item = (u16, u8)> + 'a {
+        self.fixed(0).map(|(id, value)| (id, value as u8))
+    }
+
+    pub fn two_byte_ios(&self) -> impl Iterator<Item = (u16, u16)> + 'a {
+        self.fixed(1).map(|(id, value)| (id, value as u16))
+    }
+
+    pub fn four_byte_ios(&self) -> impl Iterator<Item = (u16, u32)> + 'a {
+        self.fixed(2).map(|(id, value)| (id, value as u32))
+    }
+
+    pub fn eight_byte_ios(&self) -> impl Iterator<Item = (u16, u64)> + 'a {
+        self.fixed(3)
+    }
+
+    // The (id, value) pairs of the variable length group, the values straight from the buffer.
+    // Only Codec 8E has them, for the others there is nothing to walk through.
+    pub fn var_byte_ios(&self) -> VarIos<'a> {
+        let elements = match self.layout.var_group {
+            true => self.group(4).1,
+            false => &[],
+        };
+        VarIos { elements }
+    }
+
+    // Every (id, value) pair, in the same order IOElement::iter() hands them out,
+    // the variable length values straight from the buffer
+    pub fn iter(&self) -> impl Iterator<Item = (u16, IoValue<'a>)> + 'a {
+        self.one_byte_ios()
+            .map(|(id, value)| (id, IoValue::U8(value)))
+            .chain(
+                self.two_byte_ios()
+                    .map(|(id, value)| (id, IoValue::U16(value))),
+            )
+            .chain(
+                self.four_byte_ios()
+                    .map(|(id, value)| (id, IoValue::U32(value))),
+            )
+            .chain(
+                self.eight_byte_ios()
+                    .map(|(id, value)| (id, IoValue::U64(value))),
+            )
+            .chain(
+                self.var_byte_ios()
+                    .map(|(id, value)| (id, IoValue::Bytes(value))),
+            )
+    }
+
+    // The value of a single IO element, if the record has it
+    pub fn get(&self, id: u16) -> Option<IoValue<'a>> {
+        self.iter()
+            .find(|(element_id, _)| *element_id == id)
+            .map(|(_, value)| value)
+    }
+
+    // Copy the IO out of the buffer, in the shape of the codec it was written in
+    pub fn to_io_element(self) -> IOElement {
+        let count = |index| self.group(index).0;
+        match self.layout.codec_id {
+            0x08 => IOElement::Codec8(IOElement8 {
+                event_io_id: self.event_io_id() as u8,
+                n_total_io: self.n_total_io() as u8,
+                n1_of_one_byte: count(0) as u8,
+                one_byte_ios: self.one_byte_ios().map(|(i, v)| (i as u8, v)).collect(),
+                n2_of_two_bytes: count(1) as u8,
+                two_byte_ios: self.two_byte_ios().map(|(i, v)| (i as u8, v)).collect(),
+                n4_of_four_bytes: count(2) as u8,
+                four_byte_ios: self.four_byte_ios().map(|(i, v)| (i as u8, v)).collect(),
+                n8_of_eight_bytes: count(3) as u8,
+                eight_byte_ios: self.eight_byte_ios().map(|(i, v)| (i as u8, v)).collect(),
+            }),
+            0x8E => IOElement::Codec8Extended(IOElement8Extended {
+                event_io_id: self.event_io_id(),
+                n_total_io: self.n_total_io(),
+                n1_of_one_byte: count(0) as u16,
+                one_byte_ios: self.one_byte_ios().collect(),
+                n2_of_two_bytes: count(1) as u16,
+                two_byte_ios: self.two_byte_ios().collect(),
+                n4_of_four_bytes: count(2) as u16,
+                four_byte_ios: self.four_byte_ios().collect(),
+                n8_of_eight_bytes: count(3) as u16,
+                eight_byte_ios: self.eight_byte_ios().collect(),
+                nx_of_var_bytes: count(4) as u16,
+                var_byte_ios: self
+                    .var_byte_ios()
+                    .map(|(id, value)| (id, value.len() as u16, value.to_vec()))
+                    .collect(),
+            }),
+            _ => IOElement::Codec16(IOElement16 {
+                event_io_id: self.event_io_id(),
+                generation_type: self.io[2],
+                n_total_io: self.n_total_io() as u8,
+                n1_of_one_byte: count(0) as u8,
+                one_byte_ios: self.one_byte_ios().collect(),
+                n2_of_two_bytes: count(1) as u8,
+                two_byte_ios: self.two_byte_ios().collect(),
+                n4_of_four_bytes: count(2) as u8,
+                four_byte_ios: self.four_byte_ios().collect(),
+                n8_of_eight_bytes: count(3) as u8,
+                eight_byte_ios: self.eight_byte_ios().collect(),
+            }),
+        }
+    }
+}
+
+//   Walks through the variable length IO elements of a Codec 8E record,
+//   handing out each value as a slice of the buffer
+pub struct VarIos<'a> {
+    elements: &'a [u8],
+}
+
+impl<'a> Iterator for VarIos<'a> {
+    type Item = (u16, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.elements.len() < 4 {
+            return None;
+        }
+        let id = be(&self.elements[0..2]) as u16;
+        let length = be(&self.elements[2..4]) as usize;
+        let value = self.elements.get(4..4 + length)?;
+        self.elements = &self.elements[4 + length..];
+        Some((id, value))
+    }
+}
@@ -0,0 +1,263 @@
+use super::*;
+use std::fmt;
+
+// Which of the two coordinates was off the map
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Coordinate {
+    Longitude,
+    Latitude,
+}
+
+//   Everything that can go wrong when the librarian reads a book, written down so that
+//   whoever is listening can tell a torn page from a forged one without reading the note.
+//   Every one of them tells us where in the frame it went wrong (offset, counted from the
+//   first byte of the frame), and which codec the frame said it was written in,
+//   if we got far enough to know.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    // The frame ended before we found what we were looking for
+    Incomplete {
+        needed: &'static str,
+        offset: usize,
+        codec: Option<u8>,
+    },
+    // The frame doesn't start with four zero bytes
+    BadPreamble {
+        found: u32,
+        offset: usize,
+        codec: Option<u8>,
+    },
+    // expected: What we calculated over the frame, actual: What the frame says it should be
+    CrcMismatch {
+        expected: u32,
+        actual: u32,
+        offset: usize,
+        codec: Option<u8>,
+    },
+    // The count in front of the records doesn't match the one behind them
+    RecordCountMismatch {
+        first: u8,
+        second: u8,
+        offset: usize,
+        codec: Option<u8>,
+    },
+    // A codec we don't know how to read, the codec is the one we don't know
+    UnsupportedCodec {
+        offset: usize,
+        codec: Option<u8>,
+    },
+    // A position that isn't on this planet
+    CoordinateOutOfRange {
+        coordinate: Coordinate,
+        value: i32,
+        offset: usize,
+        codec: Option<u8>,
+    },
+    // The device introduced itself with something that isn't an IMEI
+    InvalidImei {
+        reason: &'static str,
+        offset: usize,
+        codec: Option<u8>,
+    },
+    // The frame is smaller than any frame of its kind can be
+    FrameTooSmall {
+        length: usize,
+        minimum: usize,
+        offset: usize,
+        codec: Option<u8>,
+    },
+    // The frame is larger than we are willing to hold on to
+    FrameTooLarge {
+        length: usize,
+        maximum: usize,
+        offset: usize,
+        codec: Option<u8>,
+    },
+    // A single record that is larger than the device could have written
+    RecordTooLarge {
+        length: usize,
+        maximum: usize,
+        offset: usize,
+        codec: Option<u8>,
+    },
+    // The IO total of a record doesn't match what the counts of its groups add up to.
+    // Only ever a warning on the record, never the reason a packet is turned down.
+    IoCountMismatch {
+        declared: u16,
+        counted: usize,
+        offset: usize,
+        codec: Option<u8>,
+    },
+    // The frame says it is longer than what we found in it
+    LengthMismatch {
+        declared: usize,
+        consumed: usize,
+        offset: usize,
+        codec: Option<u8>,
+    },
+    // A record that claims to have happened at the very start of time
+    InvalidTimestamp {
+        offset: usize,
+        codec: Option<u8>,
+    },
+    // A Codec 12 frame that isn't the kind of message we expected
+    UnexpectedType {
+        expected: u8,
+        found: u8,
+        offset: usize,
+        codec: Option<u8>,
+    },
+    // A perfectly good frame, just not the kind the caller asked for
+    UnexpectedFrame {
+        found: String,
+        offset: usize,
+        codec: Option<u8>,
+    },
+}
+
+impl ParseError {
+    // Where in the frame it went wrong, counted from the first byte of the frame
+    pub fn offset(&self) -> usize {
+        match self {
+            ParseError::Incomplete { offset, .. }
+            | ParseError::BadPreamble { offset, .. }
+            | ParseError::CrcMismatch { offset, .. }
+            | ParseError::RecordCountMismatch { offset, .. }
+            | ParseError::UnsupportedCodec { offset, .. }
+            | ParseError::CoordinateOutOfRange { offset, .. }
+            | ParseError::InvalidImei { offset, .. }
+            | ParseError::FrameTooSmall { offset, .. }
+            | ParseError::FrameTooLarge { offset, .. }
+            | ParseError::RecordTooLarge { offset, .. }
+            | ParseError::IoCountMismatch { offset, .. }
+            | ParseError::LengthMismatch { offset, .. }
+            | ParseError::InvalidTimestamp { offset, .. }
+            | ParseError::UnexpectedType { offset, .. }
+            | ParseError::UnexpectedFrame { offset, .. } => *offset,
+        }
+    }
+
+    // The codec the frame said it was written in, None if we never got that far
+    pub fn codec(&self) -> Option<u8> {
+        match self {
+            ParseError::Incomplete { codec, .. }
+            | ParseError::BadPreamble { codec, .. }
+            | ParseError::CrcMismatch { codec, .. }
+            | ParseError::RecordCountMismatch { codec, .. }
+            | ParseError::UnsupportedCodec { codec, .. }
+            | ParseError::CoordinateOutOfRange { codec, .. }
+            | ParseError::InvalidImei { codec, .. }
+            | ParseError::FrameTooSmall { codec, .. }
+            | ParseError::FrameTooLarge { codec, .. }
+            | ParseError::RecordTooLarge { codec, .. }
+            | ParseError::IoCountMismatch { codec, .. }
+            | ParseError::LengthMismatch { codec, .. }
+            | ParseError::InvalidTimestamp { codec, .. }
+            | ParseError::UnexpectedType { codec, .. }
+            | ParseError::UnexpectedFrame { codec, .. } => *codec,
+        }
+    }
+
+    // Can the conversation go on after this? The Parser skips past a bad frame by itself,
+    // only a device that can't even tell us who it is isn't worth listening to any longer.
+    pub fn is_recoverable(&self) -> bool {
+        !matches!(self, ParseError::InvalidImei { .. })
+    }
+
+    // The closest io::ErrorKind, for those who only speak io::Error
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            ParseError::Incomplete { .. } => io::ErrorKind::UnexpectedEof,
+            _ => io::ErrorKind::InvalidData,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Incomplete { needed, .. } => write!(f, "Buffer too short for {}", needed),
+            ParseError::BadPreamble { found, .. } => write!(
+                f,
+                "Invalid preamble: expected 0x00000000, got {:#010x}",
+                found
+            ),
+            ParseError::CrcMismatch {
+                expected, actual, ..
+            } => write!(
+                f,
+                "CRC validation failed: expected {:#06x}, got {:#06x}",
+                expected, actual
+            ),
+            ParseError::RecordCountMismatch { first, second, .. } => write!(
+                f,
+                "Data record count mismatch: {} in front, {} behind",
+                first, second
+            ),
+            ParseError::UnsupportedCodec { codec, .. } => match codec {
+                Some(codec) => write!(f, "Unsupported codec: {:#04x}", codec),
+                None => write!(f, "Unsupported codec"),
+            },
+            ParseError::CoordinateOutOfRange {
+                coordinate, value, ..
+            } => write!(f, "{:?} out of range: {}", coordinate, value),
+            ParseError::InvalidImei { reason, .. } => write!(f, "Invalid IMEI: {}", reason),
+            ParseError::FrameTooSmall {
+                length, minimum, ..
+            } => write!(
+                f,
+                "Frame of {} bytes is below the minimum of {} bytes",
+                length, minimum
+            ),
+            ParseError::FrameTooLarge {
+                length, maximum, ..
+            } => write!(
+                f,
+                "Frame of {} bytes is above the maximum of {} bytes",
+                length, maximum
+            ),
+            ParseError::RecordTooLarge {
+                length, maximum, ..
+            } => write!(
+                f,
+                "Record of {} bytes is above the maximum of {} bytes",
+                length, maximum
+            ),
+            ParseError::IoCountMismatch {
+                declared, counted, ..
+            } => write!(
+                f,
+                "IO total of {} doesn't match the {} elements counted",
+                declared, counted
+            ),
+            ParseError::LengthMismatch {
+                declared, consumed, ..
+            } => write!(
+                f,
+                "Data length of {} bytes, but only {} bytes were accounted for",
+                declared, consumed
+            ),
+            ParseError::InvalidTimestamp { .. } => write!(f, "Invalid timestamp"),
+            ParseError::UnexpectedType {
+                expected, found, ..
+            } => write!(
+                f,
+                "Unexpected Codec 12 type: expected {:#04x}, got {:#04x}",
+                expected, found
+            ),
+            ParseError::UnexpectedFrame { found, .. } => {
+                write!(f, "Expected an AVL packet, got {}", found)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// The socket side of things still talks io::Error. The ParseError rides along inside,
+// so anyone who cares can get it back out with get_ref() and downcast_ref().
+impl From<ParseError> for io::Error {
+    fn from(error: ParseError) -> Self {
+        io::Error::new(error.kind(), error)
+    }
+}
@@ -8,13 +8,24 @@
 //#############################################################################################
 
 use super::*;
+
+// Anything but AVL data a device sent us, like the answer to a command,
+// along with the IMEI of the device that sent it
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceMessage {
+    pub imei: String,
+    pub frame: TeltonikaFrame,
+}
+
 //   You can think of the ProcessingPipeline like a smart post office sorting system
 // - incoming_queue: Letters that just arrived and need to be sorted
 // - outgoing_queue: Letters that have been sorted and are ready to be delivered
+// - messages: Notes from the devices that aren't letters, they aren't sorted, just kept in order
 // - batch_size: How many letters we process at once (for efficiency)
 pub struct ProcessingPipeline {
     incoming_queue: VecDeque<AVLPacket>,
     outgoing_queue: VecDeque<AVLPacket>,
+    messages: VecDeque<DeviceMessage>,
     batch_size: usize,
 }
 
@@ -24,6 +35,7 @@ impl ProcessingPipeline {
         Self {
             incoming_queue: VecDeque::new(),
             outgoing_queue: VecDeque::new(),
+            messages: VecDeque::new(),
             batch_size,
         }
     }
@@ -43,6 +55,16 @@ impl ProcessingPipeline {
         Ok(flushed)
     }
 
+    // A device told us something that isn't AVL data, keep it until someone asks for it
+    pub fn process_message(&mut self, message: DeviceMessage) {
+        self.messages.push_back(message);
+    }
+
+    // Everything the devices told us besides AVL data, in the order it came in
+    pub fn take_messages(&mut self) -> Vec<DeviceMessage> {
+        self.messages.drain(..).collect()
+    }
+
     //   Handle a new incoming packet (letter)
     //   The priority works similar to postal service priority levels:
     // - 0-3: Standard mail (goes to back of queue)
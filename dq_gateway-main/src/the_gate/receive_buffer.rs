@@ -0,0 +1,84 @@
+//   The desk the librarian reads on. It is a fixed size, and never grows, no matter
+//   how much a device throws at us.
+//
+//   Pages are laid down at the back and read from the front. Reading a frame only moves
+//   the front forward, nothing is copied. Only when there is no longer room at the back
+//   for a whole frame, do we slide whatever is left (at most one unfinished frame)
+//   back to the start of the desk. That way the bytes of a frame always lie side by side,
+//   so they can be read where they lie, see AVLPacketRef.
+//
+// bytes: The desk itself
+// start: Where the first byte we haven't handed out yet is
+// end: Where the last byte we have been given ends
+pub struct ReceiveBuffer {
+    bytes: Box<[u8]>,
+    start: usize,
+    end: usize,
+}
+
+impl ReceiveBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            bytes: vec![0u8; capacity].into_boxed_slice(),
+            start: 0,
+            end: 0,
+        }
+    }
+
+    // Everything we have been given, and haven't handed out yet
+    pub fn data(&self) -> &[u8] {
+        &self.bytes[self.start..self.end]
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    // We are done with the first count bytes
+    pub fn consume(&mut self, count: usize) {
+        self.start += count.min(self.len());
+
+        // An empty desk is a clean desk, the next frame starts at the beginning again
+        if self.start == self.end {
+            self.start = 0;
+            self.end = 0;
+        }
+    }
+
+    // Room at the back to write into, at least wanted bytes of it if the desk allows.
+    // Whatever is written there only counts once it is committed.
+    pub fn spare(&mut self, wanted: usize) -> &mut [u8] {
+        if self.bytes.len() - self.end < wanted {
+            self.compact();
+        }
+        &mut self.bytes[self.end..]
+    }
+
+    // The first count bytes of spare() now hold data
+    pub fn commit(&mut self, count: usize) {
+        self.end = (self.end + count).min(self.bytes.len());
+    }
+
+    // Copy in as many of the bytes as there is room for.
+    // Returns: How many of them we took
+    pub fn extend(&mut self, bytes: &[u8]) -> usize {
+        let spare = self.spare(bytes.len());
+        let count = bytes.len().min(spare.len());
+        spare[..count].copy_from_slice(&bytes[..count]);
+        self.commit(count);
+        count
+    }
+
+    // Slide what is left to the start of the desk
+    fn compact(&mut self) {
+        if self.start > 0 {
+            self.bytes.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+        }
+    }
+}
@@ -17,28 +17,69 @@
 pub mod integration_tests {
 
     // Import all our other modules
+    use crate::the_gate::calculate_crc16;
+    use crate::the_gate::pack_imei;
+    use crate::the_gate::unpack_imei;
     use crate::the_gate::AVLData;
     use crate::the_gate::AVLPacket;
+    use crate::the_gate::AVLPacketRef;
+    use crate::the_gate::Codec12CommandPacket;
+    use crate::the_gate::Codec13ResponsePacket;
+    use crate::the_gate::Codec14CommandPacket;
+    use crate::the_gate::Codec14ResponsePacket;
+    use crate::the_gate::Codec15Packet;
     use crate::the_gate::Connection;
+    use crate::the_gate::Coordinate;
+    use crate::the_gate::DeviceListener;
+    use crate::the_gate::DeviceProfile;
+    #[cfg(target_os = "linux")]
+    use crate::the_gate::EventLoop;
     use crate::the_gate::GPSElement;
+    use crate::the_gate::GateGuard;
     use crate::the_gate::IOElement;
     use crate::the_gate::IOElement16;
     use crate::the_gate::IOElement8;
     use crate::the_gate::IOElement8Extended;
+    use crate::the_gate::IoDictionary;
+    use crate::the_gate::IoType;
+    use crate::the_gate::IoValue;
+    use crate::the_gate::PacketEncoder;
+    use crate::the_gate::ParseError;
     use crate::the_gate::Parser;
     use crate::the_gate::ProcessingPipeline;
     use crate::the_gate::ProtocolAction;
     use crate::the_gate::ProtocolEvent;
     use crate::the_gate::ProtocolState;
+    use crate::the_gate::Session;
+    use crate::the_gate::SessionConfig;
     use crate::the_gate::StateMachine;
+    use crate::the_gate::TeltonikaFrame;
+    use crate::the_gate::UdpAck;
+    use crate::the_gate::UdpAvlPacket;
+    use crate::the_gate::UdpListener;
+    use crate::the_gate::ValidationPolicy;
+    use crate::the_gate::CODEC12_RESPONSE_TYPE;
+    use crate::the_gate::CODEC14_ACK_TYPE;
+    use crate::the_gate::CODEC14_NACK_TYPE;
+    use crate::the_gate::KEEPALIVE_PING;
     use crate::the_gate::LARGEST_AVL_SIZE;
     use crate::the_gate::MAX_AVL_PACKET_SIZE_FM6XXX;
+    use crate::the_gate::MAX_AVL_RECORD_SIZE_FM6XXX;
+    use crate::the_gate::RECEIVE_BUFFER_SIZE;
     use crate::the_gate::SMALLEST_AVL_SIZE;
     use std::io::{self, Cursor, Read, Write};
     use std::net::SocketAddr;
-    use std::net::{TcpListener, TcpStream};
+    use std::net::{TcpListener, TcpStream, UdpSocket};
+    use std::sync::{mpsc, Arc, Mutex};
     use std::thread;
-    use std::time::Duration;
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    // The hello every device opens with, the length of its IMEI and then the IMEI itself
+    fn mock_handshake(imei: &str) -> Vec<u8> {
+        let mut handshake = (imei.len() as u16).to_be_bytes().to_vec();
+        handshake.extend_from_slice(imei.as_bytes());
+        handshake
+    }
 
     // creates test packets
     // to test if we are actually able to parse and store the protocol
@@ -48,8 +89,8 @@ pub mod integration_tests {
         for i in 0..data_count {
             // Creates psudo GPS data, where our imaginary vehicle is located
             let gps = GPSElement {
-                longitude: 25_00000,
-                latitude: 54_00000,
+                longitude: 25_0000000,
+                latitude: 54_0000000,
                 altitude: 100,
                 angle: 90,
                 satellites: 8,
@@ -76,6 +117,7 @@ pub mod integration_tests {
                 priority: 1,
                 gps,
                 io,
+                warnings: vec![],
             });
         }
 
@@ -91,240 +133,6 @@ pub mod integration_tests {
         }
     }
 
-    // The PacketSerializer helps us convert our packets into raw bytes
-    // Translating our message into a uniform language devices understand
-    struct PacketSerializer {
-        buffer: Vec<u8>,
-    }
-
-    impl PacketSerializer {
-        fn new() -> Self {
-            Self {
-                buffer: Vec::with_capacity(LARGEST_AVL_SIZE),
-            }
-        }
-
-        fn serialize_packet(&mut self, packet: &AVLPacket) -> io::Result<Vec<u8>> {
-            self.buffer.clear();
-
-            // Write preamble
-            self.write_u32(packet.preamble)?;
-
-            // Calculate and write data length
-            let data_start_pos = self.buffer.len() + 4; // Position after data_length
-            self.write_u32(0)?; // Placeholder for data_length
-
-            // Write codec ID and number of data
-            self.write_u8(packet.codec_id)?;
-            self.write_u8(packet.number_of_data1)?;
-
-            // Write AVL data records
-            for data in &packet.avl_data {
-                self.serialize_avl_data(data)?;
-            }
-
-            self.write_u8(packet.number_of_data2)?;
-
-            // Calculate actual data length
-            let data_length = (self.buffer.len() - data_start_pos) as u32;
-
-            // Go back and write the actual data length
-            let data_length_bytes = data_length.to_be_bytes();
-            self.buffer[data_start_pos - 4..data_start_pos].copy_from_slice(&data_length_bytes);
-
-            // Calculate and write CRC16
-            let crc = self.calculate_crc(&self.buffer[4..]);
-            self.write_u32(crc)?;
-
-            Ok(self.buffer.clone())
-        }
-
-        fn serialize_avl_data(&mut self, data: &AVLData) -> io::Result<()> {
-            // Write timestamp (8 bytes)
-            self.write_u64(data.timestamp)?;
-
-            // Write priority
-            self.write_u8(data.priority)?;
-
-            // Write GPS data
-            self.write_i32(data.gps.longitude)?;
-            self.write_i32(data.gps.latitude)?;
-            self.write_i16(data.gps.altitude)?;
-            self.write_i16(data.gps.angle)?;
-            self.write_u8(data.gps.satellites)?;
-            self.write_i16(data.gps.speed)?;
-
-            // Write IO data based on type
-            match &data.io {
-                IOElement::Codec8(io) => self.serialize_codec8_io(io)?,
-                IOElement::Codec8Extended(io) => self.serialize_codec8_extended_io(io)?,
-                IOElement::Codec16(io) => self.serialize_codec16_io(io)?,
-            }
-
-            Ok(())
-        }
-
-        fn serialize_codec8_io(&mut self, io: &IOElement8) -> io::Result<()> {
-            self.write_u8(io.event_io_id)?;
-            self.write_u8(io.n_total_io)?;
-
-            // 1-byte elements
-            self.write_u8(io.n1_of_one_byte)?;
-            for (id, value) in &io.one_byte_ios {
-                self.write_u8(*id)?;
-                self.write_u8(*value)?;
-            }
-
-            // 2-byte elements
-            self.write_u8(io.n2_of_two_bytes)?;
-            for (id, value) in &io.two_byte_ios {
-                self.write_u8(*id)?;
-                self.write_u16(*value)?;
-            }
-
-            // 4-byte elements
-            self.write_u8(io.n4_of_four_bytes)?;
-            for (id, value) in &io.four_byte_ios {
-                self.write_u8(*id)?;
-                self.write_u32(*value)?;
-            }
-
-            // 8-byte elements
-            self.write_u8(io.n8_of_eight_bytes)?;
-            for (id, value) in &io.eight_byte_ios {
-                self.write_u8(*id)?;
-                self.write_u64(*value)?;
-            }
-
-            Ok(())
-        }
-
-        fn serialize_codec8_extended_io(&mut self, io: &IOElement8Extended) -> io::Result<()> {
-            self.write_u16(io.event_io_id)?;
-            self.write_u16(io.n_total_io)?;
-
-            // 1-byte elements
-            self.write_u16(io.n1_of_one_byte)?;
-            for (id, value) in &io.one_byte_ios {
-                self.write_u16(*id)?;
-                self.write_u8(*value)?;
-            }
-
-            // 2-byte elements
-            self.write_u16(io.n2_of_two_bytes)?;
-            for (id, value) in &io.two_byte_ios {
-                self.write_u16(*id)?;
-                self.write_u16(*value)?;
-            }
-
-            // 4-byte elements
-            self.write_u16(io.n4_of_four_bytes)?;
-            for (id, value) in &io.four_byte_ios {
-                self.write_u16(*id)?;
-                self.write_u32(*value)?;
-            }
-
-            // 8-byte elements
-            self.write_u16(io.n8_of_eight_bytes)?;
-            for (id, value) in &io.eight_byte_ios {
-                self.write_u16(*id)?;
-                self.write_u64(*value)?;
-            }
-
-            // Variable length elements
-            self.write_u16(io.nx_of_var_bytes)?;
-            for (id, length, value) in &io.var_byte_ios {
-                self.write_u16(*id)?;
-                self.write_u16(*length)?;
-                self.buffer.extend_from_slice(value);
-            }
-
-            Ok(())
-        }
-
-        fn serialize_codec16_io(&mut self, io: &IOElement16) -> io::Result<()> {
-            self.write_u16(io.event_io_id)?;
-            self.write_u8(io.generation_type)?;
-            self.write_u8(io.n_total_io)?;
-
-            // 1-byte elements
-            self.write_u8(io.n1_of_one_byte)?;
-            for (id, value) in &io.one_byte_ios {
-                self.write_u16(*id)?;
-                self.write_u8(*value)?;
-            }
-
-            // 2-byte elements
-            self.write_u8(io.n2_of_two_bytes)?;
-            for (id, value) in &io.two_byte_ios {
-                self.write_u16(*id)?;
-                self.write_u16(*value)?;
-            }
-
-            // 4-byte elements
-            self.write_u8(io.n4_of_four_bytes)?;
-            for (id, value) in &io.four_byte_ios {
-                self.write_u16(*id)?;
-                self.write_u32(*value)?;
-            }
-
-            // 8-byte elements
-            self.write_u8(io.n8_of_eight_bytes)?;
-            for (id, value) in &io.eight_byte_ios {
-                self.write_u16(*id)?;
-                self.write_u64(*value)?;
-            }
-
-            Ok(())
-        }
-
-        // Helper methods for writing different integer types
-        fn write_u8(&mut self, value: u8) -> io::Result<()> {
-            self.buffer.push(value);
-            Ok(())
-        }
-
-        fn write_u16(&mut self, value: u16) -> io::Result<()> {
-            self.buffer.extend_from_slice(&value.to_be_bytes());
-            Ok(())
-        }
-
-        fn write_u32(&mut self, value: u32) -> io::Result<()> {
-            self.buffer.extend_from_slice(&value.to_be_bytes());
-            Ok(())
-        }
-
-        fn write_u64(&mut self, value: u64) -> io::Result<()> {
-            self.buffer.extend_from_slice(&value.to_be_bytes());
-            Ok(())
-        }
-
-        fn write_i16(&mut self, value: i16) -> io::Result<()> {
-            self.buffer.extend_from_slice(&value.to_be_bytes());
-            Ok(())
-        }
-
-        fn write_i32(&mut self, value: i32) -> io::Result<()> {
-            self.buffer.extend_from_slice(&value.to_be_bytes());
-            Ok(())
-        }
-
-        fn calculate_crc(&self, data: &[u8]) -> u32 {
-            let mut crc: u16 = 0xFFFF;
-            for &byte in data {
-                crc ^= (byte as u16) & 0xFF;
-                for _ in 0..8 {
-                    if (crc & 0x0001) != 0 {
-                        crc = (crc >> 1) ^ 0xA001;
-                    } else {
-                        crc >>= 1;
-                    }
-                }
-            }
-            crc as u32
-        }
-    }
-
     // MockDevice pretends to be a real tracking device
     // it should help us test our system without needing actual hardware
     // by lying to us, like telling us we're pretty even though we're clearly a wreck.
@@ -343,6 +151,23 @@ pub mod integration_tests {
             self.listener.local_addr().unwrap()
         }
 
+        // Have the fake device wait for us to call, say hello and wait for our answer.
+        // What it does after that is up to the test, it is handed the line and our answer.
+        fn after_handshake<T, F>(self, imei: &'static str, device: F) -> thread::JoinHandle<T>
+        where
+            F: FnOnce(TcpStream, u8) -> T + Send + 'static,
+            T: Send + 'static,
+        {
+            thread::spawn(move || {
+                let (mut socket, _) = self.listener.accept().unwrap();
+                socket.write_all(&mock_handshake(imei)).unwrap();
+
+                let mut reply = [0u8; 1];
+                socket.read_exact(&mut reply).unwrap();
+                device(socket, reply[0])
+            })
+        }
+
         // Start listening for connections
         // having the fake device wait for us to call
         fn accept_connection(self) -> thread::JoinHandle<()> {
@@ -432,11 +257,11 @@ pub mod integration_tests {
         assert!(connection.connect().is_ok());
 
         let mut parser = Parser::new();
-        let mut serializer = PacketSerializer::new();
+        let mut encoder = PacketEncoder::new();
 
         // Create and serialize test packet
         let original_packet = create_mock_avl_packet(2);
-        let serialized_data = serializer.serialize_packet(&original_packet).unwrap();
+        let serialized_data = encoder.encode(&original_packet);
 
         // Get the TcpStream from connection
         if let Some(stream) =
@@ -533,42 +358,1867 @@ pub mod integration_tests {
     fn test_pipeline_processing() {
         let mut pipeline = ProcessingPipeline::new(2);
 
-        // Create test packets with different priorities
-        let mut packet1 = create_mock_avl_packet(1);
-        packet1.avl_data[0].priority = 1;
+        // Create test packets with different priorities
+        let mut packet1 = create_mock_avl_packet(1);
+        packet1.avl_data[0].priority = 1;
+
+        let mut packet2 = create_mock_avl_packet(1);
+        packet2.avl_data[0].priority = 8; // High priority
+
+        // Test priority handling
+        pipeline.process_incoming(packet1.clone(), None).unwrap();
+        pipeline.process_incoming(packet2.clone(), None).unwrap();
+
+        let (incoming, outgoing) = pipeline.queue_stats();
+        assert_eq!(incoming + outgoing, 2, "Both packets should be queued");
+
+        // Test batch processing
+        let processed = pipeline.flush().unwrap();
+        assert_eq!(processed.len(), 2, "All packets should be processed");
+
+        // Verify high priority packet is processed first
+        assert_eq!(processed[0].avl_data[0].priority, 8);
+        assert_eq!(processed[1].avl_data[0].priority, 1);
+    }
+
+    #[test]
+    fn test_extended_codec_handling() {
+        let mut parser = Parser::new();
+        let mut encoder = PacketEncoder::new();
+
+        // Create a packet with extended codec (8E)
+        let mut packet = create_mock_avl_packet(1);
+        packet.codec_id = 0x8E;
+        packet.avl_data[0].io = IOElement::Codec8Extended(IOElement8Extended {
+            event_io_id: 0x1234,
+            n_total_io: 1,
+            n1_of_one_byte: 1,
+            one_byte_ios: vec![(0x5678, 42)],
+            n2_of_two_bytes: 0,
+            two_byte_ios: vec![],
+            n4_of_four_bytes: 0,
+            four_byte_ios: vec![],
+            n8_of_eight_bytes: 0,
+            eight_byte_ios: vec![],
+            nx_of_var_bytes: 1,
+            var_byte_ios: vec![(0x9ABC, 3, vec![1, 2, 3])],
+        });
+
+        // Set up mock device
+        let mock_device = MockDevice::new();
+        let device_addr = mock_device.addr();
+        let serialized_data = encoder.encode(&packet);
+
+        let device_thread = thread::spawn(move || {
+            let (mut socket, _) = mock_device.listener.accept().unwrap();
+            socket.write_all(&serialized_data).unwrap();
+        });
+
+        let mut connection = Connection::new(device_addr);
+        assert!(connection.connect().is_ok());
+
+        if let Some(stream) =
+            unsafe { &mut *(&mut connection as *mut Connection).cast::<Connection>() }
+                .stream
+                .as_mut()
+        {
+            let parsed_result = parser.parse_stream(stream);
+
+            assert!(parsed_result.is_ok());
+            if let Ok(Some(parsed_packet)) = parsed_result {
+                assert_eq!(parsed_packet.codec_id, 0x8E);
+
+                // Verify extended codec data
+                if let IOElement::Codec8Extended(io) = &parsed_packet.avl_data[0].io {
+                    assert_eq!(io.event_io_id, 0x1234);
+                    assert_eq!(io.n_total_io, 1);
+                    assert_eq!(io.one_byte_ios, vec![(0x5678, 42)]);
+                    assert_eq!(io.var_byte_ios, vec![(0x9ABC, 3, vec![1, 2, 3])]);
+                } else {
+                    panic!("Expected Codec8Extended IO element");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_extended_codec_io_total() {
+        let mut encoder = PacketEncoder::new();
+
+        // The same record as in test_extended_codec_handling, its total once as the device
+        // in that test wrote it, leaving the variable length element out, and once counted in full
+        let mut packet = create_mock_avl_packet(1);
+        packet.codec_id = 0x8E;
+        let io = IOElement8Extended {
+            event_io_id: 0x1234,
+            n_total_io: 1,
+            n1_of_one_byte: 1,
+            one_byte_ios: vec![(0x5678, 42)],
+            n2_of_two_bytes: 0,
+            two_byte_ios: vec![],
+            n4_of_four_bytes: 0,
+            four_byte_ios: vec![],
+            n8_of_eight_bytes: 0,
+            eight_byte_ios: vec![],
+            nx_of_var_bytes: 1,
+            var_byte_ios: vec![(0x9ABC, 3, vec![1, 2, 3])],
+        };
+
+        for (n_total_io, warned) in [(1, true), (2, false)] {
+            packet.avl_data[0].io = IOElement::Codec8Extended(IOElement8Extended {
+                n_total_io,
+                ..io.clone()
+            });
+
+            // Short or not, the groups say what is there, so the record is read all the same
+            let mut parser = Parser::new();
+            parser.feed(&encoder.encode(&packet));
+            let parsed = parser.next_packet().unwrap().unwrap();
+            assert_eq!(parsed.avl_data[0].io, packet.avl_data[0].io);
+            assert_eq!(
+                parsed.avl_data[0].warnings.first(),
+                warned.then_some(&ParseError::IoCountMismatch {
+                    declared: 1,
+                    counted: 2,
+                    offset: 10 + 24 + 2,
+                    codec: Some(0x8E),
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn test_codec16_handling() {
+        let mut parser = Parser::new();
+        let mut encoder = PacketEncoder::new();
+
+        // Create a packet with Codec 16, using IO IDs that don't fit in a single byte
+        let mut packet = create_mock_avl_packet(2);
+        packet.codec_id = 0x10;
+        for data in packet.avl_data.iter_mut() {
+            data.io = IOElement::Codec16(IOElement16 {
+                event_io_id: 0x0101,
+                generation_type: 5,
+                n_total_io: 4,
+                n1_of_one_byte: 1,
+                one_byte_ios: vec![(0x01EF, 1)],
+                n2_of_two_bytes: 1,
+                two_byte_ios: vec![(0x0042, 12_345)],
+                n4_of_four_bytes: 1,
+                four_byte_ios: vec![(0x0100, 0xDEADBEEF)],
+                n8_of_eight_bytes: 1,
+                eight_byte_ios: vec![(0x0210, 0x0102030405060708)],
+            });
+        }
+
+        // Set up mock device
+        let mock_device = MockDevice::new();
+        let device_addr = mock_device.addr();
+        let serialized_data = encoder.encode(&packet);
+
+        let device_thread = thread::spawn(move || {
+            let (mut socket, _) = mock_device.listener.accept().unwrap();
+            socket.write_all(&serialized_data).unwrap();
+        });
+
+        let mut connection = Connection::new(device_addr);
+        assert!(connection.connect().is_ok());
+
+        if let Some(stream) = connection.get_stream_mut() {
+            let parsed_packet = parser
+                .parse_stream(stream)
+                .unwrap()
+                .expect("Expected a complete Codec 16 packet");
+
+            assert_eq!(parsed_packet.codec_id, 0x10);
+            assert_eq!(parsed_packet.avl_data.len(), packet.avl_data.len());
+
+            // The whole record, generation type included, should survive the round trip
+            for (original_data, parsed_data) in
+                packet.avl_data.iter().zip(parsed_packet.avl_data.iter())
+            {
+                assert_eq!(parsed_data.timestamp, original_data.timestamp);
+                assert_eq!(parsed_data.gps, original_data.gps);
+                assert_eq!(parsed_data.io, original_data.io);
+            }
+        }
+        device_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_codec12_command_and_response() {
+        // The "getinfo" example straight out of the Teltonika Codec 12 documentation
+        let expected_getinfo = [
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0F, 0x0C, 0x01, 0x05, 0x00, 0x00, 0x00,
+            0x07, 0x67, 0x65, 0x74, 0x69, 0x6E, 0x66, 0x6F, 0x01, 0x00, 0x00, 0x43, 0x12,
+        ];
+        let getinfo = Codec12CommandPacket::new("getinfo");
+        assert_eq!(getinfo.data_length, 0x0F);
+        assert_eq!(getinfo.crc16, 0x4312);
+        assert_eq!(getinfo.to_bytes(), expected_getinfo);
+
+        // The device reads our command and answers it, like a well behaved tracker
+        let mock_device = MockDevice::new();
+        let device_addr = mock_device.addr();
+
+        let device_thread = thread::spawn(move || {
+            let (mut socket, _) = mock_device.listener.accept().unwrap();
+            let mut buf = [0u8; 64];
+            let n = socket.read(&mut buf).unwrap();
+            assert_eq!(
+                &buf[..n],
+                Codec12CommandPacket::new("setdigout 1").to_bytes()
+            );
+
+            let text = b"DOUT1:1 Timeout:INFINITY";
+            let mut response = vec![0x0C, 0x01, 0x06];
+            response.extend_from_slice(&(text.len() as u32).to_be_bytes());
+            response.extend_from_slice(text);
+            response.push(0x01);
+
+            let mut frame = vec![0x00, 0x00, 0x00, 0x00];
+            frame.extend_from_slice(&(response.len() as u32).to_be_bytes());
+            frame.extend_from_slice(&response);
+            frame.extend_from_slice(&calculate_crc16(&response).to_be_bytes());
+            socket.write_all(&frame).unwrap();
+        });
+
+        let mut connection = Connection::new(device_addr);
+        assert!(connection.connect().is_ok());
+        connection.send_command("setdigout 1").unwrap();
+
+        let mut parser = Parser::new();
+        let stream = connection.get_stream_mut().unwrap();
+        match parser.parse_frame(stream).unwrap() {
+            Some(TeltonikaFrame::CommandResponse(response)) => {
+                assert_eq!(response.response_type, 0x06);
+                assert_eq!(response.response_qty1, response.response_qty2);
+                assert_eq!(response.response_text(), "DOUT1:1 Timeout:INFINITY");
+            }
+            other => panic!("Expected a Codec 12 response, got {:?}", other),
+        }
+        device_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_session_command_response() {
+        const IMEI: &str = "356307042441013";
+        let text = "DOUT1:1 Timeout:INFINITY";
+
+        // The device answers both commands it was sent, the second one twice over, with
+        // and without the time it wrote the answer, and then hangs up
+        let mock_device = MockDevice::new();
+        let device_addr = mock_device.addr();
+        let device_thread = mock_device.after_handshake(IMEI, move |mut socket, reply| {
+            for command in [
+                Codec12CommandPacket::new("setdigout 1").to_bytes(),
+                Codec14CommandPacket::new(IMEI, "getver")
+                    .unwrap()
+                    .to_bytes(),
+            ] {
+                let mut buf = vec![0u8; command.len()];
+                socket.read_exact(&mut buf).unwrap();
+                assert_eq!(buf, command);
+            }
+
+            let mut response = vec![0x0C, 0x01, CODEC12_RESPONSE_TYPE];
+            response.extend_from_slice(&(text.len() as u32).to_be_bytes());
+            response.extend_from_slice(text.as_bytes());
+            response.push(0x01);
+            let mut frame = vec![0x00, 0x00, 0x00, 0x00];
+            frame.extend_from_slice(&(response.len() as u32).to_be_bytes());
+            frame.extend_from_slice(&response);
+            frame.extend_from_slice(&calculate_crc16(&response).to_be_bytes());
+            socket.write_all(&frame).unwrap();
+
+            let timestamped = Codec13ResponsePacket::new(1_700_000_000, "Ver:03.27.07");
+            socket.write_all(&timestamped.to_bytes()).unwrap();
+            let answered = Codec14ResponsePacket::new(IMEI, true, "Ver:03.27.07").unwrap();
+            socket.write_all(&answered.to_bytes()).unwrap();
+            reply
+        });
+
+        let mut connection = Connection::new(device_addr);
+        assert!(connection.connect().is_ok());
+        let pipeline = Arc::new(Mutex::new(ProcessingPipeline::new(10)));
+        let mut session = Session::new(
+            connection,
+            Arc::new(GateGuard::open()),
+            Arc::clone(&pipeline),
+            Duration::from_secs(5),
+        );
+        assert!(session.step().unwrap());
+        assert_eq!(session.state(), ProtocolState::Ready);
+
+        session.send_command("setdigout 1").unwrap();
+        session.send_imei_command(IMEI, "getver").unwrap();
+        assert!(session.run().is_ok());
+        assert_eq!(device_thread.join().unwrap(), 0x01);
+
+        // Whoever sent the commands finds the answers at the post office, in the order they came
+        let messages = pipeline.lock().unwrap().take_messages();
+        assert_eq!(messages.len(), 3);
+        assert!(messages.iter().all(|message| message.imei == IMEI));
+        match &messages[0].frame {
+            TeltonikaFrame::CommandResponse(response) => {
+                assert_eq!(response.response_text(), text);
+            }
+            other => panic!("Expected a Codec 12 response, got {:?}", other),
+        }
+        match &messages[1].frame {
+            TeltonikaFrame::TimestampedResponse(response) => {
+                assert_eq!(response.timestamp, 1_700_000_000);
+                assert_eq!(response.response_text(), "Ver:03.27.07");
+            }
+            other => panic!("Expected a Codec 13 response, got {:?}", other),
+        }
+        match &messages[2].frame {
+            TeltonikaFrame::ImeiCommandResponse(response) => {
+                assert!(response.imei_matched());
+                assert_eq!(response.response_text(), "Ver:03.27.07");
+            }
+            other => panic!("Expected a Codec 14 response, got {:?}", other),
+        }
+        assert!(pipeline.lock().unwrap().take_messages().is_empty());
+    }
+
+    #[test]
+    fn test_codec13_and_codec14() {
+        const IMEI: &str = "352093081452251";
+
+        // The IMEI goes out as 8 bytes, two digits to a byte
+        let getver = Codec14CommandPacket::new(IMEI, "getver").unwrap();
+        let bytes = getver.to_bytes();
+        assert_eq!(
+            &bytes[..15],
+            &[0, 0, 0, 0, 0, 0, 0, 0x16, 0x0E, 0x01, 0x05, 0, 0, 0, 0x0E]
+        );
+        assert_eq!(
+            &bytes[15..23],
+            &[0x03, 0x52, 0x09, 0x30, 0x81, 0x45, 0x22, 0x51]
+        );
+        assert_eq!(&bytes[23..29], b"getver");
+        assert_eq!(getver.crc16, calculate_crc16(&bytes[8..30]));
+        assert_eq!(pack_imei(IMEI).and_then(unpack_imei).as_deref(), Some(IMEI));
+        assert!(Codec14CommandPacket::new("35209308145225X", "getver").is_none());
+        assert!(Codec14CommandPacket::new("3520930814522510", "getver").is_none());
+
+        // Codec 13 tells us when the device wrote its answer, not when it reached us
+        let timestamped = Codec13ResponsePacket::new(1_644_238_347, "Hello from the field");
+        let answered = Codec14ResponsePacket::new(IMEI, true, "Ver:03.27.07").unwrap();
+        let not_me = Codec14ResponsePacket::new("490154203237518", false, "").unwrap();
+        let mut parser = Parser::new();
+        for packet in [
+            timestamped.to_bytes(),
+            answered.to_bytes(),
+            not_me.to_bytes(),
+        ] {
+            parser.feed(&packet);
+        }
+
+        match parser.next_frame().unwrap() {
+            Some(TeltonikaFrame::TimestampedResponse(response)) => {
+                assert_eq!(response, timestamped);
+                assert_eq!(response.response_text(), "Hello from the field");
+                assert_eq!(
+                    response.time(),
+                    UNIX_EPOCH + Duration::from_secs(1_644_238_347)
+                );
+            }
+            other => panic!("Expected a Codec 13 response, got {:?}", other),
+        }
+        match parser.next_frame().unwrap() {
+            Some(TeltonikaFrame::ImeiCommandResponse(response)) => {
+                assert_eq!(response, answered);
+                assert!(response.imei_matched());
+                assert_eq!(response.response_text(), "Ver:03.27.07");
+            }
+            other => panic!("Expected a Codec 14 response, got {:?}", other),
+        }
+        match parser.next_frame().unwrap() {
+            Some(TeltonikaFrame::ImeiCommandResponse(response)) => {
+                assert_eq!(response.response_type, CODEC14_NACK_TYPE);
+                assert!(!response.imei_matched());
+                assert_eq!(response.imei, "490154203237518");
+                assert!(response.response.is_empty());
+            }
+            other => panic!("Expected a Codec 14 response, got {:?}", other),
+        }
+
+        // Anything but an answer or a refusal isn't a Codec 14 response
+        let mut odd = answered.to_bytes();
+        odd[10] = 0x05;
+        let crc_start = odd.len() - 4;
+        let crc = calculate_crc16(&odd[8..crc_start]);
+        odd[crc_start..].copy_from_slice(&crc.to_be_bytes());
+        let mut parser = Parser::new();
+        parser.feed(&odd);
+        assert_eq!(
+            parser.next_frame().unwrap_err(),
+            ParseError::UnexpectedType {
+                expected: CODEC14_ACK_TYPE,
+                found: 0x05,
+                offset: 10,
+                codec: Some(0x0E),
+            }
+        );
+
+        // Over the wire, the device that isn't the one says so instead of doing it
+        let mock_device = MockDevice::new();
+        let device_addr = mock_device.addr();
+        let device_thread = thread::spawn(move || {
+            let (mut socket, _) = mock_device.listener.accept().unwrap();
+            let mut buf = [0u8; 64];
+            let n = socket.read(&mut buf).unwrap();
+            assert_eq!(
+                &buf[..n],
+                Codec14CommandPacket::new(IMEI, "setdigout 1")
+                    .unwrap()
+                    .to_bytes()
+            );
+
+            let refusal = Codec14ResponsePacket::new("490154203237518", false, "").unwrap();
+            socket.write_all(&refusal.to_bytes()).unwrap();
+        });
+
+        let mut connection = Connection::new(device_addr);
+        assert!(connection.connect().is_ok());
+        let error = connection
+            .send_imei_command("not an imei", "setdigout 1")
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        connection.send_imei_command(IMEI, "setdigout 1").unwrap();
+
+        let stream = connection.get_stream_mut().unwrap();
+        match Parser::new().parse_frame(stream).unwrap() {
+            Some(TeltonikaFrame::ImeiCommandResponse(response)) => {
+                assert!(!response.imei_matched())
+            }
+            other => panic!("Expected a Codec 14 response, got {:?}", other),
+        }
+        device_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_codec15_serial_data() {
+        const IMEI: &str = "352093081452251";
+        // Whatever the serial device says, it doesn't have to be text
+        let serial = [0x02, 0x30, 0xFF, 0x00, 0x7E, 0x03];
+
+        let packet = Codec15Packet::new(1_644_238_347, IMEI, &serial).unwrap();
+        let bytes = packet.to_bytes();
+        assert_eq!(
+            &bytes[..15],
+            &[0, 0, 0, 0, 0, 0, 0, 0x1A, 0x0F, 0x01, 0x01, 0, 0, 0, 0x12]
+        );
+        assert_eq!(&bytes[15..19], &1_644_238_347u32.to_be_bytes());
+        assert_eq!(&bytes[19..27], &pack_imei(IMEI).unwrap());
+        assert_eq!(&bytes[27..33], &serial);
+
+        let mut parser = Parser::new();
+        parser.feed(&bytes);
+        match parser.next_frame().unwrap() {
+            Some(TeltonikaFrame::ThirdPartyData(received)) => {
+                assert_eq!(received, packet);
+                assert_eq!(received.imei, IMEI);
+                assert_eq!(received.payload, serial);
+                assert_eq!(
+                    received.time(),
+                    UNIX_EPOCH + Duration::from_secs(1_644_238_347)
+                );
+            }
+            other => panic!("Expected a Codec 15 packet, got {:?}", other),
+        }
+
+        // An IMEI that doesn't unpack into digits
+        let mut garbled = bytes.clone();
+        garbled[20] = 0x5B;
+        let crc_start = garbled.len() - 4;
+        let crc = calculate_crc16(&garbled[8..crc_start]);
+        garbled[crc_start..].copy_from_slice(&crc.to_be_bytes());
+        let mut parser = Parser::new();
+        parser.feed(&garbled);
+        let error = parser.next_frame().unwrap_err();
+        assert!(matches!(error, ParseError::InvalidImei { offset: 19, .. }));
+
+        // Nothing goes back to the device, the conversation just carries on
+        let mut state_machine = StateMachine::new(Duration::from_secs(30));
+        state_machine.handle_event(ProtocolEvent::Connect);
+        state_machine.handle_event(ProtocolEvent::Authenticate(IMEI.to_string(), String::new()));
+        state_machine.handle_event(ProtocolEvent::AuthSuccess);
+        let result =
+            state_machine.handle_event(ProtocolEvent::from(TeltonikaFrame::ThirdPartyData(packet)));
+        assert_eq!(result.state, ProtocolState::Ready);
+        assert!(result.actions.is_empty());
+
+        // Over a real line, the data is waiting at the post office once the device hangs up
+        let mock_device = MockDevice::new();
+        let device_addr = mock_device.addr();
+        let device_thread = mock_device.after_handshake(IMEI, move |mut socket, reply| {
+            socket.write_all(&bytes).unwrap();
+            reply
+        });
+
+        let mut connection = Connection::new(device_addr);
+        assert!(connection.connect().is_ok());
+        let pipeline = Arc::new(Mutex::new(ProcessingPipeline::new(10)));
+        let mut session = Session::new(
+            connection,
+            Arc::new(GateGuard::open()),
+            Arc::clone(&pipeline),
+            Duration::from_secs(5),
+        );
+        assert!(session.run().is_ok());
+        assert_eq!(device_thread.join().unwrap(), 0x01);
+
+        let messages = pipeline.lock().unwrap().take_messages();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].imei, IMEI);
+        match &messages[0].frame {
+            TeltonikaFrame::ThirdPartyData(received) => assert_eq!(received.payload, serial),
+            other => panic!("Expected a Codec 15 packet, got {:?}", other),
+        }
+        assert_eq!(session.ack_stats(), (0, 0));
+    }
+
+    #[test]
+    fn test_keepalive_ping() {
+        const IMEI: &str = "356307042441013";
+        let mut encoder = PacketEncoder::new();
+        let packet = encoder.encode(&create_mock_avl_packet(1));
+
+        // A ping is a whole frame on its own, wherever it turns up
+        let mut parser = Parser::new();
+        parser.feed(&[KEEPALIVE_PING, KEEPALIVE_PING]);
+        parser.feed(&packet);
+        parser.feed(&[KEEPALIVE_PING]);
+        assert_eq!(parser.next_frame().unwrap(), Some(TeltonikaFrame::Ping));
+        assert_eq!(parser.next_frame().unwrap(), Some(TeltonikaFrame::Ping));
+        assert!(matches!(
+            parser.next_frame().unwrap(),
+            Some(TeltonikaFrame::Avl(_))
+        ));
+        assert_eq!(parser.next_frame().unwrap(), Some(TeltonikaFrame::Ping));
+        assert_eq!(parser.next_frame().unwrap(), None);
+        assert_eq!(parser.skipped_bytes(), 0);
+
+        // Those who only want AVL data never get to see them
+        parser.feed(&[KEEPALIVE_PING]);
+        parser.feed(&packet);
+        parser.feed(&[KEEPALIVE_PING]);
+        assert!(parser.next_packet().unwrap().is_some());
+        assert_eq!(parser.next_packet().unwrap(), None);
+        parser.feed(&[KEEPALIVE_PING]);
+        parser.feed(&packet);
+        assert!(parser.next_packet_ref().unwrap().is_some());
+        assert!(parser.next_packet_ref().unwrap().is_none());
+        assert_eq!(parser.buffered_len(), 0);
+
+        // Nothing goes back to the device, and nothing changes but the idle time
+        let mut state_machine = StateMachine::new(Duration::from_millis(50));
+        state_machine.handle_event(ProtocolEvent::Connect);
+        state_machine.handle_event(ProtocolEvent::Authenticate(IMEI.to_string(), String::new()));
+        state_machine.handle_event(ProtocolEvent::AuthSuccess);
+        thread::sleep(Duration::from_millis(60));
+        assert!(state_machine.is_timed_out());
+        let result = state_machine.handle_event(ProtocolEvent::from(TeltonikaFrame::Ping));
+        assert_eq!(result.state, ProtocolState::Ready);
+        assert!(result.actions.is_empty());
+        assert!(!state_machine.is_timed_out());
+        assert!(state_machine.idle_time() < Duration::from_millis(50));
+
+        // An open link device that pings for a while, and then goes quiet for good
+        let idle_timeout = Duration::from_millis(300);
+        let mock_device = MockDevice::new();
+        let device_addr = mock_device.addr();
+        let device_thread = mock_device.after_handshake(IMEI, move |mut socket, reply| {
+            for _ in 0..6 {
+                thread::sleep(Duration::from_millis(100));
+                socket.write_all(&[KEEPALIVE_PING]).unwrap();
+            }
+
+            // We wait for the gateway to hang up on us
+            let mut rest = Vec::new();
+            socket.read_to_end(&mut rest).unwrap();
+            (reply, rest)
+        });
+
+        let mut connection = Connection::new(device_addr);
+        assert!(connection.connect().is_ok());
+        let mut session = Session::new(
+            connection,
+            Arc::new(GateGuard::open()),
+            Arc::new(Mutex::new(ProcessingPipeline::new(10))),
+            idle_timeout,
+        );
+
+        // The pings keep the line open for longer than the idle timeout,
+        // but once they stop, we don't wait for the 30 seconds of the read timeout
+        let start = Instant::now();
+        assert!(session.run().is_ok());
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(600) + idle_timeout);
+        assert!(elapsed < Duration::from_secs(5));
+        assert_eq!(session.state(), ProtocolState::Error);
+        assert_eq!(device_thread.join().unwrap(), (0x01, Vec::new()));
+
+        // A device that drips a packet in a byte at a time never goes quiet for a whole
+        // read timeout, but it hasn't said anything either until the packet is complete
+        let packet = encoder.encode(&create_mock_avl_packet(1));
+        let mock_device = MockDevice::new();
+        let device_addr = mock_device.addr();
+        let device_thread = mock_device.after_handshake(IMEI, move |mut socket, reply| {
+            for byte in packet {
+                thread::sleep(Duration::from_millis(50));
+                if socket.write_all(&[byte]).is_err() {
+                    break;
+                }
+            }
+            reply
+        });
+
+        let mut connection = Connection::new(device_addr);
+        assert!(connection.connect().is_ok());
+        let mut session = Session::new(
+            connection,
+            Arc::new(GateGuard::open()),
+            Arc::new(Mutex::new(ProcessingPipeline::new(10))),
+            idle_timeout,
+        );
+
+        let start = Instant::now();
+        assert!(session.run().is_ok());
+        let elapsed = start.elapsed();
+        assert!(elapsed >= idle_timeout);
+        assert!(elapsed < idle_timeout * 3);
+        assert_eq!(session.state(), ProtocolState::Error);
+        assert_eq!(session.ack_stats(), (0, 0));
+        assert_eq!(device_thread.join().unwrap(), 0x01);
+    }
+
+    #[test]
+    fn test_imei_handshake() {
+        const IMEI: &str = "356307042441013";
+        let mut encoder = PacketEncoder::new();
+        let serialized_data = encoder.encode(&create_mock_avl_packet(1));
+
+        // The device greets us, waits for our answer and only then hands over its data
+        let mock_device = MockDevice::new();
+        let device_addr = mock_device.addr();
+        let device_thread = mock_device.after_handshake(IMEI, move |mut socket, reply| {
+            if reply == 0x01 {
+                socket.write_all(&serialized_data).unwrap();
+            }
+            reply
+        });
+
+        let mut connection = Connection::new(device_addr);
+        assert!(connection.connect().is_ok());
+        let mut parser = Parser::new();
+        let mut state_machine = StateMachine::new(Duration::from_secs(5));
+        let guard = GateGuard::with_allowed([IMEI]);
+        state_machine.handle_event(ProtocolEvent::Connect);
+
+        let frame = parser
+            .parse_frame(connection.get_stream_mut().unwrap())
+            .unwrap()
+            .expect("Expected the IMEI handshake");
+        assert_eq!(frame, TeltonikaFrame::Imei(IMEI.to_string()));
+
+        // Anything but 15 digits isn't an IMEI, however much it looks like a greeting
+        for handshake in [&[0x00, 0x01, b'7'][..], &[0x00, 0x10][..]] {
+            let mut short = Parser::new();
+            short.feed(handshake);
+            let error = short.next_frame().unwrap_err();
+            assert!(matches!(error, ParseError::InvalidImei { offset: 0, .. }));
+        }
+
+        let result = state_machine.handle_event(ProtocolEvent::from(frame));
+        assert_eq!(result.state, ProtocolState::Authenticating);
+        assert_eq!(state_machine.imei(), Some(IMEI));
+
+        let result = state_machine.handle_event(guard.check(IMEI));
+        assert_eq!(result.state, ProtocolState::Ready);
+        for action in result.actions {
+            if let ProtocolAction::SendAuthResponse(accepted) = action {
+                connection.send_auth_response(accepted).unwrap();
+            }
+        }
+
+        let packet = parser.parse_stream(connection.get_stream_mut().unwrap());
+        assert!(matches!(packet, Ok(Some(_))));
+        assert_eq!(device_thread.join().unwrap(), 0x01);
+
+        // A device that isn't on the guest list is told no and shown the door
+        let mut state_machine = StateMachine::new(Duration::from_secs(5));
+        state_machine.handle_event(ProtocolEvent::Connect);
+        state_machine.handle_event(ProtocolEvent::Authenticate(
+            "490154203237518".to_string(),
+            String::new(),
+        ));
+        let result = state_machine.handle_event(guard.check("490154203237518"));
+        assert_eq!(result.state, ProtocolState::Disconnected);
+        assert!(result
+            .actions
+            .iter()
+            .any(|action| matches!(action, ProtocolAction::SendAuthResponse(false))));
+        assert!(result
+            .actions
+            .iter()
+            .any(|action| matches!(action, ProtocolAction::DisconnectClient)));
+    }
+
+    #[test]
+    fn test_record_count_acknowledgement() {
+        let mut state_machine = StateMachine::new(Duration::from_secs(5));
+        state_machine.handle_event(ProtocolEvent::Connect);
+        state_machine.handle_event(ProtocolEvent::Authenticate(
+            "356307042441013".to_string(),
+            String::new(),
+        ));
+        state_machine.handle_event(ProtocolEvent::AuthSuccess);
+
+        // Real packets carry whatever CRC they carry, that has nothing to do with ordering
+        let mut packets = vec![create_mock_avl_packet(3), create_mock_avl_packet(1)];
+        packets[0].crc16 = 0xBEEF;
+        packets[1].crc16 = 0x0042;
+
+        let mut acknowledgements = Vec::new();
+        for packet in packets {
+            let result = state_machine.handle_event(ProtocolEvent::PacketReceived(packet));
+            assert_eq!(result.state, ProtocolState::Ready);
+            assert_eq!(
+                result.actions.len(),
+                1,
+                "Only the acknowledgement should be sent"
+            );
+            match result.actions[0] {
+                ProtocolAction::SendAcknowledgement(count) => acknowledgements.push(count),
+                ref other => panic!("Expected an acknowledgement, got {:?}", other),
+            }
+        }
+        assert_eq!(acknowledgements, vec![3, 1]);
+        assert_eq!(state_machine.ack_stats(), (4, 2));
+
+        // On the wire the count is a 4 byte big-endian number
+        let mock_device = MockDevice::new();
+        let device_addr = mock_device.addr();
+        let device_thread = thread::spawn(move || {
+            let (mut socket, _) = mock_device.listener.accept().unwrap();
+            let mut ack = [0u8; 4];
+            socket.read_exact(&mut ack).unwrap();
+            ack
+        });
+
+        let mut connection = Connection::new(device_addr);
+        assert!(connection.connect().is_ok());
+        connection
+            .send_acknowledgement(acknowledgements[0])
+            .unwrap();
+        assert_eq!(device_thread.join().unwrap(), [0x00, 0x00, 0x00, 0x03]);
+    }
+
+    #[test]
+    fn test_device_listener() {
+        const ALLOWED_IMEI: &str = "356307042441013";
+        const UNKNOWN_IMEI: &str = "490154203237518";
+
+        let pipeline = Arc::new(Mutex::new(ProcessingPipeline::new(10)));
+        let listener = DeviceListener::bind(
+            "127.0.0.1:0",
+            GateGuard::with_allowed([ALLOWED_IMEI]),
+            Arc::clone(&pipeline),
+            SessionConfig::new(Duration::from_secs(5)),
+        )
+        .unwrap();
+        let gateway_addr = listener.local_addr().unwrap();
+
+        // This time the devices do the calling, just like real trackers
+        let devices: Vec<_> = [ALLOWED_IMEI, ALLOWED_IMEI, UNKNOWN_IMEI]
+            .into_iter()
+            .map(|imei| {
+                thread::spawn(move || {
+                    let mut socket = TcpStream::connect(gateway_addr).unwrap();
+                    socket.write_all(&mock_handshake(imei)).unwrap();
+
+                    let mut reply = [0u8; 1];
+                    socket.read_exact(&mut reply).unwrap();
+                    if reply[0] != 0x01 {
+                        return (reply[0], None);
+                    }
+
+                    let packet = PacketEncoder::new().encode(&create_mock_avl_packet(2));
+                    socket.write_all(&packet).unwrap();
+
+                    let mut ack = [0u8; 4];
+                    socket.read_exact(&mut ack).unwrap();
+                    (reply[0], Some(u32::from_be_bytes(ack)))
+                })
+            })
+            .collect();
+
+        let sessions: Vec<_> = (0..devices.len())
+            .map(|_| listener.accept_device().unwrap())
+            .collect();
+
+        let mut replies: Vec<_> = devices.into_iter().map(|d| d.join().unwrap()).collect();
+        replies.sort();
+        assert_eq!(
+            replies,
+            vec![(0x00, None), (0x01, Some(2)), (0x01, Some(2))]
+        );
+
+        for session in sessions {
+            assert!(session.join().unwrap().is_ok());
+        }
+
+        // Only the packets from the devices we let in should have reached the post office
+        let (incoming, outgoing) = listener.pipeline().lock().unwrap().queue_stats();
+        assert_eq!(incoming + outgoing, 2);
+    }
+
+    #[test]
+    fn test_session_driver() {
+        const IMEI: &str = "356307042441013";
+
+        // A tracker that says hello, sends two packets and waits for each acknowledgement
+        let mock_device = MockDevice::new();
+        let device_addr = mock_device.addr();
+        let device_thread = mock_device.after_handshake(IMEI, move |mut socket, reply| {
+            let mut encoder = PacketEncoder::new();
+
+            let mut acks = Vec::new();
+            for records in [3, 1] {
+                let packet = encoder.encode(&create_mock_avl_packet(records));
+                socket.write_all(&packet).unwrap();
+
+                let mut ack = [0u8; 4];
+                socket.read_exact(&mut ack).unwrap();
+                acks.push(u32::from_be_bytes(ack));
+            }
+            (reply, acks)
+        });
+
+        let mut connection = Connection::new(device_addr);
+        assert!(connection.connect().is_ok());
+
+        let pipeline = Arc::new(Mutex::new(ProcessingPipeline::new(10)));
+        let mut session = Session::new(
+            connection,
+            Arc::new(GateGuard::open()),
+            Arc::clone(&pipeline),
+            Duration::from_secs(5),
+        );
+
+        // The session keeps going on its own until the device hangs up
+        assert!(session.run().is_ok());
+        assert_eq!(session.imei(), Some(IMEI));
+        assert_eq!(session.state(), ProtocolState::Disconnected);
+        assert_eq!(session.ack_stats(), (4, 2));
+
+        assert_eq!(device_thread.join().unwrap(), (0x01, vec![3, 1]));
+        let (incoming, outgoing) = pipeline.lock().unwrap().queue_stats();
+        assert_eq!(incoming + outgoing, 2);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_event_loop() {
+        const DEVICES: usize = 50;
+        const IMEI: &str = "356307042441013";
+
+        let pipeline = Arc::new(Mutex::new(ProcessingPipeline::new(10)));
+        let event_loop = EventLoop::bind(
+            "127.0.0.1:0",
+            GateGuard::open(),
+            Arc::clone(&pipeline),
+            SessionConfig::new(Duration::from_secs(1)),
+            2,
+        )
+        .unwrap();
+        let gateway_addr = event_loop.local_addr().unwrap();
+        let handle = event_loop.spawn().unwrap();
+
+        // Far more devices than workers, they all have to take turns on the same few threads
+        let devices: Vec<_> = (0..DEVICES)
+            .map(|_| {
+                thread::spawn(move || {
+                    let mut socket = TcpStream::connect(gateway_addr).unwrap();
+                    let mut encoder = PacketEncoder::new();
+                    socket.write_all(&mock_handshake(IMEI)).unwrap();
+
+                    let mut reply = [0u8; 1];
+                    socket.read_exact(&mut reply).unwrap();
+
+                    let mut acks = Vec::new();
+                    for records in [2, 1] {
+                        let packet = encoder.encode(&create_mock_avl_packet(records));
+                        socket.write_all(&packet).unwrap();
+
+                        let mut ack = [0u8; 4];
+                        socket.read_exact(&mut ack).unwrap();
+                        acks.push(u32::from_be_bytes(ack));
+                    }
+                    (reply[0], acks)
+                })
+            })
+            .collect();
+
+        for device in devices {
+            assert_eq!(device.join().unwrap(), (0x01, vec![2, 1]));
+        }
+
+        // A device that never says a word gets hung up on once the timeout passes
+        let mut quiet_device = TcpStream::connect(gateway_addr).unwrap();
+        quiet_device
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut buffer = [0u8; 1];
+        assert_eq!(quiet_device.read(&mut buffer).unwrap(), 0);
+
+        assert!(handle.stop().is_ok());
+        let (incoming, outgoing) = pipeline.lock().unwrap().queue_stats();
+        assert_eq!(incoming + outgoing, DEVICES * 2);
+    }
+
+    #[test]
+    fn test_shutdown_flushes_pending_output() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (go, wait) = mpsc::channel();
+
+        // A device that doesn't read a word until we hang up, so what we say has to wait
+        let device_thread = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            wait.recv().unwrap();
+            let mut heard = Vec::new();
+            socket.read_to_end(&mut heard).unwrap();
+            heard
+        });
+
+        let mut connection = Connection::new(addr);
+        assert!(connection.connect().is_ok());
+        connection.set_nonblocking(true).unwrap();
+
+        let command = Codec12CommandPacket::new("getinfo").to_bytes();
+        let mut said = Vec::new();
+        while !connection.has_pending_output() {
+            connection.send_command("getinfo").unwrap();
+            said.extend_from_slice(&command);
+        }
+
+        // The last thing we have to say is the one that matters most, it is why we hang up
+        connection.send_auth_response(false).unwrap();
+        said.push(0x00);
+
+        go.send(()).unwrap();
+        assert!(connection.shutdown().is_ok());
+        assert!(!connection.has_pending_output());
+        assert_eq!(device_thread.join().unwrap(), said);
+    }
+
+    #[test]
+    fn test_reads_per_wakeup() {
+        const IMEI: &str = "356307042441013";
+        const PACKETS: usize = 1000;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (written, wait) = mpsc::channel();
+
+        // A device with a lot to say, all of it on the line before we get to look
+        let device_thread = thread::spawn(move || {
+            let mut socket = TcpStream::connect(addr).unwrap();
+            let packet = PacketEncoder::new().encode(&create_mock_avl_packet(1));
+            let mut talk = mock_handshake(IMEI);
+            for _ in 0..PACKETS {
+                talk.extend_from_slice(&packet);
+            }
+            socket.write_all(&talk).unwrap();
+            written.send(()).unwrap();
+
+            // Staying on the line, so the only way we get through it all is by listening
+            let mut replies = vec![0u8; 1 + 4 * PACKETS];
+            socket.read_exact(&mut replies).unwrap();
+            socket
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut connection = Connection::from_stream(stream).unwrap();
+        connection.set_nonblocking(true).unwrap();
+        let mut session = Session::new(
+            connection,
+            Arc::new(GateGuard::open()),
+            Arc::new(Mutex::new(ProcessingPipeline::new(10))),
+            Duration::from_secs(5),
+        );
+        wait.recv().unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        // One wakeup doesn't get through all of it, the other devices get their turn first
+        assert!(session.on_readable().unwrap());
+        let (_, packets) = session.ack_stats();
+        assert!(packets > 0 && packets < PACKETS as u64);
+
+        // Nothing is lost waiting for the next one though
+        for _ in 0..100 {
+            if session.ack_stats().1 == PACKETS as u64 {
+                break;
+            }
+            assert!(session.on_readable().unwrap());
+        }
+        assert_eq!(session.ack_stats(), (PACKETS as u64, PACKETS as u64));
+        device_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_sans_io_parser() {
+        const IMEI: &str = "356307042441013";
+
+        let mut encoder = PacketEncoder::new();
+        let first = create_mock_avl_packet(2);
+        let second = create_mock_avl_packet(1);
+
+        let mut bytes = mock_handshake(IMEI);
+        bytes.extend(encoder.encode(&first));
+        bytes.extend(encoder.encode(&second));
+
+        // No socket in sight, the bytes trickle in one at a time
+        let mut parser = Parser::new();
+        let mut frames = Vec::new();
+        for byte in &bytes {
+            parser.feed(std::slice::from_ref(byte));
+            if let Some(frame) = parser.next_frame().unwrap() {
+                frames.push(frame);
+            }
+        }
+        assert_eq!(parser.buffered_len(), 0);
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0], TeltonikaFrame::Imei(IMEI.to_string()));
+        match (&frames[1], &frames[2]) {
+            (TeltonikaFrame::Avl(a), TeltonikaFrame::Avl(b)) => {
+                assert_eq!(a.avl_data, first.avl_data);
+                assert_eq!(b.avl_data, second.avl_data);
+            }
+            other => panic!("Expected two AVL packets, got {:?}", other),
+        }
+
+        // Anything that can be read from works with the stream adapter too
+        let packet_bytes = encoder.encode(&first);
+        let mut source = Cursor::new(packet_bytes);
+        let mut parser = Parser::new();
+        let packet = parser.parse_stream(&mut source).unwrap().unwrap();
+        assert_eq!(packet.number_of_data1, 2);
+        assert!(parser.parse_stream(&mut source).unwrap().is_none());
+        assert!(parser.is_finished());
+
+        // next_packet only hands out AVL data
+        let mut parser = Parser::new();
+        parser.feed(&bytes[..2 + IMEI.len()]);
+        assert!(parser.next_packet().is_err());
+    }
+
+    #[test]
+    fn test_back_to_back_packets() {
+        const IMEI: &str = "356307042441013";
+        let mut encoder = PacketEncoder::new();
+
+        // Three packets landing in one go all come out, without another read
+        let mut parser = Parser::new();
+        let mut bytes = Vec::new();
+        for records in [1, 2, 3] {
+            bytes.extend(encoder.encode(&create_mock_avl_packet(records)));
+        }
+        parser.feed(&bytes);
+        let counts: Vec<_> = parser
+            .frames()
+            .map(|frame| match frame.unwrap() {
+                TeltonikaFrame::Avl(packet) => packet.number_of_data1,
+                other => panic!("Expected an AVL packet, got {:?}", other),
+            })
+            .collect();
+        assert_eq!(counts, vec![1, 2, 3]);
+        assert_eq!(parser.buffered_len(), 0);
+
+        // A bad frame shows up once, rather than sending us round in circles
+        parser.feed(&[0, 0, 0, 0, 0, 0, 0, 0x2D, 0xFF]);
+        parser.feed(&[0u8; 48]);
+        let results: Vec<_> = parser.frames().collect();
+        assert_eq!(results.len(), 1);
+        assert!(results[0].is_err());
+
+        // A device that sends its hello and two packets in one segment, and then waits
+        // for the answers, has to get all of them before it sends anything else
+        let mock_device = MockDevice::new();
+        let device_addr = mock_device.addr();
+        let device_thread = thread::spawn(move || {
+            let (mut socket, _) = mock_device.listener.accept().unwrap();
+            let mut bytes = mock_handshake(IMEI);
+            for records in [2, 1] {
+                bytes.extend(encoder.encode(&create_mock_avl_packet(records)));
+            }
+            socket.write_all(&bytes).unwrap();
+
+            let mut answers = [0u8; 9];
+            socket.read_exact(&mut answers).unwrap();
+            answers
+        });
+
+        let mut connection = Connection::new(device_addr);
+        assert!(connection.connect().is_ok());
+        let pipeline = Arc::new(Mutex::new(ProcessingPipeline::new(10)));
+        let mut session = Session::new(
+            connection,
+            Arc::new(GateGuard::open()),
+            Arc::clone(&pipeline),
+            Duration::from_secs(5),
+        );
+        assert!(session.run().is_ok());
+
+        let answers = device_thread.join().unwrap();
+        assert_eq!(answers, [0x01, 0, 0, 0, 2, 0, 0, 0, 1]);
+        assert_eq!(session.ack_stats(), (3, 2));
+    }
+
+    #[test]
+    fn test_resynchronise_after_corrupt_frame() {
+        let mut encoder = PacketEncoder::new();
+        let first = encoder.encode(&create_mock_avl_packet(1));
+        let mut corrupt = encoder.encode(&create_mock_avl_packet(2));
+        let last = encoder.encode(&create_mock_avl_packet(3));
+
+        // A single flipped bit in the middle packet shouldn't cost us the one behind it.
+        // It lands in the altitude of the first record, so only the CRC can tell.
+        let altitude = 10 + 17;
+        corrupt[altitude] ^= 0x01;
+
+        let mut parser = Parser::new();
+        parser.feed(&first);
+        parser.feed(&corrupt);
+        parser.feed(&last);
+
+        let results: Vec<_> = parser.frames().collect();
+        assert_eq!(results.len(), 3);
+        assert!(matches!(&results[0], Ok(TeltonikaFrame::Avl(p)) if p.number_of_data1 == 1));
+        assert_eq!(
+            results[1].as_ref().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert!(matches!(&results[2], Ok(TeltonikaFrame::Avl(p)) if p.number_of_data1 == 3));
+        assert_eq!(parser.skipped_bytes(), corrupt.len() as u64);
+        assert_eq!(parser.buffered_len(), 0);
+
+        // Line noise in front of a packet is skipped, and the packet still comes through
+        let mut parser = Parser::new();
+        parser.feed(&[0xDE, 0xAD, 0xBE, 0xEF, 0x00, 0x00]);
+        parser.feed(&first);
+        let error = parser.next_frame().unwrap_err();
+        assert!(matches!(error, ParseError::InvalidImei { .. }));
+        assert!(matches!(
+            parser.next_frame(),
+            Ok(Some(TeltonikaFrame::Avl(_)))
+        ));
+        assert_eq!(parser.skipped_bytes(), 6);
+
+        // Once the device has introduced itself, the same noise is a bad preamble, not a second hello
+        let mut parser = Parser::new();
+        parser.set_greeted(true);
+        parser.feed(&[0xDE, 0xAD, 0xBE, 0xEF, 0x00, 0x00]);
+        parser.feed(&first);
+        let error = parser.next_frame().unwrap_err();
+        assert!(matches!(
+            error,
+            ParseError::BadPreamble {
+                found: 0xDEADBEEF,
+                ..
+            }
+        ));
+        assert!(error.is_recoverable());
+        assert!(matches!(
+            parser.next_frame(),
+            Ok(Some(TeltonikaFrame::Avl(_)))
+        ));
+        assert_eq!(parser.skipped_bytes(), 6);
+
+        // A Session doesn't hang up over a bad frame either, it waits for the next one.
+        // Not even when the frame's preamble is the part that got mangled.
+        let mut mangled = first.clone();
+        mangled[0] = 0x01;
+        const IMEI: &str = "356307042441013";
+        let mock_device = MockDevice::new();
+        let device_addr = mock_device.addr();
+        let skipped = (mangled.len() + corrupt.len()) as u64;
+        let device_thread = mock_device.after_handshake(IMEI, move |mut socket, _| {
+            socket.write_all(&mangled).unwrap();
+            socket.write_all(&corrupt).unwrap();
+            socket.write_all(&last).unwrap();
+
+            let mut ack = [0u8; 4];
+            socket.read_exact(&mut ack).unwrap();
+            u32::from_be_bytes(ack)
+        });
+
+        let mut connection = Connection::new(device_addr);
+        assert!(connection.connect().is_ok());
+        let mut session = Session::new(
+            connection,
+            Arc::new(GateGuard::open()),
+            Arc::new(Mutex::new(ProcessingPipeline::new(10))),
+            Duration::from_secs(5),
+        );
+        assert!(session.run().is_ok());
+        assert_eq!(device_thread.join().unwrap(), 3);
+        assert_eq!(session.ack_stats(), (3, 1));
+        assert_eq!(session.skipped_bytes(), skipped);
+        assert_eq!(session.state(), ProtocolState::Disconnected);
+    }
+
+    #[test]
+    fn test_parse_error_details() {
+        let mut encoder = PacketEncoder::new();
+        let packet = encoder.encode(&create_mock_avl_packet(1));
+        let crc_offset = packet.len() - 4;
+
+        let first_error = |bytes: &[u8]| {
+            let mut parser = Parser::new();
+            parser.feed(bytes);
+            parser.next_frame().unwrap_err()
+        };
+
+        // A torn page and a forged one look nothing alike anymore
+        let mut corrupt = packet.clone();
+        corrupt[crc_offset + 3] ^= 0xFF;
+        let error = first_error(&corrupt);
+        match &error {
+            ParseError::CrcMismatch {
+                expected, actual, ..
+            } => {
+                assert_eq!(*expected, calculate_crc16(&packet[8..crc_offset]));
+                assert_eq!(*actual, *expected ^ 0xFF);
+            }
+            other => panic!("Expected a CRC mismatch, got {:?}", other),
+        }
+        assert_eq!(error.offset(), crc_offset);
+        assert_eq!(error.codec(), Some(0x08));
+
+        let mut corrupt = packet.clone();
+        corrupt[crc_offset - 1] = 2;
+        let error = first_error(&corrupt);
+        assert_eq!(
+            error,
+            ParseError::RecordCountMismatch {
+                first: 1,
+                second: 2,
+                offset: crc_offset - 1,
+                codec: Some(0x08),
+            }
+        );
+
+        let mut corrupt = packet.clone();
+        corrupt[8] = 0x42;
+        assert_eq!(
+            first_error(&corrupt),
+            ParseError::UnsupportedCodec {
+                offset: 8,
+                codec: Some(0x42),
+            }
+        );
+
+        let mut corrupt = packet.clone();
+        corrupt[3] = 0x01;
+        assert_eq!(
+            first_error(&corrupt),
+            ParseError::BadPreamble {
+                found: 1,
+                offset: 0,
+                codec: None,
+            }
+        );
+
+        // The record claims more than the frame has room for
+        let mut corrupt = encoder.encode(&create_mock_avl_packet(3));
+        let data_length = (corrupt.len() - 12 - 10) as u32;
+        corrupt[4..8].copy_from_slice(&data_length.to_be_bytes());
+        let error = first_error(&corrupt);
+        assert!(matches!(error, ParseError::Incomplete { .. }));
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(error.codec(), Some(0x08));
+
+        // Codec id, record count, timestamp and priority come before the longitude
+        let mut off_the_map = create_mock_avl_packet(1);
+        off_the_map.avl_data[0].gps.longitude = 200_0000000;
+        let corrupt = encoder.encode(&off_the_map);
+        assert_eq!(
+            first_error(&corrupt),
+            ParseError::CoordinateOutOfRange {
+                coordinate: Coordinate::Longitude,
+                value: 200_0000000,
+                offset: 19,
+                codec: Some(0x08),
+            }
+        );
+
+        // Over a socket it is an io::Error, but the ParseError is still in there
+        let mut corrupt = packet.clone();
+        corrupt[crc_offset] ^= 0x01;
+        let error = Parser::new()
+            .parse_stream(&mut Cursor::new(corrupt))
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let inner = error.get_ref().unwrap().downcast_ref::<ParseError>();
+        assert!(matches!(inner, Some(ParseError::CrcMismatch { .. })));
+    }
+
+    #[test]
+    fn test_encoder_round_trip() {
+        let mut encoder = PacketEncoder::new();
+
+        let mut extended = create_mock_avl_packet(2);
+        extended.codec_id = 0x8E;
+        for data in extended.avl_data.iter_mut() {
+            data.io = IOElement::Codec8Extended(IOElement8Extended {
+                event_io_id: 0x0100,
+                n_total_io: 3,
+                n1_of_one_byte: 1,
+                one_byte_ios: vec![(0x00EF, 1)],
+                n2_of_two_bytes: 0,
+                two_byte_ios: vec![],
+                n4_of_four_bytes: 1,
+                four_byte_ios: vec![(0x0101, 0xCAFEBABE)],
+                n8_of_eight_bytes: 0,
+                eight_byte_ios: vec![],
+                nx_of_var_bytes: 1,
+                var_byte_ios: vec![(0x0102, 4, vec![9, 8, 7, 6])],
+            });
+        }
+
+        let mut codec16 = create_mock_avl_packet(1);
+        codec16.codec_id = 0x10;
+        codec16.avl_data[0].io = IOElement::Codec16(IOElement16 {
+            event_io_id: 0x0200,
+            generation_type: 2,
+            n_total_io: 2,
+            n1_of_one_byte: 0,
+            one_byte_ios: vec![],
+            n2_of_two_bytes: 1,
+            two_byte_ios: vec![(0x0201, 500)],
+            n4_of_four_bytes: 0,
+            four_byte_ios: vec![],
+            n8_of_eight_bytes: 1,
+            eight_byte_ios: vec![(0x0202, u64::MAX)],
+        });
+
+        for packet in [create_mock_avl_packet(3), extended, codec16] {
+            let bytes = encoder.encode(&packet);
+
+            let mut parser = Parser::new();
+            parser.feed(&bytes);
+            let parsed = parser.next_packet().unwrap().unwrap();
+
+            // The parsed packet carries the length and CRC the encoder worked out
+            assert_eq!(parsed.data_length as usize, bytes.len() - 12);
+            assert_eq!(parsed.crc16, calculate_crc16(&bytes[8..bytes.len() - 4]));
+            assert_eq!(parsed.avl_data, packet.avl_data);
+
+            // And writing it out again gives back the very same bytes
+            assert_eq!(encoder.encode(&parsed), bytes);
+            assert_eq!(parsed.to_bytes(), bytes);
+        }
+    }
+
+    #[test]
+    fn test_encoder_counts_what_it_writes() {
+        let mut encoder = PacketEncoder::new();
+        let mut parser = Parser::new();
+        parser.feed(&encoder.encode(&create_mock_avl_packet(3)));
+        let mut packet = parser.next_packet().unwrap().unwrap();
+
+        // Drop a record after parsing, the stored counts still say 3
+        packet.avl_data.remove(1);
+        assert_eq!(packet.number_of_data1, 3);
+
+        // And grow an IO group without touching its counts or the var-length prefix
+        packet.codec_id = 0x8E;
+        packet.avl_data[0].io = IOElement::Codec8Extended(IOElement8Extended {
+            event_io_id: 0,
+            n_total_io: 0,
+            n1_of_one_byte: 0,
+            one_byte_ios: vec![(0x00EF, 1), (0x00F0, 0)],
+            n2_of_two_bytes: 0,
+            two_byte_ios: vec![],
+            n4_of_four_bytes: 0,
+            four_byte_ios: vec![],
+            n8_of_eight_bytes: 0,
+            eight_byte_ios: vec![],
+            nx_of_var_bytes: 0,
+            var_byte_ios: vec![(0x0102, 1, vec![9, 8, 7, 6])],
+        });
+        packet.avl_data[1].io = IOElement::Codec8Extended(IOElement8Extended {
+            event_io_id: 0,
+            n_total_io: 0,
+            n1_of_one_byte: 0,
+            one_byte_ios: vec![],
+            n2_of_two_bytes: 0,
+            two_byte_ios: vec![],
+            n4_of_four_bytes: 0,
+            four_byte_ios: vec![],
+            n8_of_eight_bytes: 0,
+            eight_byte_ios: vec![],
+            nx_of_var_bytes: 0,
+            var_byte_ios: vec![],
+        });
+
+        let bytes = encoder.encode(&packet);
+        parser.feed(&bytes);
+        let parsed = parser.next_packet().unwrap().unwrap();
+
+        assert_eq!(parsed.number_of_data1, 2);
+        assert_eq!(parsed.number_of_data2, 2);
+        assert_eq!(parsed.avl_data.len(), 2);
+        match &parsed.avl_data[0].io {
+            IOElement::Codec8Extended(io) => {
+                assert_eq!(io.n_total_io, 3);
+                assert_eq!(io.n1_of_one_byte, 2);
+                assert_eq!(io.nx_of_var_bytes, 1);
+                assert_eq!(io.var_byte_ios, vec![(0x0102, 4, vec![9, 8, 7, 6])]);
+            }
+            other => panic!("Expected a Codec 8 Extended IO element, got {:?}", other),
+        }
+
+        // Once the counts are right, writing it out again gives back the very same bytes
+        assert_eq!(encoder.encode(&parsed), bytes);
+    }
+
+    #[test]
+    fn test_encoder_keeps_the_io_total_it_read() {
+        // Firmware that leaves its variable length element out of the IO total,
+        // laid out by hand so the encoder has no say in it
+        let mut record = vec![0x00, 0x00, 0x01, 0x7E, 0xD3, 0x1A, 0x0B, 0x18, 0x01];
+        record.extend_from_slice(&[0; 15]);
+        record.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]); // event id, total of 1
+        record.extend_from_slice(&[0x00, 0x01, 0x00, 0xEF, 0x01]); // one 1 byte element
+        record.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00]); // no 2, 4 or 8 bytes
+        record.extend_from_slice(&[0x00, 0x01, 0x01, 0x02, 0x00, 0x02, 0xAB, 0xCD]);
+        let mut data = vec![0x8E, 0x01];
+        data.extend_from_slice(&record);
+        data.push(0x01);
+        let mut frame = vec![0x00, 0x00, 0x00, 0x00];
+        frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&data);
+        frame.extend_from_slice(&calculate_crc16(&data).to_be_bytes());
+
+        let mut parser = Parser::new();
+        parser.feed(&frame);
+        let parsed = parser.next_packet().unwrap().unwrap();
+        assert!(matches!(
+            parsed.avl_data[0].warnings[..],
+            [ParseError::IoCountMismatch {
+                declared: 1,
+                counted: 2,
+                ..
+            }]
+        ));
+
+        // Written out again, it is the same frame, total and all
+        assert_eq!(parsed.to_bytes(), frame);
+        let mut encoder = PacketEncoder::new();
+        assert_eq!(encoder.encode(&parsed), frame);
+
+        // Unless we ask for the total to be put right
+        encoder.set_normalise_totals(true);
+        parser.feed(&encoder.encode(&parsed));
+        let normalised = parser.next_packet().unwrap().unwrap();
+        assert!(normalised.avl_data[0].warnings.is_empty());
+        match &normalised.avl_data[0].io {
+            IOElement::Codec8Extended(io) => assert_eq!(io.n_total_io, 2),
+            other => panic!("Expected a Codec 8 Extended IO element, got {:?}", other),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "256 records don't fit in the frame")]
+    fn test_encoder_refuses_counts_that_wrap() {
+        // One record too many for the count, which would otherwise be written as 0
+        let mut packet = create_mock_avl_packet(255);
+        packet.avl_data.push(packet.avl_data[0].clone());
+        PacketEncoder::new().encode(&packet);
+    }
+
+    #[test]
+    fn test_packet_view() {
+        let mut encoder = PacketEncoder::new();
+
+        let mut extended = create_mock_avl_packet(2);
+        extended.codec_id = 0x8E;
+        extended.avl_data[1].io = IOElement::Codec8Extended(IOElement8Extended {
+            event_io_id: 0x0100,
+            n_total_io: 3,
+            n1_of_one_byte: 1,
+            one_byte_ios: vec![(0x00EF, 1)],
+            n2_of_two_bytes: 1,
+            two_byte_ios: vec![(0x0042, 12_345)],
+            n4_of_four_bytes: 0,
+            four_byte_ios: vec![],
+            n8_of_eight_bytes: 0,
+            eight_byte_ios: vec![],
+            nx_of_var_bytes: 2,
+            var_byte_ios: vec![(0x0102, 4, vec![9, 8, 7, 6]), (0x0103, 0, vec![])],
+        });
+        extended.avl_data[0].io = IOElement::Codec8Extended(IOElement8Extended {
+            event_io_id: 0,
+            n_total_io: 0,
+            n1_of_one_byte: 0,
+            one_byte_ios: vec![],
+            n2_of_two_bytes: 0,
+            two_byte_ios: vec![],
+            n4_of_four_bytes: 0,
+            four_byte_ios: vec![],
+            n8_of_eight_bytes: 0,
+            eight_byte_ios: vec![],
+            nx_of_var_bytes: 0,
+            var_byte_ios: vec![],
+        });
+        let plain = create_mock_avl_packet(3);
+
+        let mut stream = encoder.encode(&extended);
+        stream.extend_from_slice(&encoder.encode(&plain));
+        let first_length = encoder.encode(&extended).len();
+
+        let mut parser = Parser::new();
+        parser.feed(&stream);
+
+        {
+            let view = parser.next_packet_ref().unwrap().unwrap();
+            assert_eq!(view.codec_id(), 0x8E);
+            assert_eq!(view.number_of_data(), 2);
+            assert_eq!(view.as_bytes(), &stream[..first_length]);
+
+            let record = view.records().nth(1).unwrap();
+            assert_eq!(record.timestamp(), extended.avl_data[1].timestamp);
+            assert_eq!(record.gps(), extended.avl_data[1].gps);
+
+            let io = record.io();
+            assert_eq!(io.event_io_id(), 0x0100);
+            assert_eq!(
+                io.two_byte_ios().collect::<Vec<_>>(),
+                vec![(0x0042, 12_345)]
+            );
+            let var: Vec<_> = io.var_byte_ios().collect();
+            assert_eq!(var, vec![(0x0102, &[9u8, 8, 7, 6][..]), (0x0103, &[][..])]);
+
+            // Straight out of the buffer, not a copy of it
+            let buffer = view.as_bytes().as_ptr_range();
+            assert!(buffer.contains(&var[0].1.as_ptr()));
+
+            let mut owned = Parser::new();
+            owned.feed(&stream[..first_length]);
+            assert_eq!(view.to_packet(), owned.next_packet().unwrap().unwrap());
+        }
+
+        // The pages we lent out are only thrown away once we are asked for more
+        assert_eq!(parser.buffered_len(), stream.len() - first_length);
+        let view = parser.next_packet_ref().unwrap().unwrap();
+        assert_eq!(view.codec_id(), 0x08);
+        let records: Vec<_> = view.records().map(|r| r.to_avl_data()).collect();
+        assert_eq!(records, plain.avl_data);
+        assert!(parser.next_packet_ref().unwrap().is_none());
+        assert_eq!(parser.buffered_len(), 0);
+
+        // A bad frame is turned down just like next_packet would, and we find our feet again
+        let mut corrupt = encoder.encode(&plain);
+        let crc_offset = corrupt.len() - 4;
+        corrupt[crc_offset] ^= 0x01;
+        parser.feed(&corrupt);
+        parser.feed(&encoder.encode(&plain));
+        let error = parser.next_packet_ref().unwrap_err();
+        assert!(matches!(error, ParseError::CrcMismatch { offset, .. } if offset == crc_offset));
+        let view = parser.next_packet_ref().unwrap().unwrap();
+        assert_eq!(view.to_packet().avl_data, plain.avl_data);
+
+        // The same goes for the views made without a Parser
+        assert_eq!(AVLPacketRef::parse(&corrupt).unwrap_err(), error);
+    }
+
+    #[test]
+    fn test_receive_buffer_stays_bounded() {
+        let mut encoder = PacketEncoder::new();
+        let mut stream = Vec::new();
+        for i in 0..200 {
+            stream.extend(encoder.encode(&create_mock_avl_packet(i % 10 + 1)));
+        }
+        assert!(stream.len() > 4 * RECEIVE_BUFFER_SIZE);
+
+        // Read from a socket, the desk never grows, however much is waiting
+        let mut parser = Parser::new();
+        let mut source = Cursor::new(stream.clone());
+        let mut packets = 0;
+        while !parser.is_finished() {
+            if parser.parse_stream(&mut source).unwrap().is_some() {
+                packets += 1;
+            }
+            assert!(parser.buffered_len() <= RECEIVE_BUFFER_SIZE);
+        }
+        assert_eq!(packets, 200);
+
+        // Fed by hand, we take what fits, and the rest comes after some frames are out
+        let mut parser = Parser::new();
+        let mut rest = &stream[..];
+        let mut packets = 0;
+        while !rest.is_empty() {
+            let taken = parser.feed(rest);
+            assert!(taken > 0);
+            rest = &rest[taken..];
+            while parser.next_packet_ref().unwrap().is_some() {
+                packets += 1;
+            }
+        }
+        assert_eq!(packets, 200);
+        assert_eq!(parser.buffered_len(), 0);
+
+        // A frame that could never fit is turned down as soon as it says how big it is
+        let mut parser = Parser::new();
+        parser.feed(&[0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF]);
+        let error = parser.next_frame().unwrap_err();
+        assert_eq!(
+            error,
+            ParseError::FrameTooLarge {
+                length: 0xFFFF_FFFF + 12,
+                maximum: LARGEST_AVL_SIZE,
+                offset: 4,
+                codec: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_device_profiles() {
+        let mut encoder = PacketEncoder::new();
+
+        // 20 Codec 8 records make a frame of 655 bytes, fine for an FMB, too big for an FM6XXX
+        let large = encoder.encode(&create_mock_avl_packet(20));
+        assert!(large.len() > MAX_AVL_PACKET_SIZE_FM6XXX && large.len() <= LARGEST_AVL_SIZE);
+
+        let mut parser = Parser::with_profile(DeviceProfile::FMB);
+        parser.feed(&large);
+        assert_eq!(parser.next_packet().unwrap().unwrap().number_of_data1, 20);
+
+        // The FM6XXX is turned down as soon as it says how big the frame is
+        let mut parser = Parser::with_profile(DeviceProfile::FM6XXX);
+        parser.feed(&large[..8]);
+        assert_eq!(
+            parser.next_frame().unwrap_err(),
+            ParseError::FrameTooLarge {
+                length: large.len(),
+                maximum: MAX_AVL_PACKET_SIZE_FM6XXX,
+                offset: 4,
+                codec: None,
+            }
+        );
 
-        let mut packet2 = create_mock_avl_packet(1);
-        packet2.avl_data[0].priority = 8; // High priority
+        // A small frame can still hold a record bigger than the device would ever write
+        let mut packet = create_mock_avl_packet(1);
+        packet.codec_id = 0x8E;
+        packet.avl_data[0].io = IOElement::Codec8Extended(IOElement8Extended {
+            event_io_id: 0,
+            n_total_io: 1,
+            n1_of_one_byte: 0,
+            one_byte_ios: vec![],
+            n2_of_two_bytes: 0,
+            two_byte_ios: vec![],
+            n4_of_four_bytes: 0,
+            four_byte_ios: vec![],
+            n8_of_eight_bytes: 0,
+            eight_byte_ios: vec![],
+            nx_of_var_bytes: 1,
+            var_byte_ios: vec![(0x0100, 300, vec![0xAB; 300])],
+        });
+        let bytes = encoder.encode(&packet);
+        assert!(bytes.len() <= MAX_AVL_PACKET_SIZE_FM6XXX);
 
-        // Test priority handling
-        pipeline.process_incoming(packet1.clone(), None).unwrap();
-        pipeline.process_incoming(packet2.clone(), None).unwrap();
+        let mut parser = Parser::new();
+        parser.set_profile(DeviceProfile::FM6XXX);
+        parser.feed(&bytes);
+        let error = parser.next_packet().unwrap_err();
+        assert_eq!(
+            error,
+            ParseError::RecordTooLarge {
+                length: bytes.len() - 15,
+                maximum: MAX_AVL_RECORD_SIZE_FM6XXX,
+                offset: 10,
+                codec: Some(0x8E),
+            }
+        );
+        let view = AVLPacketRef::parse_with_profile(&bytes, &DeviceProfile::FM6XXX);
+        assert_eq!(view.unwrap_err(), error);
+        assert!(AVLPacketRef::parse_with_profile(&bytes, &DeviceProfile::FMC).is_ok());
+
+        // A profile can't make us wait for more than any device could send
+        let generous = DeviceProfile {
+            name: "generous",
+            max_frame_size: usize::MAX,
+            max_record_size: usize::MAX,
+        };
+        assert_eq!(generous.frame_limit(), LARGEST_AVL_SIZE);
+        assert!(generous.record_limit() < LARGEST_AVL_SIZE);
+
+        // The families can be asked for by name, the way main is given them
+        assert_eq!(DeviceProfile::named("fm6xxx"), Some(DeviceProfile::FM6XXX));
+        assert_eq!(DeviceProfile::named("FMB"), Some(DeviceProfile::FMB));
+        assert_eq!(DeviceProfile::named("FMX"), None);
+
+        // A mailbox set up for FM6XXX devices turns down the record an FMC could send
+        let udp = UdpAvlPacket {
+            packet_id: 1,
+            avl_packet_id: 1,
+            imei: "356307042441013".to_string(),
+            packet,
+        };
+        let pipeline = Arc::new(Mutex::new(ProcessingPipeline::new(10)));
+        let config = SessionConfig {
+            profile: DeviceProfile::FM6XXX,
+            ..SessionConfig::new(Duration::from_secs(5))
+        };
+        let listener =
+            UdpListener::bind("127.0.0.1:0", GateGuard::open(), pipeline, config).unwrap();
+        let device = UdpSocket::bind("127.0.0.1:0").unwrap();
+        device
+            .send_to(&udp.to_bytes(), listener.local_addr().unwrap())
+            .unwrap();
+        let error = listener.receive_datagram().unwrap_err();
+        assert!(matches!(
+            error.get_ref().and_then(|e| e.downcast_ref::<ParseError>()),
+            Some(ParseError::RecordTooLarge { .. })
+        ));
+    }
 
-        let (incoming, outgoing) = pipeline.queue_stats();
-        assert_eq!(incoming + outgoing, 2, "Both packets should be queued");
+    #[test]
+    fn test_validation_policies() {
+        // A unit that hasn't had its first fix yet, in between two perfectly good records
+        let mut packet = create_mock_avl_packet(3);
+        packet.avl_data[1].timestamp = 0;
+        packet.avl_data[1].gps.longitude = 181_0000000;
+        let bytes = PacketEncoder::new().encode(&packet);
+
+        // Codec 8 records of the mock packet are 32 bytes each, starting after the count
+        let record_offset = 10 + 32;
+        let zero_timestamp = ParseError::InvalidTimestamp {
+            offset: record_offset,
+            codec: Some(0x08),
+        };
+        let off_the_map = ParseError::CoordinateOutOfRange {
+            coordinate: Coordinate::Longitude,
+            value: 181_0000000,
+            offset: record_offset + 9,
+            codec: Some(0x08),
+        };
+
+        let parse = |policy| {
+            let mut parser = Parser::new();
+            parser.set_validation_policy(policy);
+            parser.feed(&bytes);
+            parser.next_packet()
+        };
+
+        // Strict, as it has always been, one bad record and the whole packet goes
+        assert_eq!(parse(ValidationPolicy::Strict).unwrap_err(), zero_timestamp);
+
+        // Lenient keeps it, and writes down what is wrong with it
+        let lenient = parse(ValidationPolicy::Lenient).unwrap().unwrap();
+        assert_eq!(lenient.avl_data.len(), 3);
+        assert!(lenient.avl_data[0].warnings.is_empty());
+        assert_eq!(
+            lenient.avl_data[1].warnings,
+            vec![zero_timestamp, off_the_map]
+        );
+        assert!(lenient.avl_data[2].warnings.is_empty());
+
+        // Dropping leaves it out, but the device still gets acknowledged for all three
+        let dropped = parse(ValidationPolicy::DropBadRecords).unwrap().unwrap();
+        assert_eq!(dropped.number_of_data1, 3);
+        assert_eq!(
+            dropped.avl_data,
+            vec![packet.avl_data[0].clone(), packet.avl_data[2].clone()]
+        );
+        let mut state_machine = StateMachine::new(Duration::from_secs(5));
+        state_machine.handle_event(ProtocolEvent::Connect);
+        state_machine.handle_event(ProtocolEvent::Authenticate(
+            "356307042441013".to_string(),
+            String::new(),
+        ));
+        state_machine.handle_event(ProtocolEvent::AuthSuccess);
+        let result = state_machine.handle_event(ProtocolEvent::PacketReceived(dropped));
+        assert!(matches!(
+            result.actions[..],
+            [ProtocolAction::SendAcknowledgement(3)]
+        ));
 
-        // Test batch processing
-        let processed = pipeline.flush().unwrap();
-        assert_eq!(processed.len(), 2, "All packets should be processed");
+        // The views do the same, without copying anything
+        let view = AVLPacketRef::parse_with_policy(
+            &bytes,
+            &DeviceProfile::ANY,
+            ValidationPolicy::DropBadRecords,
+        )
+        .unwrap();
+        let timestamps: Vec<_> = view.records().map(|record| record.timestamp()).collect();
+        assert_eq!(
+            timestamps,
+            vec![packet.avl_data[0].timestamp, packet.avl_data[2].timestamp]
+        );
 
-        // Verify high priority packet is processed first
-        assert_eq!(processed[0].avl_data[0].priority, 8);
-        assert_eq!(processed[1].avl_data[0].priority, 1);
+        // And so does a listener that was told to drop them, for every Session it starts
+        assert_eq!(
+            ValidationPolicy::named("Drop"),
+            Some(ValidationPolicy::DropBadRecords)
+        );
+        let pipeline = Arc::new(Mutex::new(ProcessingPipeline::new(10)));
+        let listener = DeviceListener::bind(
+            "127.0.0.1:0",
+            GateGuard::open(),
+            Arc::clone(&pipeline),
+            SessionConfig {
+                policy: ValidationPolicy::DropBadRecords,
+                ..SessionConfig::new(Duration::from_secs(5))
+            },
+        )
+        .unwrap();
+        let gateway_addr = listener.local_addr().unwrap();
+        let device = thread::spawn(move || {
+            let mut socket = TcpStream::connect(gateway_addr).unwrap();
+            socket
+                .write_all(&mock_handshake("356307042441013"))
+                .unwrap();
+            let mut reply = [0u8; 1];
+            socket.read_exact(&mut reply).unwrap();
+
+            socket.write_all(&bytes).unwrap();
+            let mut ack = [0u8; 4];
+            socket.read_exact(&mut ack).unwrap();
+            u32::from_be_bytes(ack)
+        });
+        let session = listener.accept_device().unwrap();
+
+        assert_eq!(device.join().unwrap(), 3);
+        assert!(session.join().unwrap().is_ok());
+        let delivered = pipeline.lock().unwrap().flush().unwrap();
+        assert_eq!(delivered.len(), 1);
+        assert_eq!(
+            delivered[0].avl_data,
+            vec![packet.avl_data[0].clone(), packet.avl_data[2].clone()]
+        );
     }
 
     #[test]
-    fn test_extended_codec_handling() {
-        let mut parser = Parser::new();
-        let mut serializer = PacketSerializer::new();
+    fn test_io_count_consistency() {
+        let mut encoder = PacketEncoder::new();
 
-        // Create a packet with extended codec (8E)
-        let mut packet = create_mock_avl_packet(1);
+        // Firmware that forgets to count its variable length elements in the total
+        let mut packet = create_mock_avl_packet(2);
         packet.codec_id = 0x8E;
-        packet.avl_data[0].io = IOElement::Codec8Extended(IOElement8Extended {
-            event_io_id: 0x1234,
+        packet.avl_data[1].io = IOElement::Codec8Extended(IOElement8Extended {
+            event_io_id: 0,
             n_total_io: 1,
             n1_of_one_byte: 1,
-            one_byte_ios: vec![(0x5678, 42)],
+            one_byte_ios: vec![(0x0001, 1)],
             n2_of_two_bytes: 0,
             two_byte_ios: vec![],
             n4_of_four_bytes: 0,
@@ -576,49 +2226,416 @@ pub mod integration_tests {
             n8_of_eight_bytes: 0,
             eight_byte_ios: vec![],
             nx_of_var_bytes: 1,
-            var_byte_ios: vec![(0x9ABC, 3, vec![1, 2, 3])],
+            var_byte_ios: vec![(0x0002, 2, vec![0xAB, 0xCD])],
+        });
+        packet.avl_data[0].io = IOElement::Codec8Extended(IOElement8Extended {
+            event_io_id: 0,
+            n_total_io: 0,
+            n1_of_one_byte: 0,
+            one_byte_ios: vec![],
+            n2_of_two_bytes: 0,
+            two_byte_ios: vec![],
+            n4_of_four_bytes: 0,
+            four_byte_ios: vec![],
+            n8_of_eight_bytes: 0,
+            eight_byte_ios: vec![],
+            nx_of_var_bytes: 0,
+            var_byte_ios: vec![],
         });
+        let mut first = packet.clone();
+        first.avl_data.truncate(1);
+        first.number_of_data1 = 1;
+        first.number_of_data2 = 1;
+
+        let bytes = encoder.encode(&packet);
+        let record_offset = 10 + encoder.encode(&first).len() - 15;
+        let mismatch = ParseError::IoCountMismatch {
+            declared: 1,
+            counted: 2,
+            offset: record_offset + 24 + 2,
+            codec: Some(0x8E),
+        };
+
+        // The groups still say how many elements they hold, so whatever the policy,
+        // the record is kept, with the mismatch written down on the record that got it wrong
+        for policy in [
+            ValidationPolicy::default(),
+            ValidationPolicy::Lenient,
+            ValidationPolicy::DropBadRecords,
+        ] {
+            let mut parser = Parser::new();
+            parser.set_validation_policy(policy);
+            parser.feed(&bytes);
+            let parsed = parser.next_packet().unwrap().unwrap();
+            assert_eq!(parsed.avl_data.len(), 2);
+            assert!(parsed.avl_data[0].warnings.is_empty());
+            assert_eq!(parsed.avl_data[1].warnings, vec![mismatch.clone()]);
+
+            let view = AVLPacketRef::parse_with_policy(&bytes, &DeviceProfile::ANY, policy);
+            assert_eq!(view.unwrap().records().count(), 2);
+        }
 
-        // Set up mock device
-        let mock_device = MockDevice::new();
-        let device_addr = mock_device.addr();
-        let serialized_data = serializer.serialize_packet(&packet).unwrap();
+        // A data length that promises more than the records fill
+        let mut padded = encoder.encode(&create_mock_avl_packet(1));
+        let crc_offset = padded.len() - 4;
+        let data_length = (padded.len() - 12 + 2) as u32;
+        padded[4..8].copy_from_slice(&data_length.to_be_bytes());
+        padded.splice(crc_offset..crc_offset, [0xAA, 0xBB]);
 
-        let device_thread = thread::spawn(move || {
-            let (mut socket, _) = mock_device.listener.accept().unwrap();
-            socket.write_all(&serialized_data).unwrap();
+        let mut parser = Parser::new();
+        parser.feed(&padded);
+        assert_eq!(
+            parser.next_frame().unwrap_err(),
+            ParseError::LengthMismatch {
+                declared: data_length as usize,
+                consumed: data_length as usize - 2,
+                offset: crc_offset,
+                codec: Some(0x08),
+            }
+        );
+
+        // The same goes for the answer to a command
+        let command = Codec12CommandPacket::new("getinfo");
+        let mut response = command.to_bytes();
+        response[10] = CODEC12_RESPONSE_TYPE;
+        let crc_offset = response.len() - 4;
+        let data_length = command.data_length + 2;
+        response[4..8].copy_from_slice(&data_length.to_be_bytes());
+        response.splice(crc_offset..crc_offset, [0xAA, 0xBB]);
+
+        let mut parser = Parser::new();
+        parser.feed(&response);
+        let error = parser.next_frame().unwrap_err();
+        assert!(matches!(error, ParseError::LengthMismatch { offset, .. } if offset == crc_offset));
+    }
+
+    #[test]
+    fn test_unified_io_access() {
+        let mut encoder = PacketEncoder::new();
+
+        let mut extended = create_mock_avl_packet(1);
+        extended.codec_id = 0x8E;
+        extended.avl_data[0].io = IOElement::Codec8Extended(IOElement8Extended {
+            event_io_id: 0x0100,
+            n_total_io: 3,
+            n1_of_one_byte: 1,
+            one_byte_ios: vec![(0x00EF, 1)],
+            n2_of_two_bytes: 0,
+            two_byte_ios: vec![],
+            n4_of_four_bytes: 1,
+            four_byte_ios: vec![(0x0101, 0xCAFEBABE)],
+            n8_of_eight_bytes: 0,
+            eight_byte_ios: vec![],
+            nx_of_var_bytes: 1,
+            var_byte_ios: vec![(0x0102, 4, vec![9, 8, 7, 6])],
         });
 
-        let mut connection = Connection::new(device_addr);
-        assert!(connection.connect().is_ok());
+        let mut codec16 = create_mock_avl_packet(1);
+        codec16.codec_id = 0x10;
+        codec16.avl_data[0].io = IOElement::Codec16(IOElement16 {
+            event_io_id: 0x0200,
+            generation_type: 2,
+            n_total_io: 2,
+            n1_of_one_byte: 0,
+            one_byte_ios: vec![],
+            n2_of_two_bytes: 1,
+            two_byte_ios: vec![(0x0201, 500)],
+            n4_of_four_bytes: 0,
+            four_byte_ios: vec![],
+            n8_of_eight_bytes: 1,
+            eight_byte_ios: vec![(0x0202, u64::MAX)],
+        });
 
-        if let Some(stream) =
-            unsafe { &mut *(&mut connection as *mut Connection).cast::<Connection>() }
-                .stream
-                .as_mut()
+        let expected: [(u16, Vec<(u16, IoValue)>); 3] = [
+            (1, vec![(1, IoValue::U8(0))]),
+            (
+                0x0100,
+                vec![
+                    (0x00EF, IoValue::U8(1)),
+                    (0x0101, IoValue::U32(0xCAFEBABE)),
+                    (0x0102, IoValue::Bytes(&[9, 8, 7, 6])),
+                ],
+            ),
+            (
+                0x0200,
+                vec![
+                    (0x0201, IoValue::U16(500)),
+                    (0x0202, IoValue::U64(u64::MAX)),
+                ],
+            ),
+        ];
+
+        for (packet, (event_io_id, ios)) in [create_mock_avl_packet(1), extended, codec16]
+            .iter()
+            .zip(expected)
         {
-            let parsed_result = parser.parse_stream(stream);
+            let io = &packet.avl_data[0].io;
+            assert_eq!(io.event_io_id(), event_io_id);
+            assert_eq!(io.iter().collect::<Vec<_>>(), ios);
+            for (id, value) in &ios {
+                assert_eq!(io.get(*id), Some(*value));
+            }
+            assert_eq!(io.get(0xFFFF), None);
+
+            // A view over the wire bytes answers the same questions the same way
+            let bytes = encoder.encode(packet);
+            let view = AVLPacketRef::parse(&bytes).unwrap();
+            let record = view.records().next().unwrap();
+            assert_eq!(record.io().event_io_id(), event_io_id);
+            assert_eq!(record.io().iter().collect::<Vec<_>>(), ios);
+            assert_eq!(record.io().get(ios[0].0), Some(ios[0].1));
+        }
 
-            assert!(parsed_result.is_ok());
-            if let Ok(Some(parsed_packet)) = parsed_result {
-                assert_eq!(parsed_packet.codec_id, 0x8E);
+        assert_eq!(IoValue::U16(500).as_u64(), Some(500));
+        assert_eq!(IoValue::Bytes(&[1, 2]).as_u64(), None);
+    }
 
-                // Verify extended codec data
-                if let IOElement::Codec8Extended(io) = &parsed_packet.avl_data[0].io {
-                    assert_eq!(io.event_io_id, 0x1234);
-                    assert_eq!(io.n_total_io, 1);
-                    assert_eq!(io.one_byte_ios, vec![(0x5678, 42)]);
-                    assert_eq!(io.var_byte_ios, vec![(0x9ABC, 3, vec![1, 2, 3])]);
-                } else {
-                    panic!("Expected Codec8Extended IO element");
-                }
-            }
+    #[test]
+    fn test_io_dictionary() {
+        let dictionary = IoDictionary::built_in();
+
+        let mut record = create_mock_avl_packet(1).avl_data.remove(0);
+        record.io = IOElement::Codec8Extended(IOElement8Extended {
+            event_io_id: 239,
+            n_total_io: 5,
+            n1_of_one_byte: 1,
+            one_byte_ios: vec![(239, 1)],
+            n2_of_two_bytes: 2,
+            two_byte_ios: vec![(66, 12_840), (17, 0xFFF6)],
+            n4_of_four_bytes: 1,
+            four_byte_ios: vec![(16, 123_456)],
+            n8_of_eight_bytes: 0,
+            eight_byte_ios: vec![],
+            nx_of_var_bytes: 1,
+            var_byte_ios: vec![(0x7FFF, 2, vec![1, 2])],
+        });
+
+        let named: Vec<_> = record
+            .named_ios(&dictionary, &DeviceProfile::FMB)
+            .map(|io| (io.id, io.name(), io.scaled(), io.unit()))
+            .collect();
+        assert_eq!(
+            named,
+            vec![
+                (239, Some("Ignition"), Some(1.0), ""),
+                (66, Some("External Voltage"), Some(12.84), "V"),
+                (17, Some("Axis X"), Some(-10.0), "mG"),
+                (16, Some("Total Odometer"), Some(123_456.0), "m"),
+                (0x7FFF, None, None, ""),
+            ]
+        );
+
+        // The older devices speak in millivolts, everyone else still knows what 16 is
+        let voltage = dictionary.describe(&DeviceProfile::FM6XXX, 66, IoValue::U16(12_840));
+        assert_eq!((voltage.scaled(), voltage.unit()), (Some(12_840.0), "mV"));
+        let odometer = dictionary.lookup(&DeviceProfile::FM6XXX, 16).unwrap();
+        assert_eq!(odometer.value_type, IoType::U32);
+        assert!(!odometer.signed);
+
+        // A value that isn't as wide as the dictionary says isn't scaled by the wrong page
+        let too_wide = dictionary.describe(&DeviceProfile::FMB, 17, IoValue::U32(0xFFF6));
+        assert_eq!(too_wide.definition, None);
+        assert_eq!((too_wide.name(), too_wide.scaled()), (None, Some(65_526.0)));
+
+        // Ids we have never heard of keep their raw value
+        let unknown = dictionary.describe(&DeviceProfile::FMB, 0x7FFE, IoValue::U32(7));
+        assert_eq!((unknown.name(), unknown.scaled()), (None, Some(7.0)));
+
+        // New firmware ids come from a file, on top of what is built in
+        let path = std::env::temp_dir().join(format!("io_dictionary_{}.csv", std::process::id()));
+        std::fs::write(
+            &path,
+            "# family, id, name, type, unit, multiplier, signed\n\
+             FMC, 0x7FFF, Something New, bytes, , 1, unsigned\n",
+        )
+        .unwrap();
+        let error = IoDictionary::load(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("line 2"));
+
+        std::fs::write(
+            &path,
+            "FMC, 32767, Something New, bytes, , 1, unsigned\n\
+             *, 66, External Voltage, u16, mV, 1, unsigned\n",
+        )
+        .unwrap();
+        let loaded = IoDictionary::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            loaded
+                .lookup(&DeviceProfile::FMC, 0x7FFF)
+                .map(|io| io.name.as_str()),
+            Some("Something New")
+        );
+        assert_eq!(loaded.lookup(&DeviceProfile::FMB, 0x7FFF), None);
+        assert_eq!(loaded.lookup(&DeviceProfile::FMB, 66).unwrap().unit, "mV");
+        assert_eq!(
+            loaded.lookup(&DeviceProfile::FMB, 239).unwrap().name,
+            "Ignition"
+        );
+    }
+
+    #[test]
+    fn test_gps_and_time_conversions() {
+        let mut record = create_mock_avl_packet(1).avl_data.remove(0);
+        record.gps = GPSElement {
+            longitude: 252_797_000,
+            latitude: -546_872_000,
+            altitude: 112,
+            angle: 270,
+            satellites: 9,
+            speed: 83,
+        };
+
+        assert_eq!(record.gps.longitude_degrees(), 25.2797);
+        assert_eq!(record.gps.latitude_degrees(), -54.6872);
+        assert_eq!(record.gps.heading_degrees(), 270.0);
+        assert_eq!(record.gps.speed_kmh(), Some(83.0));
+        assert!(record.gps.is_valid());
+        assert_eq!(
+            record.time(),
+            UNIX_EPOCH + Duration::from_millis(1644238347000)
+        );
+
+        // No satellites, or no idea of the speed, means no fix
+        let no_satellites = GPSElement {
+            satellites: 0,
+            ..record.gps
+        };
+        let no_speed = GPSElement {
+            speed: 0xFFFFu16 as i16,
+            ..record.gps
+        };
+        assert!(!no_satellites.is_valid());
+        assert!(!no_speed.is_valid());
+        assert_eq!(no_speed.speed_kmh(), None);
+
+        // The whole of the map is in range, and the view reads the same time
+        let mut packet = create_mock_avl_packet(2);
+        packet.avl_data[0].gps.longitude = -GPSElement::MAX_LONGITUDE;
+        packet.avl_data[0].gps.latitude = GPSElement::MAX_LATITUDE;
+        packet.avl_data[1].gps = record.gps;
+        let bytes = PacketEncoder::new().encode(&packet);
+        let view = AVLPacketRef::parse(&bytes).unwrap();
+        let times: Vec<_> = view.records().map(|record| record.time()).collect();
+        assert_eq!(
+            times,
+            vec![packet.avl_data[0].time(), packet.avl_data[1].time()]
+        );
+        assert_eq!(
+            view.records().nth(1).unwrap().gps().longitude_degrees(),
+            25.2797
+        );
+    }
+
+    #[test]
+    fn test_udp_transport() {
+        const ALLOWED_IMEI: &str = "352093086403655";
+        const UNKNOWN_IMEI: &str = "490154203237518";
+
+        let postcard = |imei: &str, packet_id| UdpAvlPacket {
+            packet_id,
+            avl_packet_id: 0x05,
+            imei: imei.to_string(),
+            packet: create_mock_avl_packet(2),
+        };
+
+        // The header in front of the AVL part, and nothing of the TCP frame around it
+        let sent = postcard(ALLOWED_IMEI, 0xCAFE);
+        let datagram = sent.to_bytes();
+        let frame = sent.packet.to_bytes();
+        assert_eq!(
+            &datagram[..8],
+            &[
+                0x00,
+                (datagram.len() - 2) as u8,
+                0xCA,
+                0xFE,
+                0x01,
+                0x05,
+                0x00,
+                0x0F
+            ]
+        );
+        assert_eq!(&datagram[8..23], ALLOWED_IMEI.as_bytes());
+        assert_eq!(&datagram[23..], &frame[8..frame.len() - 4]);
+
+        let received = UdpAvlPacket::parse(&datagram).unwrap();
+        assert_eq!(received.packet_id, 0xCAFE);
+        assert_eq!(received.avl_packet_id, 0x05);
+        assert_eq!(received.imei, ALLOWED_IMEI);
+        assert_eq!(received.packet.avl_data, sent.packet.avl_data);
+        assert_eq!(received.packet.data_length as usize, datagram.len() - 23);
+        assert_eq!(received.to_bytes(), datagram);
+        assert_eq!(
+            received.ack(2).to_bytes(),
+            [0x00, 0x05, 0xCA, 0xFE, 0x01, 0x05, 0x02]
+        );
+
+        // A datagram is whole or it is wrong, and the offsets count from its first byte
+        let error = UdpAvlPacket::parse(&datagram[..datagram.len() - 1]).unwrap_err();
+        assert!(matches!(
+            error,
+            ParseError::LengthMismatch { offset: 0, .. }
+        ));
+        let mut letters = datagram.clone();
+        letters[10] = b'X';
+        let error = UdpAvlPacket::parse(&letters).unwrap_err();
+        assert!(matches!(error, ParseError::InvalidImei { offset: 8, .. }));
+        let mut miscounted = datagram.clone();
+        let last = miscounted.len() - 1;
+        miscounted[last] = 3;
+        let error = UdpAvlPacket::parse(&miscounted).unwrap_err();
+        assert_eq!(error.offset(), last);
+        assert_eq!(error.codec(), Some(0x08));
+
+        // Over a real socket, only the devices on the guest list get an answer
+        let pipeline = Arc::new(Mutex::new(ProcessingPipeline::new(10)));
+        let listener = UdpListener::bind(
+            "127.0.0.1:0",
+            GateGuard::with_allowed([ALLOWED_IMEI]),
+            Arc::clone(&pipeline),
+            SessionConfig::new(Duration::from_secs(5)),
+        )
+        .unwrap();
+        let device = UdpSocket::bind("127.0.0.1:0").unwrap();
+        device
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+
+        for (imei, packet_id) in [(UNKNOWN_IMEI, 1), (ALLOWED_IMEI, 2)] {
+            device
+                .send_to(
+                    &postcard(imei, packet_id).to_bytes(),
+                    listener.local_addr().unwrap(),
+                )
+                .unwrap();
         }
+        assert_eq!(listener.receive_datagram().unwrap(), None);
+        let ack = listener.receive_datagram().unwrap().unwrap();
+        assert_eq!(
+            ack,
+            UdpAck {
+                packet_id: 2,
+                avl_packet_id: 0x05,
+                accepted: 2
+            }
+        );
+
+        let mut reply = [0u8; 16];
+        let (length, _) = device.recv_from(&mut reply).unwrap();
+        assert_eq!(&reply[..length], &ack.to_bytes());
+        assert!(device.recv_from(&mut reply).is_err());
+
+        let (incoming, outgoing) = pipeline.lock().unwrap().queue_stats();
+        assert_eq!(incoming + outgoing, 1);
     }
+
     #[test]
     fn test_codec_edge_cases() {
         let mut parser = Parser::new();
-        let mut serializer = PacketSerializer::new();
+        let mut encoder = PacketEncoder::new();
 
         let mock_device = MockDevice::new();
         let device_addr = mock_device.addr();
@@ -636,7 +2653,7 @@ pub mod integration_tests {
             eight_byte_ios: vec![],
         });
 
-        let serialized = serializer.serialize_packet(&empty_io_packet).unwrap();
+        let serialized = encoder.encode(&empty_io_packet);
 
         let device_thread = thread::spawn(move || {
             let (mut socket, _) = mock_device.listener.accept().unwrap();
@@ -673,7 +2690,7 @@ pub mod integration_tests {
             eight_byte_ios: vec![],
         });
 
-        let serialized = serializer.serialize_packet(&max_io_packet).unwrap();
+        let serialized = encoder.encode(&max_io_packet);
 
         let device_thread = thread::spawn(move || {
             let (mut socket, _) = mock_device.listener.accept().unwrap();
@@ -699,7 +2716,7 @@ pub mod integration_tests {
         let mut invalid_codec_packet = create_mock_avl_packet(1);
         invalid_codec_packet.codec_id = 0xFF; // Invalid codec ID
 
-        let serialized = serializer.serialize_packet(&invalid_codec_packet).unwrap();
+        let serialized = encoder.encode(&invalid_codec_packet);
 
         let device_thread = thread::spawn(move || {
             let (mut socket, _) = mock_device.listener.accept().unwrap();
@@ -725,9 +2742,7 @@ pub mod integration_tests {
         let mut boundary_timestamp_packet = create_mock_avl_packet(1);
         boundary_timestamp_packet.avl_data[0].timestamp = 0;
 
-        let serialized = serializer
-            .serialize_packet(&boundary_timestamp_packet)
-            .unwrap();
+        let serialized = encoder.encode(&boundary_timestamp_packet);
 
         let device_thread = thread::spawn(move || {
             let (mut socket, _) = mock_device.listener.accept().unwrap();
@@ -750,13 +2765,13 @@ pub mod integration_tests {
     #[test]
     fn test_packet_size_limits() {
         let mut parser = Parser::new();
-        let mut serializer = PacketSerializer::new();
+        let mut encoder = PacketEncoder::new();
 
         // Test minimum size packet
         let mock_device = MockDevice::new();
         let device_addr = mock_device.addr();
         let min_packet = create_mock_avl_packet(1);
-        let min_serialized = serializer.serialize_packet(&min_packet).unwrap();
+        let min_serialized = encoder.encode(&min_packet);
 
         assert!(
             min_serialized.len() >= SMALLEST_AVL_SIZE,
@@ -787,7 +2802,7 @@ pub mod integration_tests {
         let mock_device = MockDevice::new();
         let device_addr = mock_device.addr();
         let max_packet = create_mock_avl_packet(255); // Maximum records for FM6XXX
-        let max_serialized = serializer.serialize_packet(&max_packet).unwrap();
+        let max_serialized = encoder.encode(&max_packet);
 
         assert!(
             max_serialized.len() <= MAX_AVL_PACKET_SIZE_FM6XXX,
@@ -817,7 +2832,7 @@ pub mod integration_tests {
         let mock_device = MockDevice::new();
         let device_addr = mock_device.addr();
         let oversize_packet = create_mock_avl_packet(0xFF); // Trying to exceed maximum
-        let oversize_serialized = serializer.serialize_packet(&oversize_packet).unwrap();
+        let oversize_serialized = encoder.encode(&oversize_packet);
 
         let device_thread = thread::spawn(move || {
             let (mut socket, _) = mock_device.listener.accept().unwrap();
@@ -875,6 +2890,7 @@ pub mod integration_tests {
                     priority: (i % 8) as u8,
                     gps,
                     io,
+                    warnings: vec![],
                 });
             }
 
@@ -922,7 +2938,7 @@ pub mod integration_tests {
             ) -> thread::JoinHandle<()> {
                 thread::spawn(move || {
                     let (mut socket, _) = self.listener.accept().unwrap();
-                    let mut serializer = PacketSerializer::new();
+                    let mut encoder = PacketEncoder::new();
                     let start = Instant::now();
                     let mut sequence = 0u64;
 
@@ -931,17 +2947,11 @@ pub mod integration_tests {
                         let packet_size = (sequence % 10 + 1) as u8; // Vary packet size
                         let packet = create_variable_packet(packet_size, sequence);
 
-                        match serializer.serialize_packet(&packet) {
-                            Ok(data) => {
-                                if socket.write_all(&data).is_ok() {
-                                    self.packet_count.fetch_add(1, Ordering::Relaxed);
-                                } else {
-                                    self.error_count.fetch_add(1, Ordering::Relaxed);
-                                }
-                            }
-                            Err(_) => {
-                                self.error_count.fetch_add(1, Ordering::Relaxed);
-                            }
+                        let data = encoder.encode(&packet);
+                        if socket.write_all(&data).is_ok() {
+                            self.packet_count.fetch_add(1, Ordering::Relaxed);
+                        } else {
+                            self.error_count.fetch_add(1, Ordering::Relaxed);
                         }
 
                         sequence += 1;
@@ -1079,5 +3089,330 @@ pub mod integration_tests {
             // Assert minimum throughput requirements
             assert!(throughput > 1000.0, "Throughput below minimum requirement");
         }
+
+        // The receive path as it was before the ReceiveBuffer, kept only to measure against.
+        // Every read lands in a temporary and is appended to a Vec that grows as it likes,
+        // and every frame is drained off the front, moving whatever is behind it.
+        struct DrainingReceiver {
+            buffer: Vec<u8>,
+            temp_buffer: [u8; 4096],
+        }
+
+        impl DrainingReceiver {
+            fn parse_stream<R: Read>(&mut self, stream: &mut R) -> Option<AVLPacket> {
+                loop {
+                    if let Ok(view) = AVLPacketRef::parse(&self.buffer) {
+                        let length = view.as_bytes().len();
+                        let packet = view.to_packet();
+                        self.buffer.drain(..length);
+                        return Some(packet);
+                    }
+                    let bytes_read = stream.read(&mut self.temp_buffer).unwrap();
+                    if bytes_read == 0 {
+                        return None;
+                    }
+                    self.buffer
+                        .extend_from_slice(&self.temp_buffer[..bytes_read]);
+                }
+            }
+        }
+
+        // Not so much a test as a measuring tape, run it with
+        // cargo test --release bench_receive_path -- --ignored --nocapture
+        // The packets of test_pipeline_throughput come off a stream and go through the pipeline,
+        // once through the receive path as it was and once as it is now. Both decode the
+        // packets the same way, through a view copied out into a packet, so the difference
+        // between them is down to the receive buffer alone.
+        #[test]
+        #[ignore]
+        fn bench_receive_path() {
+            const BATCH_SIZE: usize = 1000;
+            const NUM_BATCHES: usize = 200;
+            let mut encoder = PacketEncoder::new();
+            let mut stream = Vec::new();
+            for batch in 0..NUM_BATCHES {
+                for i in 0..BATCH_SIZE {
+                    // Seeded past 360, so the made up coordinates don't go below zero
+                    let seed = 360 + (batch * BATCH_SIZE + i) as u64;
+                    stream
+                        .extend(encoder.encode(&create_variable_packet((i % 10 + 1) as u8, seed)));
+                }
+            }
+
+            // Everything that comes off the stream goes through the pipeline in batches
+            let run = |name: &str, next_packet: &mut dyn FnMut() -> Option<AVLPacket>| {
+                let mut pipeline = ProcessingPipeline::new(BATCH_SIZE);
+                let start = Instant::now();
+                let mut packets = 0;
+                while let Some(packet) = next_packet() {
+                    pipeline.process_incoming(packet, None).unwrap();
+                    packets += 1;
+                    if packets % BATCH_SIZE == 0 {
+                        assert_eq!(pipeline.flush().unwrap().len(), BATCH_SIZE);
+                    }
+                }
+                let duration = start.elapsed();
+                assert_eq!(packets, BATCH_SIZE * NUM_BATCHES);
+
+                let throughput = packets as f64 / duration.as_secs_f64();
+                println!(
+                    "{:<28} {:>10.2?} {:>14.0} packets/second",
+                    name, duration, throughput
+                );
+                throughput
+            };
+
+            let mut source = Cursor::new(&stream);
+            let mut receiver = DrainingReceiver {
+                buffer: Vec::new(),
+                temp_buffer: [0u8; 4096],
+            };
+            let before = run("Vec::drain", &mut || receiver.parse_stream(&mut source));
+
+            let mut source = Cursor::new(&stream);
+            let mut parser = Parser::new();
+            let after = run("ReceiveBuffer", &mut || loop {
+                if let Some(view) = parser.next_packet_ref().unwrap() {
+                    return Some(view.to_packet());
+                }
+                if parser.read_from(&mut source).unwrap() == 0 {
+                    return None;
+                }
+            });
+
+            println!(
+                "ReceiveBuffer runs at {:.2}x the packets of Vec::drain",
+                after / before
+            );
+        }
+    }
+
+    // The fuzzing department. Their job is to throw every kind of garbage at the Parser,
+    // because anything facing the internet will get garbage thrown at it sooner or later.
+    // The Parser is allowed to refuse it, but it is never allowed to fall over (panic).
+    //
+    // - fuzz/corpus/parser holds the inputs we have learned from, every one of them is
+    //   replayed on every test run. Files starting with "valid_" must also parse.
+    // - The mutation run starts from those inputs and scrambles them. Set DQ_FUZZ_ITERATIONS
+    //   to let it run longer than the default. When it finds something that makes the Parser
+    //   fall over, it prints the input in hex, which then belongs in the corpus.
+    #[cfg(test)]
+    mod fuzz_tests {
+        use super::*;
+        use std::fs;
+        use std::panic;
+        use std::path::PathBuf;
+
+        const DEFAULT_ITERATIONS: usize = 20_000;
+
+        // target: The part being fuzzed, "parser" for the TCP Parser, "udp" for datagrams
+        fn corpus_dir(target: &str) -> PathBuf {
+            PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("fuzz/corpus")
+                .join(target)
+        }
+
+        fn load_corpus(target: &str) -> Vec<(String, Vec<u8>)> {
+            let mut corpus: Vec<_> = fs::read_dir(corpus_dir(target))
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| path.extension().is_some_and(|ext| ext == "bin"))
+                .map(|path| {
+                    let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                    (name, fs::read(&path).unwrap())
+                })
+                .collect();
+            corpus.sort();
+            corpus
+        }
+
+        // Feed the bytes to a fresh Parser, chunk bytes at a time, taking out every frame
+        // as soon as it can be had. Returns: How many frames came out whole
+        fn drive(bytes: &[u8], chunk: usize) -> usize {
+            let mut parser = Parser::new();
+            let mut frames = 0;
+            for mut piece in bytes.chunks(chunk.max(1)) {
+                while !piece.is_empty() {
+                    let taken = parser.feed(piece);
+                    let before = parser.buffered_len();
+                    frames += parser.frames().filter(|frame| frame.is_ok()).count();
+
+                    // A full desk has to make room for more, one way or another
+                    assert!(
+                        taken > 0 || parser.buffered_len() < before,
+                        "Parser is stuck with a full buffer"
+                    );
+                    piece = &piece[taken..];
+                }
+            }
+            parser.finish();
+            frames
+        }
+
+        // Run the Parser over the input, and turn a panic into a failure that tells us
+        // exactly which bytes did it
+        fn assert_no_panic(bytes: &[u8], chunk: usize) -> usize {
+            match panic::catch_unwind(|| drive(bytes, chunk)) {
+                Ok(frames) => frames,
+                Err(_) => panic!(
+                    "Parser panicked on {} bytes fed {} at a time: {}",
+                    bytes.len(),
+                    chunk,
+                    bytes
+                        .iter()
+                        .map(|b| format!("{:02x}", b))
+                        .collect::<String>()
+                ),
+            }
+        }
+
+        // Read the datagram under every policy, and turn a panic into a failure that tells us
+        // exactly which bytes did it. Returns: The packet as read under the Strict policy
+        fn assert_no_udp_panic(datagram: &[u8]) -> Result<UdpAvlPacket, ParseError> {
+            let read = || {
+                for policy in [ValidationPolicy::Lenient, ValidationPolicy::DropBadRecords] {
+                    let _ = UdpAvlPacket::parse_with_policy(datagram, &DeviceProfile::ANY, policy);
+                }
+                UdpAvlPacket::parse(datagram)
+            };
+            match panic::catch_unwind(read) {
+                Ok(result) => result,
+                Err(_) => panic!(
+                    "UDP parsing panicked on {} bytes: {}",
+                    datagram.len(),
+                    datagram
+                        .iter()
+                        .map(|b| format!("{:02x}", b))
+                        .collect::<String>()
+                ),
+            }
+        }
+
+        fn fuzz_iterations() -> usize {
+            std::env::var("DQ_FUZZ_ITERATIONS")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(DEFAULT_ITERATIONS)
+        }
+
+        // A tiny xorshift generator, so the run is the same every time without any crates
+        struct Scrambler(u64);
+
+        impl Scrambler {
+            fn next(&mut self) -> u64 {
+                self.0 ^= self.0 << 13;
+                self.0 ^= self.0 >> 7;
+                self.0 ^= self.0 << 17;
+                self.0
+            }
+
+            fn below(&mut self, bound: usize) -> usize {
+                (self.next() % bound.max(1) as u64) as usize
+            }
+
+            // Mess up the input in one of the ways a bad line, or a bad person, would
+            fn mutate(&mut self, input: &mut Vec<u8>, corpus: &[(String, Vec<u8>)]) {
+                let at = self.below(input.len());
+                match self.below(8) {
+                    0 if !input.is_empty() => input[at] ^= 1 << self.below(8),
+                    1 if !input.is_empty() => input[at] = self.next() as u8,
+                    // The interesting values, the ones that lengths and counts are made of
+                    2 if !input.is_empty() => {
+                        input[at] = [0x00, 0x01, 0x7F, 0x80, 0xFF][self.below(5)]
+                    }
+                    3 => input.truncate(at),
+                    4 => input.insert(at.min(input.len()), self.next() as u8),
+                    5 if at + 4 <= input.len() => {
+                        let value = [0u32, 1, 0xFFFF, 0x7FFF_FFFF, u32::MAX][self.below(5)];
+                        input[at..at + 4].copy_from_slice(&value.to_be_bytes());
+                    }
+                    // The frame lies about its own length, just a little, so the records
+                    // run off the end of it
+                    6 if input.len() >= 12 => {
+                        let length = (input.len() - 12 + self.below(9)).saturating_sub(4);
+                        input[4..8].copy_from_slice(&(length as u32).to_be_bytes());
+                    }
+                    _ => {
+                        let (_, other) = &corpus[self.below(corpus.len())];
+                        let from = self.below(other.len());
+                        input.extend_from_slice(&other[from..]);
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn test_parser_regression_corpus() {
+            let corpus = load_corpus("parser");
+            assert!(!corpus.is_empty(), "The regression corpus is missing");
+
+            for (name, bytes) in &corpus {
+                let whole = assert_no_panic(bytes, bytes.len());
+                let trickled = assert_no_panic(bytes, 1);
+
+                // However the bytes arrive, the answer should be the same
+                assert_eq!(whole, trickled, "{} parsed differently when trickled", name);
+                if name.starts_with("valid_") {
+                    assert!(whole > 0, "{} should have parsed", name);
+                }
+
+                // Whatever the devices wrote, we can write again, byte for byte
+                let mut parser = Parser::new();
+                parser.feed(bytes);
+                if let Ok(Some(TeltonikaFrame::Avl(packet))) = parser.next_frame() {
+                    let length = packet.data_length as usize + 12;
+                    assert_eq!(packet.to_bytes(), bytes[..length], "{} re-encoded", name);
+                }
+            }
+        }
+
+        #[test]
+        fn test_parser_fuzz() {
+            let corpus = load_corpus("parser");
+            let mut scrambler = Scrambler(0x9E37_79B9_7F4A_7C15);
+            for _ in 0..fuzz_iterations() {
+                let (_, seed) = &corpus[scrambler.below(corpus.len())];
+                let mut input = seed.clone();
+                for _ in 0..=scrambler.below(8) {
+                    scrambler.mutate(&mut input, &corpus);
+                }
+
+                let chunk = [input.len(), 1, 7, 64][scrambler.below(4)];
+                assert_no_panic(&input, chunk);
+            }
+        }
+
+        #[test]
+        fn test_udp_regression_corpus() {
+            let corpus = load_corpus("udp");
+            assert!(!corpus.is_empty(), "The UDP regression corpus is missing");
+
+            for (name, bytes) in &corpus {
+                let result = assert_no_udp_panic(bytes);
+                if !name.starts_with("valid_") {
+                    assert!(result.is_err(), "{} should have been rejected", name);
+                    continue;
+                }
+
+                // Whatever the devices wrote, we can write again, byte for byte
+                let packet =
+                    result.unwrap_or_else(|e| panic!("{} should have parsed: {}", name, e));
+                assert_eq!(&packet.to_bytes(), bytes, "{} re-encoded", name);
+            }
+        }
+
+        #[test]
+        fn test_udp_fuzz() {
+            let corpus = load_corpus("udp");
+            let mut scrambler = Scrambler(0x2545_F491_4F6C_DD1D);
+            for _ in 0..fuzz_iterations() {
+                let (_, seed) = &corpus[scrambler.below(corpus.len())];
+                let mut input = seed.clone();
+                for _ in 0..=scrambler.below(8) {
+                    scrambler.mutate(&mut input, &corpus);
+                }
+                let _ = assert_no_udp_panic(&input);
+            }
+        }
     }
 }
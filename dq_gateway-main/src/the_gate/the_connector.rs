@@ -10,17 +10,26 @@
 use super::*;
 use std::thread;
 
+// How many times we try to get our last words onto the line before hanging up anyway,
+// and how long we give the device to make room in between
+const GOODBYE_ATTEMPTS: u32 = 5;
+const GOODBYE_PAUSE: Duration = Duration::from_millis(20);
+
 //   The Connection struct is very much like a phone line between
 //   our system and a device:
 // - stream: The actual phone line between us (TCP connection)
 // - addr: The phone number we're calling (IP address and the port)
 // - reconnect_attempts: How many times we've tried to call back
 // - session_id: A unique ID for this conversation (like a call reference number in a log)
+// - pending_output: What we have said, but the line wasn't ready to carry yet
+// - nonblocking: Whether we wait on the line, or the event loop tells us when it is ready
 pub struct Connection {
     pub stream: Option<TcpStream>,
     addr: SocketAddr,
     reconnect_attempts: u32,
     session_id: Option<u32>,
+    pending_output: Vec<u8>,
+    nonblocking: bool,
 }
 
 impl Connection {
@@ -32,8 +41,30 @@ impl Connection {
             addr,
             reconnect_attempts: 0,
             session_id: None,
+            pending_output: Vec::new(),
+            nonblocking: false,
         }
     }
+    // Pick up a call the device made to us. The line is already open,
+    // we just need to know who is on the other end and set up the call quality.
+    pub fn from_stream(stream: TcpStream) -> io::Result<Self> {
+        let addr = stream.peer_addr()?;
+        Self::configure_stream(&stream)?;
+        Ok(Self {
+            stream: Some(stream),
+            addr,
+            reconnect_attempts: 0,
+            session_id: None,
+            pending_output: Vec::new(),
+            nonblocking: false,
+        })
+    }
+
+    // The phone number of whoever is on the other end
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
     // Get access to our phone line if it's connected
     pub fn get_stream_mut(&mut self) -> Option<&mut TcpStream> {
         self.stream.as_mut()
@@ -102,15 +133,126 @@ impl Connection {
             .unwrap_or(false)
     }
 
+    // Ask the device to do something, like "getinfo" or "setdigout 1".
+    // The answer comes back later as a Codec 12 response through the Parser.
+    pub fn send_command(&mut self, command: &str) -> io::Result<()> {
+        self.send(&Codec12CommandPacket::new(command).to_bytes())
+    }
+
+    // Ask the device to do something, but only if it is the device with this IMEI.
+    // The answer comes back later as a Codec 14 response through the Parser.
+    pub fn send_imei_command(&mut self, imei: &str, command: &str) -> io::Result<()> {
+        let packet = Codec14CommandPacket::new(imei, command).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "IMEI must be 1 to 15 digits")
+        })?;
+        self.send(&packet.to_bytes())
+    }
+
+    // Answer the device's handshake, a single 0x01 lets it in, a 0x00 turns it away
+    pub fn send_auth_response(&mut self, accepted: bool) -> io::Result<()> {
+        self.send(&[accepted as u8])
+    }
+
+    // Tell the device how many records we accepted, as a 4 byte big-endian count.
+    // If the count doesn't match what it sent, the device will send the data again.
+    pub fn send_acknowledgement(&mut self, record_count: u32) -> io::Result<()> {
+        self.send(&record_count.to_be_bytes())
+    }
+
+    // Let the event loop do the waiting for us. Reads and writes return right away,
+    // and whatever the line can't take yet is kept until it is ready again.
+    pub fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        if let Some(stream) = self.stream.as_ref() {
+            stream.set_nonblocking(nonblocking)?;
+        }
+        self.nonblocking = nonblocking;
+        Ok(())
+    }
+
+    // Is there anything we said that hasn't made it onto the line yet?
+    pub fn has_pending_output(&self) -> bool {
+        !self.pending_output.is_empty()
+    }
+
+    // Push as much of what we have to say onto the line as it will take.
+    // A blocking line takes all of it or fails, a non-blocking one keeps the rest for later.
+    pub fn flush_pending(&mut self) -> io::Result<()> {
+        let stream = self
+            .stream
+            .as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "No active connection"))?;
+
+        while !self.pending_output.is_empty() {
+            match stream.write(&self.pending_output) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "The device stopped accepting data",
+                    ))
+                }
+                Ok(written) => {
+                    self.pending_output.drain(..written);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock && self.nonblocking => break,
+                Err(e) => {
+                    // The line is broken, there is no point in holding on to what we wanted to say
+                    self.pending_output.clear();
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Everything we say goes through here, so nothing overtakes what is still waiting
+    fn send(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if self.stream.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "No active connection",
+            ));
+        }
+        self.pending_output.extend_from_slice(bytes);
+        self.flush_pending()
+    }
+
     // End the connection properly.
     // Like saying goodbye before hanging up the phone
     pub fn shutdown(&mut self) -> io::Result<()> {
+        // Whatever we still owe the device, a rejection or an ack, goes out before we hang up.
+        // A device that won't take it in a few tries doesn't get to keep us on the line though.
+        for _ in 0..GOODBYE_ATTEMPTS {
+            if !self.has_pending_output() || self.flush_pending().is_err() {
+                break;
+            }
+            if self.has_pending_output() {
+                thread::sleep(GOODBYE_PAUSE);
+            }
+        }
+        self.pending_output.clear();
         if let Some(stream) = self.stream.take() {
-            stream.shutdown(std::net::Shutdown::Both)?;
+            // If they already hung up on us, there is nobody left to say goodbye to
+            match stream.shutdown(std::net::Shutdown::Both) {
+                Err(e) if e.kind() != io::ErrorKind::NotConnected => return Err(e),
+                _ => {}
+            }
         }
         Ok(())
     }
 
+    // How long a read waits for the device before giving up, None to wait forever.
+    // Only matters on a blocking line, a non-blocking one never waits.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match &self.stream {
+            Some(stream) => stream.set_read_timeout(timeout),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "No active connection",
+            )),
+        }
+    }
+
     // Configure the connection settings
     // Things like adjusting the quality of the call and the timeout settings
     fn configure_stream(stream: &TcpStream) -> io::Result<()> {
@@ -0,0 +1,366 @@
+//#############################################################################################
+//#                                 IMPORTANT INFORMATION                                     #
+//#############################################################################################
+//#   This is the part of the codebase that doesn't wait. Sockets are non-blocking, and the   #
+//#   kernel (epoll) tells us which devices have something to say, so a handful of threads    #
+//#   can keep thousands of Sessions going. Linux only, and without any extra crates, we      #
+//#   talk to epoll directly through the C library the standard library already links.        #
+//#############################################################################################
+
+use super::*;
+use std::collections::HashMap;
+use std::net::{TcpListener, ToSocketAddrs};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::raw::c_int;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+//-------------------------------------------------------------------
+//|                     EPOLL, STRAIGHT FROM LIBC                   |
+//-------------------------------------------------------------------
+const EPOLL_CLOEXEC: c_int = 0x80000;
+const EPOLL_CTL_ADD: c_int = 1;
+const EPOLL_CTL_DEL: c_int = 2;
+const EPOLL_CTL_MOD: c_int = 3;
+const EPOLLIN: u32 = 0x001;
+const EPOLLOUT: u32 = 0x004;
+const EPOLLERR: u32 = 0x008;
+const EPOLLHUP: u32 = 0x010;
+const EPOLLRDHUP: u32 = 0x2000;
+const EPOLLEXCLUSIVE: u32 = 1 << 28;
+
+// The kernel packs this struct on x86_64, and nowhere else
+#[repr(C)]
+#[cfg_attr(target_arch = "x86_64", repr(packed))]
+#[derive(Clone, Copy)]
+struct EpollEvent {
+    events: u32,
+    data: u64,
+}
+
+extern "C" {
+    fn epoll_create1(flags: c_int) -> c_int;
+    fn epoll_ctl(epfd: c_int, op: c_int, fd: c_int, event: *mut EpollEvent) -> c_int;
+    fn epoll_wait(epfd: c_int, events: *mut EpollEvent, maxevents: c_int, timeout: c_int) -> c_int;
+}
+
+//   The Poller is the receptionist who watches all the phone lines at once,
+//   and only taps us on the shoulder when one of them actually rings.
+struct Poller {
+    epoll: OwnedFd,
+}
+
+impl Poller {
+    fn new() -> io::Result<Self> {
+        let fd = unsafe { epoll_create1(EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // We just got this fd from the kernel, and nobody else owns it
+        Ok(Self {
+            epoll: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    // Start watching a line, the token is how we recognise it when it rings
+    fn add(&self, fd: RawFd, token: u64, interest: u32) -> io::Result<()> {
+        self.control(EPOLL_CTL_ADD, fd, token, interest)
+    }
+
+    // Change what we are watching a line for, like whether we have something to say on it
+    fn modify(&self, fd: RawFd, token: u64, interest: u32) -> io::Result<()> {
+        self.control(EPOLL_CTL_MOD, fd, token, interest)
+    }
+
+    // Stop watching a line altogether
+    fn delete(&self, fd: RawFd) -> io::Result<()> {
+        self.control(EPOLL_CTL_DEL, fd, 0, 0)
+    }
+
+    fn control(&self, op: c_int, fd: RawFd, token: u64, interest: u32) -> io::Result<()> {
+        let mut event = EpollEvent {
+            events: interest,
+            data: token,
+        };
+        if unsafe { epoll_ctl(self.epoll.as_raw_fd(), op, fd, &mut event) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // Wait for any of the lines to ring, but never longer than the timeout.
+    // Returns: How many of the events are filled in
+    fn wait(&self, events: &mut [EpollEvent], timeout: Duration) -> io::Result<usize> {
+        let ready = unsafe {
+            epoll_wait(
+                self.epoll.as_raw_fd(),
+                events.as_mut_ptr(),
+                events.len() as c_int,
+                timeout.as_millis().min(c_int::MAX as u128) as c_int,
+            )
+        };
+        if ready < 0 {
+            let error = io::Error::last_os_error();
+            // A signal woke us up, nothing rang
+            if error.kind() == io::ErrorKind::Interrupted {
+                return Ok(0);
+            }
+            return Err(error);
+        }
+        Ok(ready as usize)
+    }
+}
+
+// The token we recognise the listening socket by, the Sessions count up from 1
+const LISTENER_TOKEN: u64 = 0;
+// How many rings we pick up in one go
+const EVENTS_PER_WAIT: usize = 1024;
+// How long we wait for a ring before we look around for quiet devices and a stop signal
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+//   The EventLoop is the DeviceListener for when there are far too many devices
+//   to give each of them a thread of their own.
+// - listener: The phone number the devices call, shared by every worker
+// - guard: The bouncer deciding which devices are let in
+// - pipeline: The post office every decoded packet is handed to
+// - config: How every conversation is set up
+// - workers: How many threads share the work
+// - running: Cleared when it's time to close up shop
+pub struct EventLoop {
+    listener: TcpListener,
+    guard: Arc<GateGuard>,
+    pipeline: Arc<Mutex<ProcessingPipeline>>,
+    config: SessionConfig,
+    workers: usize,
+    running: Arc<AtomicBool>,
+}
+
+// Lets whoever started the EventLoop tell it to stop, and wait until it has
+pub struct EventLoopHandle {
+    running: Arc<AtomicBool>,
+    workers: Vec<thread::JoinHandle<io::Result<()>>>,
+}
+
+impl EventLoopHandle {
+    // Close up shop. Every worker finishes its current round and hangs up on its devices.
+    pub fn stop(self) -> io::Result<()> {
+        self.running.store(false, Ordering::Relaxed);
+        for worker in self.workers {
+            worker
+                .join()
+                .map_err(|_| io::Error::other("Event loop worker panicked"))??;
+        }
+        Ok(())
+    }
+}
+
+impl EventLoop {
+    // Get ourselves a phone number, and decide how many of us will be answering
+    pub fn bind<A: ToSocketAddrs>(
+        addr: A,
+        guard: GateGuard,
+        pipeline: Arc<Mutex<ProcessingPipeline>>,
+        config: SessionConfig,
+        workers: usize,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(Self {
+            listener,
+            guard: Arc::new(guard),
+            pipeline,
+            config,
+            workers: workers.max(1),
+            running: Arc::new(AtomicBool::new(true)),
+        })
+    }
+
+    // The number we ended up with, handy when we asked for port 0
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // Start every worker in the background
+    pub fn spawn(self) -> io::Result<EventLoopHandle> {
+        let mut workers = Vec::with_capacity(self.workers);
+        for _ in 0..self.workers {
+            let worker = Worker {
+                listener: self.listener.try_clone()?,
+                guard: Arc::clone(&self.guard),
+                pipeline: Arc::clone(&self.pipeline),
+                config: self.config,
+                running: Arc::clone(&self.running),
+            };
+            workers.push(thread::spawn(move || worker.run()));
+        }
+
+        Ok(EventLoopHandle {
+            running: self.running,
+            workers,
+        })
+    }
+
+    // Run the gateway until it's told to stop, which for now means forever
+    pub fn run(self) -> io::Result<()> {
+        let handle = self.spawn()?;
+        for worker in handle.workers {
+            worker
+                .join()
+                .map_err(|_| io::Error::other("Event loop worker panicked"))??;
+        }
+        Ok(())
+    }
+}
+
+//   A Worker is one of the threads answering the phones. It has its own Poller and its
+//   own set of Sessions, and only the listener, guard and post office are shared.
+//   All the workers watch the same listener, and the kernel wakes only one of them
+//   for each new call (EPOLLEXCLUSIVE), so they don't trip over each other.
+struct Worker {
+    listener: TcpListener,
+    guard: Arc<GateGuard>,
+    pipeline: Arc<Mutex<ProcessingPipeline>>,
+    config: SessionConfig,
+    running: Arc<AtomicBool>,
+}
+
+impl Worker {
+    fn run(self) -> io::Result<()> {
+        let poller = Poller::new()?;
+        self.listen(&poller)?;
+
+        let mut sessions: HashMap<u64, Session> = HashMap::new();
+        let mut next_token = LISTENER_TOKEN + 1;
+        let mut events = vec![EpollEvent { events: 0, data: 0 }; EVENTS_PER_WAIT];
+        let mut last_sweep = Instant::now();
+        let mut deaf_since: Option<Instant> = None;
+
+        while self.running.load(Ordering::Relaxed) {
+            let ready = poller.wait(&mut events, POLL_INTERVAL)?;
+
+            for event in &events[..ready] {
+                let (token, flags) = (event.data, event.events);
+
+                if token == LISTENER_TOKEN {
+                    // Most likely we are out of file descriptors. The call is still waiting,
+                    // so the phone would ring again right away, and keep on ringing.
+                    // We stop listening for a round instead, and look after the devices we have.
+                    if let Err(e) = self.accept_devices(&poller, &mut sessions, &mut next_token) {
+                        eprintln!("Failed to accept device: {}", e);
+                        poller.delete(self.listener.as_raw_fd())?;
+                        deaf_since = Some(Instant::now());
+                    }
+                    continue;
+                }
+
+                let session = match sessions.get_mut(&token) {
+                    Some(session) => session,
+                    None => continue,
+                };
+
+                let mut open = true;
+                if flags & (EPOLLIN | EPOLLRDHUP | EPOLLHUP | EPOLLERR) != 0 {
+                    open = session.on_readable().unwrap_or(false);
+                }
+                if open && flags & EPOLLOUT != 0 {
+                    open = session.on_writable().is_ok();
+                }
+                if open {
+                    open = Self::watch(&poller, session, token, EPOLL_CTL_MOD).is_ok();
+                }
+
+                // Dropping the Session closes the socket, which also takes it off the Poller
+                if !open {
+                    sessions.remove(&token);
+                }
+            }
+
+            // Every now and then, we hang up on the devices that have gone quiet
+            if last_sweep.elapsed() >= POLL_INTERVAL {
+                sessions.retain(|_, session| session.check_timeout().unwrap_or(false));
+                last_sweep = Instant::now();
+            }
+
+            // After a round of rest, we try picking up the phone again
+            if deaf_since.is_some_and(|since| since.elapsed() >= POLL_INTERVAL) {
+                self.listen(&poller)?;
+                deaf_since = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Have the Poller tell us about new calls. Only one worker is woken for each of them.
+    fn listen(&self, poller: &Poller) -> io::Result<()> {
+        poller.add(
+            self.listener.as_raw_fd(),
+            LISTENER_TOKEN,
+            EPOLLIN | EPOLLEXCLUSIVE,
+        )
+    }
+
+    // Pick up every call waiting on the line, until there are none left
+    fn accept_devices(
+        &self,
+        poller: &Poller,
+        sessions: &mut HashMap<u64, Session>,
+        next_token: &mut u64,
+    ) -> io::Result<()> {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // A device that hung up before we answered shouldn't take the worker down
+                Err(e) if e.kind() == io::ErrorKind::ConnectionAborted => continue,
+                // Anything else won't go away by trying again right now, see Worker::run
+                Err(e) => return Err(e),
+            };
+
+            let mut connection = match Connection::from_stream(stream) {
+                Ok(connection) => connection,
+                Err(_) => continue,
+            };
+            if connection.set_nonblocking(true).is_err() {
+                continue;
+            }
+
+            let token = *next_token;
+            *next_token += 1;
+
+            let session = Session::with_config(
+                connection,
+                Arc::clone(&self.guard),
+                Arc::clone(&self.pipeline),
+                &self.config,
+            );
+            if Self::watch(poller, &session, token, EPOLL_CTL_ADD).is_ok() {
+                sessions.insert(token, session);
+            }
+        }
+    }
+
+    // Watch a Session's line for anything the device says, and for room to talk
+    // when we still have something waiting to be said
+    fn watch(poller: &Poller, session: &Session, token: u64, op: c_int) -> io::Result<()> {
+        let fd = session
+            .connection()
+            .stream
+            .as_ref()
+            .map(|stream| stream.as_raw_fd())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "No active connection"))?;
+
+        let mut interest = EPOLLIN | EPOLLRDHUP;
+        if session.connection().has_pending_output() {
+            interest |= EPOLLOUT;
+        }
+
+        match op {
+            EPOLL_CTL_ADD => poller.add(fd, token, interest),
+            _ => poller.modify(fd, token, interest),
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use super::*;
+use std::net::{TcpListener, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// How long we leave the phone alone after picking it up went wrong. When we are out of
+// file descriptors, trying again right away only fails again, as fast as we can try.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+//   The DeviceListener is the switchboard of our gateway. Trackers don't wait for us
+//   to call them, they call us. So we sit by the phone and pick up every call that comes in.
+// - listener: The phone number the devices have been told to call (the bound port)
+// - guard: The bouncer deciding which devices are let in
+// - pipeline: The post office every decoded packet is handed to, shared by all the calls
+// - config: How every conversation is set up
+pub struct DeviceListener {
+    listener: TcpListener,
+    guard: Arc<GateGuard>,
+    pipeline: Arc<Mutex<ProcessingPipeline>>,
+    config: SessionConfig,
+}
+
+impl DeviceListener {
+    // Get ourselves a phone number, and let the devices know where to call
+    pub fn bind<A: ToSocketAddrs>(
+        addr: A,
+        guard: GateGuard,
+        pipeline: Arc<Mutex<ProcessingPipeline>>,
+        config: SessionConfig,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr)?,
+            guard: Arc::new(guard),
+            pipeline,
+            config,
+        })
+    }
+
+    // The number we ended up with, handy when we asked for port 0
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // The post office the devices are sending their packets to
+    pub fn pipeline(&self) -> Arc<Mutex<ProcessingPipeline>> {
+        Arc::clone(&self.pipeline)
+    }
+
+    // Pick up every call that comes in, forever.
+    // A device that fails to connect properly shouldn't stop us from answering the next one.
+    pub fn run(&self) -> io::Result<()> {
+        loop {
+            match self.accept_device() {
+                Ok(_) => {}
+                // The device hung up before we answered, the next one is already waiting
+                Err(e)
+                    if e.kind() == io::ErrorKind::ConnectionAborted
+                        || e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    eprintln!("Failed to accept device: {}", e);
+                    thread::sleep(ACCEPT_BACKOFF);
+                }
+            }
+        }
+    }
+
+    // Pick up a single call, and give the device its own line to talk on.
+    // Every device gets its own Session, they only share the guard and the post office.
+    pub fn accept_device(&self) -> io::Result<thread::JoinHandle<io::Result<()>>> {
+        let (stream, _) = self.listener.accept()?;
+        let mut session = Session::with_config(
+            Connection::from_stream(stream)?,
+            Arc::clone(&self.guard),
+            Arc::clone(&self.pipeline),
+            &self.config,
+        );
+
+        Ok(thread::spawn(move || session.run()))
+    }
+}
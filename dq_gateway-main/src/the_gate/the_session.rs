@@ -0,0 +1,345 @@
+use super::*;
+use std::sync::{Arc, Mutex};
+
+// How often we read from one device each time the event loop wakes us for it.
+// The line is level-triggered, so whatever is left wakes us again on the next round,
+// after the other devices on the same worker have had their turn.
+const READS_PER_WAKEUP: usize = 16;
+
+//   How the listeners set up every Session they start, so they all hold the devices
+//   to the same rules without each of them having to be told separately.
+// - timeout_duration: How long each conversation is allowed to go quiet
+// - profile: The limits the device's frames are held to, see DeviceProfile
+// - policy: What to do with records that can't be true, see ValidationPolicy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionConfig {
+    pub timeout_duration: Duration,
+    pub profile: DeviceProfile,
+    pub policy: ValidationPolicy,
+}
+
+impl SessionConfig {
+    // Any device, held to the rules as they have always been
+    pub fn new(timeout_duration: Duration) -> Self {
+        Self {
+            timeout_duration,
+            profile: DeviceProfile::ANY,
+            policy: ValidationPolicy::default(),
+        }
+    }
+}
+
+//   A Session is one whole conversation with a single device, and the one who actually
+//   does the talking. The other parts only know their own little job:
+// - connection: The phone line, it can send and receive but has no idea what is being said
+// - parser: Turns what we hear into frames we understand
+// - state_machine: Decides what we should say back
+// - guard: Decides if the device is let in at all
+// - pipeline: Where the packets go once we have them
+//   The Session listens, asks the others what to do, and then does it.
+pub struct Session {
+    connection: Connection,
+    parser: Parser,
+    state_machine: StateMachine,
+    guard: Arc<GateGuard>,
+    pipeline: Arc<Mutex<ProcessingPipeline>>,
+}
+
+impl Session {
+    // The phone has been picked up, so the conversation starts in the Connected state
+    pub fn new(
+        connection: Connection,
+        guard: Arc<GateGuard>,
+        pipeline: Arc<Mutex<ProcessingPipeline>>,
+        timeout_duration: Duration,
+    ) -> Self {
+        let mut state_machine = StateMachine::new(timeout_duration);
+        state_machine.handle_event(ProtocolEvent::Connect);
+
+        // On a blocking line, we want to wake up in time to notice the device has gone quiet.
+        // If the line won't have it, the timeout the Connection set up still wakes us, just later.
+        let _ = connection.set_read_timeout(Some(timeout_duration));
+
+        Self {
+            connection,
+            parser: Parser::new(),
+            state_machine,
+            guard,
+            pipeline,
+        }
+    }
+
+    // The same, set up the way the listener was told to
+    pub fn with_config(
+        connection: Connection,
+        guard: Arc<GateGuard>,
+        pipeline: Arc<Mutex<ProcessingPipeline>>,
+        config: &SessionConfig,
+    ) -> Self {
+        let mut session = Self::new(connection, guard, pipeline, config.timeout_duration);
+        session.set_profile(config.profile);
+        session.set_validation_policy(config.policy);
+        session
+    }
+
+    // Hold the device to the limits of its family, once we know which one it is
+    pub fn set_profile(&mut self, profile: DeviceProfile) {
+        self.parser.set_profile(profile);
+    }
+
+    // What to do with records from this device that can't be true, see ValidationPolicy
+    pub fn set_validation_policy(&mut self, policy: ValidationPolicy) {
+        self.parser.set_validation_policy(policy);
+    }
+
+    // Where we are in the conversation right now
+    pub fn state(&self) -> ProtocolState {
+        self.state_machine.state()
+    }
+
+    // Who we are talking to, once they have told us
+    pub fn imei(&self) -> Option<&str> {
+        self.state_machine.imei()
+    }
+
+    // How many records and packets we have acknowledged so far
+    // Returns: (records, packets)
+    pub fn ack_stats(&self) -> (u64, u64) {
+        self.state_machine.ack_stats()
+    }
+
+    // How many bytes we have thrown away so far, getting past frames we couldn't read
+    pub fn skipped_bytes(&self) -> u64 {
+        self.parser.skipped_bytes()
+    }
+
+    // Ask the device to do something. Once step() has read its answer, the answer is
+    // handed to the post office, see ProcessingPipeline::take_messages
+    pub fn send_command(&mut self, command: &str) -> io::Result<()> {
+        self.connection.send_command(command)
+    }
+
+    // Ask the device to do something, only if it has the IMEI we name.
+    // Its answer, or its word that it isn't the one, goes to the post office like any other
+    pub fn send_imei_command(&mut self, imei: &str, command: &str) -> io::Result<()> {
+        self.connection.send_imei_command(imei, command)
+    }
+
+    // How long it has been since the device last said anything, pings included
+    pub fn idle_time(&self) -> Duration {
+        self.state_machine.idle_time()
+    }
+
+    // How long the device may stay quiet before we hang up on it
+    pub fn set_idle_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.state_machine.set_timeout_duration(timeout);
+        self.connection.set_read_timeout(Some(timeout))
+    }
+
+    // The phone line itself, for those who need to know which line to watch
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    // Keep the conversation going until one of us hangs up
+    pub fn run(&mut self) -> io::Result<()> {
+        while self.step()? {}
+        Ok(())
+    }
+
+    // Listen once, and respond to whatever we heard.
+    // On a blocking line, a read that times out is only a timeout once the device
+    // has been quiet for longer than we allow, pings keep it going.
+    // Only whole frames count as saying something. A device dripping in a byte at a time
+    // never lets the read time out, so we look at the clock after every read, not only then.
+    // Returns: true while the conversation goes on, false once the line is closed
+    pub fn step(&mut self) -> io::Result<bool> {
+        let open = match self.next_event(&mut 1) {
+            Ok(Some(event)) => self.handle_event(event)?,
+            Ok(None) => self.connection.stream.is_some(),
+            Err(e)
+                if e.kind() == io::ErrorKind::Interrupted
+                    || e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                true
+            }
+            Err(e) => return Err(e),
+        };
+        if open {
+            self.check_timeout()
+        } else {
+            Ok(false)
+        }
+    }
+
+    // The event loop tells us the device has said something on a non-blocking line.
+    // We keep listening until the line runs dry or the device has had its share of reads,
+    // and respond to everything we heard.
+    // Returns: true while the conversation goes on, false once the line is closed
+    pub fn on_readable(&mut self) -> io::Result<bool> {
+        let mut reads_left = READS_PER_WAKEUP;
+        loop {
+            match self.next_event(&mut reads_left) {
+                Ok(Some(event)) => {
+                    if !self.handle_event(event)? {
+                        return Ok(false);
+                    }
+                }
+                Ok(None) if self.connection.stream.is_none() => return Ok(false),
+                // A device that keeps on talking has to wait for its next turn
+                Ok(None) if reads_left == 0 => return self.check_timeout(),
+                Ok(None) => continue,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // The same goes for a device that drips in a byte every time we look
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return self.check_timeout(),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // The event loop tells us the line can take more of what we have to say
+    pub fn on_writable(&mut self) -> io::Result<()> {
+        self.connection.flush_pending()
+    }
+
+    // The device has been quiet for too long, so we give up on it.
+    // Returns: true while the conversation goes on, false once the line is closed
+    pub fn check_timeout(&mut self) -> io::Result<bool> {
+        if self.state_machine.is_timed_out() {
+            self.handle_event(ProtocolEvent::Timeout)
+        } else {
+            Ok(true)
+        }
+    }
+
+    // Read from the line once, and work out what it means for the conversation.
+    // A frame we already have is handed out without reading, the read is only spent when needed.
+    // reads_left: How many more reads we may spend, once they are spent we only hand out what we have
+    // Returns: The event, None if we need more to go on, or the error from a line with nothing to say
+    fn next_event(&mut self, reads_left: &mut usize) -> io::Result<Option<ProtocolEvent>> {
+        let stream = match self.connection.get_stream_mut() {
+            Some(stream) => stream,
+            None => return Ok(None),
+        };
+
+        // A device that has been let in has already said hello, anything else is line noise
+        self.parser
+            .set_greeted(self.state_machine.state() == ProtocolState::Ready);
+        let frame = match self.parser.next_frame() {
+            Ok(None) if *reads_left > 0 => {
+                *reads_left -= 1;
+                self.parser.parse_frame(stream)
+            }
+            frame => frame.map_err(io::Error::from),
+        };
+        match frame {
+            Ok(Some(TeltonikaFrame::Avl(packet))) => {
+                // Packets only count once the device has been let in
+                if self.state_machine.state() == ProtocolState::Ready {
+                    self.pipeline
+                        .lock()
+                        .map_err(|_| io::Error::other("Pipeline lock poisoned"))?
+                        .process_incoming(packet.clone(), None)?;
+                }
+                Ok(Some(ProtocolEvent::PacketReceived(packet)))
+            }
+            // So do the answers to our commands, whoever sent the command picks them up there,
+            // and whatever the serial device behind the tracker had to say
+            Ok(Some(
+                frame @ (TeltonikaFrame::CommandResponse(_)
+                | TeltonikaFrame::TimestampedResponse(_)
+                | TeltonikaFrame::ImeiCommandResponse(_)
+                | TeltonikaFrame::ThirdPartyData(_)),
+            )) => {
+                self.hand_on(&frame)?;
+                Ok(Some(ProtocolEvent::from(frame)))
+            }
+            Ok(Some(frame)) => Ok(Some(ProtocolEvent::from(frame))),
+            Ok(None) if self.parser.is_finished() => Ok(Some(ProtocolEvent::ConnectionLost)),
+            Ok(None) => Ok(None),
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::TimedOut
+                    || e.kind() == io::ErrorKind::Interrupted =>
+            {
+                Err(e)
+            }
+            Err(e) => match e
+                .get_ref()
+                .and_then(|inner| inner.downcast_ref::<ParseError>())
+            {
+                // One bad frame is no reason to hang up, the Parser has already moved past it
+                // and the device resends whatever we don't acknowledge
+                Some(error) if error.is_recoverable() => {
+                    eprintln!(
+                        "Skipped a bad frame from {}: {} ({} bytes skipped so far)",
+                        self.state_machine.imei().unwrap_or("an unknown device"),
+                        error,
+                        self.parser.skipped_bytes()
+                    );
+                    Ok(None)
+                }
+                _ => Ok(Some(ProtocolEvent::InvalidPacket)),
+            },
+        }
+    }
+
+    // Hand something the device told us to the post office, once the device has been let in
+    fn hand_on(&self, frame: &TeltonikaFrame) -> io::Result<()> {
+        let imei = match (self.state_machine.state(), self.state_machine.imei()) {
+            (ProtocolState::Ready, Some(imei)) => imei.to_string(),
+            _ => return Ok(()),
+        };
+        self.pipeline
+            .lock()
+            .map_err(|_| io::Error::other("Pipeline lock poisoned"))?
+            .process_message(DeviceMessage {
+                imei,
+                frame: frame.clone(),
+            });
+        Ok(())
+    }
+
+    // Let the StateMachine decide what to do about something that happened, and do it.
+    // Returns: true while the conversation goes on, false once the line is closed
+    pub fn handle_event(&mut self, event: ProtocolEvent) -> io::Result<bool> {
+        // The device told us who it is, so the guard has to make up its mind right away
+        let imei = match &event {
+            ProtocolEvent::Authenticate(imei, _) => Some(imei.clone()),
+            _ => None,
+        };
+
+        let mut actions = self.state_machine.handle_event(event).actions;
+        if let Some(imei) = imei {
+            let verdict = self.guard.check(&imei);
+            actions.extend(self.state_machine.handle_event(verdict).actions);
+        }
+
+        for action in actions {
+            if !self.execute(action)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    // Carry out a single action on the line.
+    // Returns: true while the conversation goes on, false once the line is closed
+    pub fn execute(&mut self, action: ProtocolAction) -> io::Result<bool> {
+        match action {
+            ProtocolAction::SendAcknowledgement(count) => {
+                self.connection.send_acknowledgement(count)?;
+                Ok(true)
+            }
+            ProtocolAction::SendAuthResponse(accepted) => {
+                self.connection.send_auth_response(accepted)?;
+                Ok(true)
+            }
+            ProtocolAction::DisconnectClient | ProtocolAction::ResetConnection => {
+                self.connection.shutdown()?;
+                Ok(false)
+            }
+        }
+    }
+}
@@ -8,6 +8,7 @@
 //#############################################################################################
 
 use super::*;
+use std::time::{SystemTime, UNIX_EPOCH};
 // The size of a packet can't be less than 45 bytes, and not more than 1280 bytes
 // This is the size limitations.
 
@@ -31,33 +32,83 @@ use super::*;
 // AVL Data record
 #[derive(Debug, Clone, PartialEq)]
 pub struct AVLData {
-    pub timestamp: u64,  // 8 bytes
-    pub priority: u8,    // 1 byte
-    pub gps: GPSElement, // 15 bytes
-    pub io: IOElement,   // Variable size
+    pub timestamp: u64,            // 8 bytes
+    pub priority: u8,              // 1 byte
+    pub gps: GPSElement,           // 15 bytes
+    pub io: IOElement,             // Variable size
+    pub warnings: Vec<ParseError>, // Not on the wire, what we let slide when reading it
 }
 
 // Core AVL packet (Codec 8, 8E, 16)
 #[derive(Debug, Clone, PartialEq)]
 pub struct AVLPacket {
-    pub preamble: u32,       // Always 0x00000000 (4 bytes)
-    pub data_length: u32,    // 4 bytes
-    pub codec_id: u8,        // 1 byte (0x08 for Codec8, 0x8E for Codec8E, 0x10 for Codec16)
-    pub number_of_data1: u8, // 1 byte
-    pub avl_data: Vec<AVLData>,
-    pub number_of_data2: u8, // 1 byte (must match number_of_data1)
-    pub crc16: u32,          // 4 bytes
+    pub preamble: u32,          // Always 0x00000000 (4 bytes)
+    pub data_length: u32,       // 4 bytes
+    pub codec_id: u8,           // 1 byte (0x08 for Codec8, 0x8E for Codec8E, 0x10 for Codec16)
+    pub number_of_data1: u8,    // 1 byte
+    pub avl_data: Vec<AVLData>, // Can be shorter than the counts, see ValidationPolicy
+    pub number_of_data2: u8,    // 1 byte (must match number_of_data1)
+    pub crc16: u32,             // 4 bytes
 }
 
 // GPS Element (15 bytes total)
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct GPSElement {
-    pub longitude: i32, // 4 bytes
-    pub latitude: i32,  // 4 bytes
-    pub altitude: i16,  // 2 bytes
-    pub angle: i16,     // 2 bytes
+    pub longitude: i32, // 4 bytes, in 1e-7 degrees, west is negative
+    pub latitude: i32,  // 4 bytes, in 1e-7 degrees, south is negative
+    pub altitude: i16,  // 2 bytes, meters above sea level
+    pub angle: i16,     // 2 bytes, degrees from north, clockwise
     pub satellites: u8, // 1 byte
-    pub speed: i16,     // 2 bytes
+    pub speed: i16,     // 2 bytes, km/h, 0xFFFF when the device doesn't know
+}
+
+// The speed a device writes when it doesn't know how fast it is going
+const UNKNOWN_SPEED: u16 = 0xFFFF;
+
+//   The coordinates come in 1e-7 degrees, 25.2797 east is written as 252797000.
+//   Anything that wants them as degrees should come through here,
+//   it is far too easy to be off by a couple of zeros.
+impl GPSElement {
+    // How many coordinate units make up one degree
+    pub const UNITS_PER_DEGREE: f64 = 10_000_000.0;
+    // The furthest east or west a longitude goes, in coordinate units
+    pub const MAX_LONGITUDE: i32 = 1_800_000_000;
+    // The furthest north or south a latitude goes, in coordinate units
+    pub const MAX_LATITUDE: i32 = 900_000_000;
+
+    pub fn longitude_degrees(&self) -> f64 {
+        self.longitude as f64 / Self::UNITS_PER_DEGREE
+    }
+
+    pub fn latitude_degrees(&self) -> f64 {
+        self.latitude as f64 / Self::UNITS_PER_DEGREE
+    }
+
+    // Which way the device is heading, in degrees from north, 0 to 360
+    pub fn heading_degrees(&self) -> f64 {
+        self.angle as u16 as f64
+    }
+
+    // How fast the device is going, None when it doesn't know
+    pub fn speed_kmh(&self) -> Option<f64> {
+        match self.speed as u16 {
+            UNKNOWN_SPEED => None,
+            speed => Some(speed as f64),
+        }
+    }
+
+    //   If the position can be trusted. Without any satellites in sight, or without
+    //   a speed, the device is only telling us where it was last time it knew.
+    pub fn is_valid(&self) -> bool {
+        self.satellites > 0 && self.speed as u16 != UNKNOWN_SPEED
+    }
+}
+
+impl AVLData {
+    // When the record was written, the timestamp is in milliseconds since 1970 (UTC)
+    pub fn time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.timestamp)
+    }
 }
 
 // IO Elements for different codecs
@@ -68,6 +119,119 @@ pub enum IOElement {
     Codec16(IOElement16),
 }
 
+// The value of a single IO element, whatever codec it came in.
+// Only Codec 8E has variable length values, they are handed out as they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoValue<'a> {
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    Bytes(&'a [u8]),
+}
+
+impl IoValue<'_> {
+    // The value as a plain number, None for a variable length value
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            IoValue::U8(value) => Some(value as u64),
+            IoValue::U16(value) => Some(value as u64),
+            IoValue::U32(value) => Some(value as u64),
+            IoValue::U64(value) => Some(value),
+            IoValue::Bytes(_) => None,
+        }
+    }
+}
+
+// So nobody has to care which codec the device used. All the ids are handed out
+// as u16, a Codec 8 id fits in one just as well.
+impl IOElement {
+    // The id of the IO element that caused the record to be written, 0 if none did
+    pub fn event_io_id(&self) -> u16 {
+        match self {
+            IOElement::Codec8(io) => io.event_io_id as u16,
+            IOElement::Codec8Extended(io) => io.event_io_id,
+            IOElement::Codec16(io) => io.event_io_id,
+        }
+    }
+
+    // Every (id, value) pair, the one byte values first and the variable length ones last
+    pub fn iter(&self) -> IoValues<'_> {
+        IoValues {
+            io: self,
+            group: 0,
+            index: 0,
+        }
+    }
+
+    // The value of a single IO element, if the record has it. "What is IO 239?"
+    pub fn get(&self, id: u16) -> Option<IoValue<'_>> {
+        self.iter()
+            .find(|(element_id, _)| *element_id == id)
+            .map(|(_, value)| value)
+    }
+}
+
+//   Walks through the IO elements of a record, one group at a time
+// group: Which group we are in, 0 to 4 for one, two, four, eight and variable length values
+// index: Where we are in that group
+pub struct IoValues<'a> {
+    io: &'a IOElement,
+    group: usize,
+    index: usize,
+}
+
+impl<'a> IoValues<'a> {
+    // The element at index in the current group, None once the group has run out
+    fn element(&self) -> Option<(u16, IoValue<'a>)> {
+        let index = self.index;
+        match (self.io, self.group) {
+            (IOElement::Codec8(io), 0) => pick(&io.one_byte_ios, index, IoValue::U8),
+            (IOElement::Codec8(io), 1) => pick(&io.two_byte_ios, index, IoValue::U16),
+            (IOElement::Codec8(io), 2) => pick(&io.four_byte_ios, index, IoValue::U32),
+            (IOElement::Codec8(io), 3) => pick(&io.eight_byte_ios, index, IoValue::U64),
+            (IOElement::Codec8Extended(io), 0) => pick(&io.one_byte_ios, index, IoValue::U8),
+            (IOElement::Codec8Extended(io), 1) => pick(&io.two_byte_ios, index, IoValue::U16),
+            (IOElement::Codec8Extended(io), 2) => pick(&io.four_byte_ios, index, IoValue::U32),
+            (IOElement::Codec8Extended(io), 3) => pick(&io.eight_byte_ios, index, IoValue::U64),
+            (IOElement::Codec8Extended(io), 4) => io
+                .var_byte_ios
+                .get(index)
+                .map(|(id, _, value)| (*id, IoValue::Bytes(value))),
+            (IOElement::Codec16(io), 0) => pick(&io.one_byte_ios, index, IoValue::U8),
+            (IOElement::Codec16(io), 1) => pick(&io.two_byte_ios, index, IoValue::U16),
+            (IOElement::Codec16(io), 2) => pick(&io.four_byte_ios, index, IoValue::U32),
+            (IOElement::Codec16(io), 3) => pick(&io.eight_byte_ios, index, IoValue::U64),
+            _ => None,
+        }
+    }
+}
+
+// One (id, value) pair out of a group, whatever size its ids are
+fn pick<'a, I: Copy + Into<u16>, V: Copy>(
+    ios: &[(I, V)],
+    index: usize,
+    wrap: fn(V) -> IoValue<'a>,
+) -> Option<(u16, IoValue<'a>)> {
+    ios.get(index).map(|&(id, value)| (id.into(), wrap(value)))
+}
+
+impl<'a> Iterator for IoValues<'a> {
+    type Item = (u16, IoValue<'a>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.group <= 4 {
+            if let Some(element) = self.element() {
+                self.index += 1;
+                return Some(element);
+            }
+            self.group += 1;
+            self.index = 0;
+        }
+        None
+    }
+}
+
 // Codec 8 IO Element
 #[derive(Debug, Clone, PartialEq)]
 pub struct IOElement8 {
@@ -145,3 +309,393 @@ pub struct Codec12ResponsePacket {
     pub response_qty2: u8,  // 1 byte (should match response_qty1)
     pub crc16: u32,         // 4 bytes
 }
+
+// Codec 13 Response Packet, a Codec 12 response with the time the device wrote it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Codec13ResponsePacket {
+    pub preamble: u32,      // Always 0x00000000 (4 bytes)
+    pub data_length: u32,   // 4 bytes
+    pub codec_id: u8,       // 1 byte (0x0D for Codec13)
+    pub response_qty1: u8,  // 1 byte
+    pub response_type: u8,  // 1 byte (0x05, the same as a command)
+    pub response_size: u32, // 4 bytes, the timestamp and the response
+    pub timestamp: u32,     // 4 bytes, seconds since 1970 (UTC)
+    pub response: Vec<u8>,  // Variable size - response in HEX
+    pub response_qty2: u8,  // 1 byte (should match response_qty1)
+    pub crc16: u32,         // 4 bytes
+}
+
+// Codec 14 Command Packet, a Codec 12 command only the device with the IMEI will carry out
+#[derive(Debug, Clone, PartialEq)]
+pub struct Codec14CommandPacket {
+    pub preamble: u32,     // Always 0x00000000 (4 bytes)
+    pub data_length: u32,  // 4 bytes
+    pub codec_id: u8,      // 1 byte (0x0E for Codec14)
+    pub command_qty1: u8,  // 1 byte
+    pub command_type: u8,  // 1 byte (0x05 for command)
+    pub command_size: u32, // 4 bytes, the IMEI and the command
+    pub imei: String,      // 8 bytes, the digits packed two to a byte
+    pub command: Vec<u8>,  // Variable size - command in HEX
+    pub command_qty2: u8,  // 1 byte (should match command_qty1)
+    pub crc16: u32,        // 4 bytes
+}
+
+// Codec 14 Response Packet
+#[derive(Debug, Clone, PartialEq)]
+pub struct Codec14ResponsePacket {
+    pub preamble: u32,      // Always 0x00000000 (4 bytes)
+    pub data_length: u32,   // 4 bytes
+    pub codec_id: u8,       // 1 byte (0x0E for Codec14)
+    pub response_qty1: u8,  // 1 byte
+    pub response_type: u8,  // 1 byte (0x06 when the IMEI matched, 0x11 when it didn't)
+    pub response_size: u32, // 4 bytes, the IMEI and the response
+    pub imei: String,       // 8 bytes, the digits packed two to a byte
+    pub response: Vec<u8>,  // Variable size - response in HEX, empty when the IMEI didn't match
+    pub response_qty2: u8,  // 1 byte (should match response_qty1)
+    pub crc16: u32,         // 4 bytes
+}
+
+// Codec 15 Packet, whatever a serial device attached to an FMX6 had to say
+#[derive(Debug, Clone, PartialEq)]
+pub struct Codec15Packet {
+    pub preamble: u32,     // Always 0x00000000 (4 bytes)
+    pub data_length: u32,  // 4 bytes
+    pub codec_id: u8,      // 1 byte (0x0F for Codec15)
+    pub message_qty1: u8,  // 1 byte
+    pub message_type: u8,  // 1 byte (0x01)
+    pub message_size: u32, // 4 bytes, the timestamp, the IMEI and the payload
+    pub timestamp: u32,    // 4 bytes, seconds since 1970 (UTC)
+    pub imei: String,      // 8 bytes, the digits packed two to a byte
+    pub payload: Vec<u8>,  // Variable size - the serial data, exactly as the serial device sent it
+    pub message_qty2: u8,  // 1 byte (should match message_qty1)
+    pub crc16: u32,        // 4 bytes
+}
+
+// Everything a device can hand us over the wire, once we have made sense of it.
+// It starts by telling us its IMEI, most of the time after that it is AVL data,
+// but when we have asked it to do something, it will answer with a Codec 12, 13 or 14 response.
+// Devices with something attached to their serial port pass that along in Codec 15.
+// With open link on, a device with nothing to say pings us now and then to keep the line open.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TeltonikaFrame {
+    Ping,
+    Imei(String),
+    Avl(AVLPacket),
+    CommandResponse(Codec12ResponsePacket),
+    TimestampedResponse(Codec13ResponsePacket),
+    ImeiCommandResponse(Codec14ResponsePacket),
+    ThirdPartyData(Codec15Packet),
+}
+
+impl TeltonikaFrame {
+    // A short name for the frame, handy when telling someone what we got instead
+    pub fn describe(&self) -> String {
+        match self {
+            TeltonikaFrame::Ping => "a keepalive ping".to_string(),
+            TeltonikaFrame::Imei(_) => "an IMEI handshake".to_string(),
+            TeltonikaFrame::Avl(packet) => {
+                format!("an AVL packet (codec {:#04x})", packet.codec_id)
+            }
+            TeltonikaFrame::CommandResponse(_) => "a Codec 12 response".to_string(),
+            TeltonikaFrame::TimestampedResponse(_) => "a Codec 13 response".to_string(),
+            TeltonikaFrame::ImeiCommandResponse(_) => "a Codec 14 response".to_string(),
+            TeltonikaFrame::ThirdPartyData(_) => "a Codec 15 packet".to_string(),
+        }
+    }
+}
+
+//   Codec 12, 13, 14 and 15 are all written the same way after the codec id, the way
+//   Parser::parse_command_envelope reads them: quantity 1, type, size, the message,
+//   quantity 2 and the CRC. The message comes in the parts it is made of, the timestamp,
+//   the IMEI and the text or payload, whichever the codec has. The size, the data length
+//   and the CRC are worked out from them, so they can't disagree with what is written.
+// quantities: Quantity 1 and 2, they should be the same
+fn write_command_envelope(
+    codec_id: u8,
+    quantities: (u8, u8),
+    message_type: u8,
+    message: &[&[u8]],
+) -> Vec<u8> {
+    let message_size: usize = message.iter().map(|part| part.len()).sum();
+    let data_length = message_size + 8;
+
+    let mut bytes = Vec::with_capacity(data_length + 12);
+    bytes.extend_from_slice(&0u32.to_be_bytes());
+    bytes.extend_from_slice(&(data_length as u32).to_be_bytes());
+    bytes.push(codec_id);
+    bytes.push(quantities.0);
+    bytes.push(message_type);
+    bytes.extend_from_slice(&(message_size as u32).to_be_bytes());
+    for part in message {
+        bytes.extend_from_slice(part);
+    }
+    bytes.push(quantities.1);
+    let crc16 = calculate_crc16(&bytes[8..]);
+    bytes.extend_from_slice(&crc16.to_be_bytes());
+    bytes
+}
+
+// The CRC write_command_envelope worked out, for the packets to keep next to their fields
+fn envelope_crc(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes[bytes.len() - 4..].try_into().unwrap())
+}
+
+impl Codec12CommandPacket {
+    // Wraps a plain text command, like "getinfo" or "setdigout 1", in a Codec 12 envelope.
+    // The data length covers everything from the codec id to the second quantity,
+    // which is the command itself plus 8 bytes of bookkeeping.
+    pub fn new(command: &str) -> Self {
+        let command = command.as_bytes().to_vec();
+        let command_size = command.len() as u32;
+
+        let mut packet = Self {
+            preamble: 0x00000000,
+            data_length: command_size + 8,
+            codec_id: 0x0C,
+            command_qty1: 1,
+            command_type: CODEC12_COMMAND_TYPE,
+            command_size,
+            command,
+            command_qty2: 1,
+            crc16: 0,
+        };
+        packet.crc16 = envelope_crc(&packet.to_bytes());
+        packet
+    }
+
+    // Lays the packet out byte by byte, ready to be written to the device
+    pub fn to_bytes(&self) -> Vec<u8> {
+        write_command_envelope(
+            self.codec_id,
+            (self.command_qty1, self.command_qty2),
+            self.command_type,
+            &[&self.command],
+        )
+    }
+}
+
+impl Codec12ResponsePacket {
+    // The device answers in plain text, so we let people read it as such
+    pub fn response_text(&self) -> String {
+        String::from_utf8_lossy(&self.response).into_owned()
+    }
+}
+
+//   Codec 14 writes the IMEI as 8 bytes, two digits to a byte, with a zero in front
+//   to make up the sixteenth: "352093081452251" is 0x03 0x52 0x09 0x30 0x81 0x45 0x22 0x51.
+// Returns: None for anything that isn't 1 to 15 digits
+pub fn pack_imei(imei: &str) -> Option<[u8; 8]> {
+    if imei.is_empty() || imei.len() > MAX_IMEI_LENGTH || !imei.bytes().all(|b| b.is_ascii_digit())
+    {
+        return None;
+    }
+    let packed = imei
+        .bytes()
+        .fold(0u64, |packed, digit| (packed << 4) | (digit - b'0') as u64);
+    Some(packed.to_be_bytes())
+}
+
+// The IMEI back out of its 8 bytes, always 15 digits.
+// Returns: None if one of the digits isn't a digit
+pub fn unpack_imei(packed: [u8; 8]) -> Option<String> {
+    let packed = u64::from_be_bytes(packed);
+    (0..MAX_IMEI_LENGTH)
+        .rev()
+        .map(|index| {
+            let digit = ((packed >> (index * 4)) & 0x0F) as u8;
+            (digit <= 9).then_some((b'0' + digit) as char)
+        })
+        .collect()
+}
+
+impl Codec13ResponsePacket {
+    // Wraps a plain text response, the way a device would send it.
+    // timestamp: When the response was written, in seconds since 1970 (UTC)
+    pub fn new(timestamp: u32, response: &str) -> Self {
+        let response = response.as_bytes().to_vec();
+        let response_size = response.len() as u32 + 4;
+
+        let mut packet = Self {
+            preamble: 0x00000000,
+            data_length: response_size + 8,
+            codec_id: 0x0D,
+            response_qty1: 1,
+            response_type: CODEC12_COMMAND_TYPE,
+            response_size,
+            timestamp,
+            response,
+            response_qty2: 1,
+            crc16: 0,
+        };
+        packet.crc16 = envelope_crc(&packet.to_bytes());
+        packet
+    }
+
+    // Lays the packet out byte by byte, the way the device sent it
+    pub fn to_bytes(&self) -> Vec<u8> {
+        write_command_envelope(
+            self.codec_id,
+            (self.response_qty1, self.response_qty2),
+            self.response_type,
+            &[&self.timestamp.to_be_bytes(), &self.response],
+        )
+    }
+
+    pub fn response_text(&self) -> String {
+        String::from_utf8_lossy(&self.response).into_owned()
+    }
+
+    // When the device wrote the response, rather than when it reached us
+    pub fn time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.timestamp as u64)
+    }
+}
+
+impl Codec14CommandPacket {
+    //   Wraps a plain text command for the device with the given IMEI. Any other device
+    //   that gets it, answers that it isn't the one, instead of carrying it out.
+    // Returns: None if the IMEI isn't 1 to 15 digits
+    pub fn new(imei: &str, command: &str) -> Option<Self> {
+        pack_imei(imei)?;
+        let command = command.as_bytes().to_vec();
+        let command_size = command.len() as u32 + 8;
+
+        let mut packet = Self {
+            preamble: 0x00000000,
+            data_length: command_size + 8,
+            codec_id: 0x0E,
+            command_qty1: 1,
+            command_type: CODEC12_COMMAND_TYPE,
+            command_size,
+            imei: imei.to_string(),
+            command,
+            command_qty2: 1,
+            crc16: 0,
+        };
+        packet.crc16 = envelope_crc(&packet.to_bytes());
+        Some(packet)
+    }
+
+    // Lays the packet out byte by byte, ready to be written to the device
+    pub fn to_bytes(&self) -> Vec<u8> {
+        write_command_envelope(
+            self.codec_id,
+            (self.command_qty1, self.command_qty2),
+            self.command_type,
+            &[&pack_imei(&self.imei).unwrap_or_default(), &self.command],
+        )
+    }
+}
+
+impl Codec14ResponsePacket {
+    //   The answer of the device with the given IMEI. When matched is false, the device
+    //   is telling us the command was meant for someone else, and the response is left empty.
+    // Returns: None if the IMEI isn't 1 to 15 digits
+    pub fn new(imei: &str, matched: bool, response: &str) -> Option<Self> {
+        pack_imei(imei)?;
+        let (response_type, response) = match matched {
+            true => (CODEC14_ACK_TYPE, response.as_bytes().to_vec()),
+            false => (CODEC14_NACK_TYPE, Vec::new()),
+        };
+        let response_size = response.len() as u32 + 8;
+
+        let mut packet = Self {
+            preamble: 0x00000000,
+            data_length: response_size + 8,
+            codec_id: 0x0E,
+            response_qty1: 1,
+            response_type,
+            response_size,
+            imei: imei.to_string(),
+            response,
+            response_qty2: 1,
+            crc16: 0,
+        };
+        packet.crc16 = envelope_crc(&packet.to_bytes());
+        Some(packet)
+    }
+
+    // Lays the packet out byte by byte, the way the device sent it
+    pub fn to_bytes(&self) -> Vec<u8> {
+        write_command_envelope(
+            self.codec_id,
+            (self.response_qty1, self.response_qty2),
+            self.response_type,
+            &[&pack_imei(&self.imei).unwrap_or_default(), &self.response],
+        )
+    }
+
+    // Whether the device was the one the command was meant for, and carried it out
+    pub fn imei_matched(&self) -> bool {
+        self.response_type == CODEC14_ACK_TYPE
+    }
+
+    pub fn response_text(&self) -> String {
+        String::from_utf8_lossy(&self.response).into_owned()
+    }
+}
+
+impl Codec15Packet {
+    //   Wraps the bytes a serial device handed the tracker, the way the tracker would send them.
+    //   We never send these ourselves, but it is handy to have them for testing.
+    // Returns: None if the IMEI isn't 1 to 15 digits
+    pub fn new(timestamp: u32, imei: &str, payload: &[u8]) -> Option<Self> {
+        pack_imei(imei)?;
+        let message_size = payload.len() as u32 + 12;
+
+        let mut packet = Self {
+            preamble: 0x00000000,
+            data_length: message_size + 8,
+            codec_id: 0x0F,
+            message_qty1: 1,
+            message_type: CODEC15_MESSAGE_TYPE,
+            message_size,
+            timestamp,
+            imei: imei.to_string(),
+            payload: payload.to_vec(),
+            message_qty2: 1,
+            crc16: 0,
+        };
+        packet.crc16 = envelope_crc(&packet.to_bytes());
+        Some(packet)
+    }
+
+    // Lays the packet out byte by byte, the way the device sent it
+    pub fn to_bytes(&self) -> Vec<u8> {
+        write_command_envelope(
+            self.codec_id,
+            (self.message_qty1, self.message_qty2),
+            self.message_type,
+            &[
+                &self.timestamp.to_be_bytes(),
+                &pack_imei(&self.imei).unwrap_or_default(),
+                &self.payload,
+            ],
+        )
+    }
+
+    // When the tracker got the data from the serial device
+    pub fn time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.timestamp as u64)
+    }
+}
+
+// This calculates a special number that helps us verify nothing got corrupted
+// Like checking if any pages have grammatical mistakes, spelling errors
+// or got coffee stains on them during delivery. Maybe it's dog ate his homework?
+// Teltonika uses CRC-16/IBM, counted from the codec id up to and including
+// the last quantity byte, so the preamble and data length are left out.
+pub fn calculate_crc16(data: &[u8]) -> u32 {
+    let mut crc: u16 = 0x0000;
+    for &byte in data {
+        crc ^= byte as u16;
+        for _ in 0..8 {
+            if (crc & 0x0001) != 0 {
+                crc = (crc >> 1) ^ 0xA001;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc as u32
+}
@@ -0,0 +1,238 @@
+use super::*;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+//   Over UDP a device doesn't call us, it sends postcards. Every datagram stands on its own,
+//   so every one of them says who sent it, and which postcard it is:
+// Length          - How many bytes follow this field (2 bytes)
+// Packet_ID       - The device's number for the datagram (2 bytes)
+// Not_Usable_Byte - Always 0x01, and of no use to us (1 byte)
+// AVL_Packet_ID   - The device's number for the AVL packet inside (1 byte)
+// IMEI_Length     - How long the IMEI is (2 bytes)
+// IMEI            - Who sent it
+// AVL_Data        - Codec id, record count, the records and the record count again.
+//                   No preamble, data length or CRC, UDP looks after the bytes itself.
+//
+// The Length, IDs and IMEI length in front of the IMEI
+const UDP_HEADER_SIZE: usize = 8;
+
+// The byte no one has found a use for yet
+const NOT_USABLE_BYTE: u8 = 0x01;
+
+// How long we leave the mailbox alone after the socket itself let us down. Trying again
+// right away only fails again, as fast as we can try, see ACCEPT_BACKOFF in the_listener.rs.
+const RECEIVE_BACKOFF: Duration = Duration::from_millis(100);
+
+// An AVL packet that came in over UDP, along with the postcard it was written on
+#[derive(Debug, Clone, PartialEq)]
+pub struct UdpAvlPacket {
+    pub packet_id: u16,
+    pub avl_packet_id: u8,
+    pub imei: String,
+    pub packet: AVLPacket, // data_length is the length of the AVL part, crc16 is always 0
+}
+
+impl UdpAvlPacket {
+    // Read a whole datagram, held to the limits of any device
+    pub fn parse(datagram: &[u8]) -> Result<Self, ParseError> {
+        Self::parse_with_policy(datagram, &DeviceProfile::ANY, ValidationPolicy::Strict)
+    }
+
+    // The same, for a particular kind of device, and with our own say about bad records
+    pub fn parse_with_policy(
+        datagram: &[u8],
+        profile: &DeviceProfile,
+        policy: ValidationPolicy,
+    ) -> Result<Self, ParseError> {
+        if datagram.len() < UDP_HEADER_SIZE {
+            return Err(ParseError::Incomplete {
+                needed: "UDP header",
+                offset: datagram.len(),
+                codec: None,
+            });
+        }
+        let field = |at: usize| u16::from_be_bytes([datagram[at], datagram[at + 1]]);
+
+        // A datagram arrives whole or not at all, so a length that is off was never right
+        let length = field(0) as usize;
+        if length != datagram.len() - 2 {
+            return Err(ParseError::LengthMismatch {
+                declared: length,
+                consumed: datagram.len() - 2,
+                offset: 0,
+                codec: None,
+            });
+        }
+        let packet_id = field(2);
+        let avl_packet_id = datagram[5];
+
+        let imei_length = field(6) as usize;
+        if imei_length > MAX_IMEI_LENGTH {
+            return Err(ParseError::InvalidImei {
+                reason: "longer than 15 digits",
+                offset: 6,
+                codec: None,
+            });
+        }
+        let imei_end = UDP_HEADER_SIZE + imei_length;
+        let imei = datagram
+            .get(UDP_HEADER_SIZE..imei_end)
+            .ok_or(ParseError::Incomplete {
+                needed: "IMEI",
+                offset: datagram.len(),
+                codec: None,
+            })?;
+        if !imei.iter().all(u8::is_ascii_digit) {
+            return Err(ParseError::InvalidImei {
+                reason: "contains non-digit characters",
+                offset: UDP_HEADER_SIZE,
+                codec: None,
+            });
+        }
+
+        Ok(Self {
+            packet_id,
+            avl_packet_id,
+            imei: String::from_utf8_lossy(imei).into_owned(),
+            packet: parse_udp_avl(datagram, imei_end, profile, policy)?,
+        })
+    }
+
+    // Lays the datagram out byte by byte, the way a device would send it
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // The AVL part is a TCP frame without the preamble, data length and CRC
+        let frame = self.packet.to_bytes();
+        let avl = &frame[8..frame.len() - 4];
+
+        let length = UDP_HEADER_SIZE - 2 + self.imei.len() + avl.len();
+        let mut bytes = Vec::with_capacity(length + 2);
+        bytes.extend_from_slice(&(length as u16).to_be_bytes());
+        bytes.extend_from_slice(&self.packet_id.to_be_bytes());
+        bytes.push(NOT_USABLE_BYTE);
+        bytes.push(self.avl_packet_id);
+        bytes.extend_from_slice(&(self.imei.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(self.imei.as_bytes());
+        bytes.extend_from_slice(avl);
+        bytes
+    }
+
+    // Our answer to this datagram, saying how many of its records we accepted
+    pub fn ack(&self, accepted: u8) -> UdpAck {
+        UdpAck {
+            packet_id: self.packet_id,
+            avl_packet_id: self.avl_packet_id,
+            accepted,
+        }
+    }
+}
+
+//   The postcard we send back. It repeats the numbers the device wrote on its own,
+//   so the device knows which one we are answering, and how many records we took in.
+//   If it never arrives, the device sends the same data again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpAck {
+    pub packet_id: u16,
+    pub avl_packet_id: u8,
+    pub accepted: u8,
+}
+
+impl UdpAck {
+    // Length(2), Packet_ID(2), Not_Usable_Byte(1), AVL_Packet_ID(1), accepted records(1)
+    pub fn to_bytes(self) -> [u8; 7] {
+        let [id_high, id_low] = self.packet_id.to_be_bytes();
+        [
+            0x00,
+            0x05,
+            id_high,
+            id_low,
+            NOT_USABLE_BYTE,
+            self.avl_packet_id,
+            self.accepted,
+        ]
+    }
+}
+
+//   The mailbox for devices that send postcards instead of calling. There is no
+//   conversation to keep track of, every datagram is checked, handed to the post office
+//   and answered on its own.
+// - socket: The mailbox the devices have been told to send to (the bound port)
+// - guard: Checks the IMEI on every postcard, there is no handshake to do it once
+// - pipeline: The post office every decoded packet is handed to
+// - profile: The limits the datagrams are held to
+// - policy: What to do with records that can't be true
+//   The profile and policy come from the same SessionConfig the TCP side is given,
+//   a postcard has no conversation to time out, so its timeout goes unused.
+pub struct UdpListener {
+    socket: UdpSocket,
+    guard: Arc<GateGuard>,
+    pipeline: Arc<Mutex<ProcessingPipeline>>,
+    profile: DeviceProfile,
+    policy: ValidationPolicy,
+}
+
+impl UdpListener {
+    pub fn bind<A: ToSocketAddrs>(
+        addr: A,
+        guard: GateGuard,
+        pipeline: Arc<Mutex<ProcessingPipeline>>,
+        config: SessionConfig,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            socket: UdpSocket::bind(addr)?,
+            guard: Arc::new(guard),
+            pipeline,
+            profile: config.profile,
+            policy: config.policy,
+        })
+    }
+
+    // The address we ended up with, handy when we asked for port 0
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    // Empty the mailbox, forever.
+    // A postcard we can't read shouldn't stop us from reading the next one.
+    pub fn run(&self) -> io::Result<()> {
+        loop {
+            match self.receive_datagram() {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                // The next postcard is already waiting, and is likely fine
+                Err(e) if e.get_ref().is_some_and(|e| e.is::<ParseError>()) => {
+                    eprintln!("Failed to read datagram: {}", e);
+                }
+                Err(e) => {
+                    eprintln!("Failed to handle datagram: {}", e);
+                    thread::sleep(RECEIVE_BACKOFF);
+                }
+            }
+        }
+    }
+
+    //   Take a single postcard out of the mailbox, and answer it.
+    //   Datagrams we can't read, or from devices the guard doesn't let in, go unanswered,
+    //   the device will try again.
+    // Returns: The answer we sent, None if we didn't
+    pub fn receive_datagram(&self) -> io::Result<Option<UdpAck>> {
+        let mut datagram = [0u8; RECEIVE_BUFFER_SIZE];
+        let (length, sender) = self.socket.recv_from(&mut datagram)?;
+        let received =
+            UdpAvlPacket::parse_with_policy(&datagram[..length], &self.profile, self.policy)?;
+
+        if !matches!(self.guard.check(&received.imei), ProtocolEvent::AuthSuccess) {
+            return Ok(None);
+        }
+
+        // The device is acknowledged for every record it sent, whatever the policy kept
+        let ack = received.ack(received.packet.number_of_data1);
+        self.pipeline
+            .lock()
+            .map_err(|_| io::Error::other("Pipeline lock poisoned"))?
+            .process_incoming(received.packet, None)?;
+
+        self.socket.send_to(&ack.to_bytes(), sender)?;
+        Ok(Some(ack))
+    }
+}